@@ -0,0 +1,220 @@
+//! Zero, duplicate and compressible pages of a process.
+//!
+//! [`census`] reads the present pages of a process with [`RemoteMemory`] and
+//! counts the all-zero pages, the pages whose contents (by hash) already
+//! appeared elsewhere in the process, which is what KSM could merge, and
+//! compresses a sample of the pages with LZ4 to estimate the compression
+//! ratio. The zero page and KSM flags of `/proc/kpageflags` are counted next
+//! to them to cross-check what the kernel already shares. Swapped pages are
+//! skipped, reading them would swap them back in.
+use std::collections::{
+    BTreeMap,
+    HashSet,
+};
+
+use crate::{
+    checkpoint::checksum,
+    deps::{
+        log::debug,
+        lz4_flex,
+        serde,
+    },
+    error::Error,
+    maps::column::{
+        AddressRange,
+        Perm,
+    },
+    pagemaps::{
+        coalesce_ranges,
+        PageSize,
+        ProcessVMA,
+    },
+    remote::RemoteMemory,
+};
+
+
+pub const PAGESIZE: usize = PageSize::Normal as usize;
+/// pages read at once
+const CHUNK_PAGES: usize = 256;
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PageCensus {
+    /// present pages read
+    pub pages:            u64,
+    /// pages that were unmapped before they could be read
+    pub unreadable:       u64,
+    /// pages with all-zero contents
+    pub zero:             u64,
+    /// pages mapping the shared zero page according to kpageflags
+    pub zero_page:        u64,
+    /// non-zero pages with the contents of a page seen before
+    pub duplicate:        u64,
+    /// pages merged by KSM according to kpageflags
+    pub ksm:              u64,
+    /// distinct KSM frames the `ksm` pages map, counted in the region they
+    /// are first seen in
+    #[serde(default)]
+    pub ksm_frames:       u64,
+    /// pages compressed to estimate the compression ratio
+    pub sampled:          u64,
+    pub compressed_bytes: u64,
+}
+
+
+impl PageCensus {
+    /// The sampled bytes per compressed byte, `None` if nothing was sampled.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        if self.compressed_bytes == 0 {
+            None
+        } else {
+            Some((self.sampled * PAGESIZE as u64) as f64 / self.compressed_bytes as f64)
+        }
+    }
+
+    /// The pages KSM could free by merging duplicates and zero pages, minus
+    /// the pages already freed: every mapping of the zero page, and every
+    /// KSM mapping but one per KSM frame, which the census counts as a
+    /// duplicate too. KSM frames shared with other processes count as freed
+    /// once here, though they may save more memory system wide.
+    pub fn merge_potential(&self) -> u64 {
+        let ksm_shared = self.ksm.saturating_sub(self.ksm_frames);
+        (self.duplicate + self.zero).saturating_sub(ksm_shared + self.zero_page)
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &PageCensus,
+    ) {
+        self.pages += other.pages;
+        self.unreadable += other.unreadable;
+        self.zero += other.zero;
+        self.zero_page += other.zero_page;
+        self.duplicate += other.duplicate;
+        self.ksm += other.ksm;
+        self.ksm_frames += other.ksm_frames;
+        self.sampled += other.sampled;
+        self.compressed_bytes += other.compressed_bytes;
+    }
+}
+
+
+/// The outcome of [`census`], per region by start address.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct CensusReport {
+    pub regions: BTreeMap<usize, PageCensus>,
+    pub total:   PageCensus,
+}
+
+
+/// Take the census of the present pages of the regions starting at
+/// `regions`, compressing every `sample_every`-th page read.
+pub fn census(
+    vm: &ProcessVMA,
+    regions: &[usize],
+    sample_every: usize,
+) -> Result<CensusReport, Error> {
+    let sample_every = sample_every.max(1) as u64;
+    let mut remote = RemoteMemory::new(vm.pid());
+    let mut seen = HashSet::new();
+    let mut ksm_frames = HashSet::new();
+    let mut report = CensusReport::default();
+
+    for addr in regions {
+        let region = vm.region(*addr).ok_or(Error::RegionNotFound { addr: *addr })?;
+        if !region.region().perms().contains(Perm::Read) {
+            debug!("skipping unreadable region {}", region.region().addr_range());
+            continue;
+        }
+
+        let mut stats = PageCensus::default();
+        let mut present = Vec::new();
+        for page in region.try_iter(Some(PageSize::Normal))? {
+            let page = page?;
+            if !page.pte.is_present() {
+                continue;
+            }
+            if let Some(flags) = page.kpageflags {
+                stats.zero_page += flags.zero_page() as u64;
+                if flags.ksm() {
+                    stats.ksm += 1;
+                    if let Some(pfn) = page.pte.page_frame_number() {
+                        stats.ksm_frames += ksm_frames.insert(pfn) as u64;
+                    }
+                }
+            }
+            present.push(page.addr_range);
+        }
+
+        let mut buf = vec![0u8; CHUNK_PAGES * PAGESIZE];
+        for range in coalesce_ranges(present) {
+            for start in (range.start()..range.end()).step_by(CHUNK_PAGES * PAGESIZE) {
+                let chunk = AddressRange::new(start, range.end().min(start + CHUNK_PAGES * PAGESIZE));
+                let missing = remote.read_ranges(&[chunk], &mut buf)?.missing;
+
+                for (index, page) in buf[..chunk.len()].chunks_exact(PAGESIZE).enumerate() {
+                    let addr = chunk.start() + index * PAGESIZE;
+                    if missing.iter().any(|missing| missing.contains(addr)) {
+                        stats.unreadable += 1;
+                        continue;
+                    }
+
+                    if (report.total.pages + stats.pages) % sample_every == 0 {
+                        stats.sampled += 1;
+                        stats.compressed_bytes += lz4_flex::block::compress(page).len() as u64;
+                    }
+                    stats.pages += 1;
+
+                    if page.iter().all(|byte| *byte == 0) {
+                        stats.zero += 1;
+                    } else if !seen.insert(checksum(page)) {
+                        stats.duplicate += 1;
+                    }
+                }
+            }
+        }
+
+        report.total.merge(&stats);
+        report.regions.insert(*addr, stats);
+    }
+    Ok(report)
+}
+
+
+#[test]
+fn test_census_counts_zero_and_duplicate_pages() {
+    use crate::mmapfile::MmapFile;
+
+    let mut map = MmapFile::anonymous_private(6 * PAGESIZE).unwrap();
+    // pages 0 and 1 stay zero, 2 and 3 are the same, 4 and 5 are distinct
+    for index in 0..2 {
+        map.page_mut(index).unwrap().write::<u64>(0, 0);
+    }
+    for (index, value) in [7u64, 7, 8, 9].iter().enumerate() {
+        map.page_mut(index + 2).unwrap().write::<u64>(0, *value);
+    }
+    let start = map.addr_range().start();
+
+    let vm = ProcessVMA::this_process().unwrap();
+    let report = census(&vm, &[start], 1).unwrap();
+    let stats = report.regions[&start];
+    assert_eq!(stats.pages, 6);
+    assert_eq!(stats.zero, 2);
+    assert_eq!(stats.duplicate, 1);
+    assert_eq!(stats.sampled, 6);
+    // mostly zero pages compress well
+    assert!(stats.compression_ratio().unwrap() > 10.0);
+    assert_eq!(report.total, stats);
+
+    // 4 ksm pages of 1 frame already free 3 of the 5 duplicates, the 2
+    // zero page mappings 2 of the 3 zero pages
+    let stats = PageCensus {
+        duplicate: 5,
+        zero: 3,
+        ksm: 4,
+        ksm_frames: 1,
+        zero_page: 2,
+        ..PageCensus::default()
+    };
+    assert_eq!(stats.merge_potential(), 3);
+}
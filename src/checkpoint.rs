@@ -0,0 +1,598 @@
+//! Incremental memory checkpoints of a process.
+//!
+//! A base image holds every present or swapped page of the selected regions.
+//! A delta image holds only the pages that became soft-dirty since the
+//! previous image of the chain, the soft-dirty bits are cleared by each
+//! capture, and the ranges of its regions without a present or swapped page.
+//! Merging a chain replays the deltas over the base, dropping the pages a
+//! delta lists as absent, and keeps the pages that are still mapped in the
+//! layout of the last image.
+//!
+//! An image file is laid out as
+//!
+//! ```text
+//! magic (8 bytes)
+//! page data, one 4K page after the other
+//! header (JSON): pid, sequence, parent, region layout and page index
+//! trailer: header offset, header length, header checksum (u64 LE), magic
+//! ```
+//!
+//! Every page and the header carry a 64-bit FNV-1a checksum. A delta links
+//! to its parent by the checksum of the parent's header.
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::File,
+    io::{
+        BufWriter,
+        Write,
+    },
+    os::unix::fs::FileExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    deps::{
+        log::{
+            debug,
+            info,
+            warn,
+        },
+        serde,
+        serde_json,
+    },
+    error::Error,
+    maps::{
+        column::{
+            AddressRange,
+            Perm,
+        },
+        MappedRegion,
+    },
+    pagemaps::{
+        coalesce_ranges,
+        PageSize,
+        ProcessVMA,
+    },
+    quiesce::{
+        Quiesce,
+        QuiesceMethod,
+    },
+    remote::RemoteMemory,
+};
+
+
+pub const PAGESIZE: usize = PageSize::Normal as usize;
+
+/// pages read at once
+const CHUNK_PAGES: usize = 256;
+const MAGIC: &[u8; 8] = b"BHCKPT\x00\x01";
+const TRAILER_LEN: u64 = 3 * 8 + MAGIC.len() as u64;
+
+
+/// 64-bit FNV-1a hash of `bytes`.
+pub fn checksum(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes
+        .iter()
+        .fold(OFFSET_BASIS, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(PRIME))
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ImageKind {
+    Base,
+    Delta,
+}
+
+
+/// One page of an image.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PageEntry {
+    pub addr:     usize,
+    /// offset of the page data in the image file
+    pub offset:   u64,
+    pub checksum: u64,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ImageHeader {
+    pub pid:      usize,
+    pub kind:     ImageKind,
+    /// 0 for the base image, one more than the parent for a delta
+    pub sequence: u64,
+    /// header checksum of the image a delta applies on top of
+    pub parent:   Option<u64>,
+    /// seconds since the unix epoch
+    pub created:  u64,
+    /// the captured regions as they were mapped at the time of the capture
+    pub layout:   Vec<MappedRegion>,
+    /// sorted by address
+    pub pages:    Vec<PageEntry>,
+    /// ranges of the layout without a present or swapped page, such as
+    /// discarded pages, only recorded by deltas
+    #[serde(default)]
+    pub absent:   Vec<AddressRange>,
+}
+
+
+/// An image file opened for reading.
+#[derive(Debug)]
+pub struct Image {
+    path:     PathBuf,
+    file:     File,
+    header:   ImageHeader,
+    checksum: u64,
+}
+
+
+impl Image {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let invalid = |reason: String| {
+            Error::InvalidImage {
+                path: path.to_path_buf(),
+                reason,
+            }
+        };
+
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut magic = [0u8; 8];
+        if len < MAGIC.len() as u64 + TRAILER_LEN {
+            return Err(invalid(format!("{} bytes is too short for an image", len)));
+        }
+        file.read_exact_at(&mut magic, 0)?;
+        if &magic != MAGIC {
+            return Err(invalid(format!("bad magic {:?}", magic)));
+        }
+
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        file.read_exact_at(&mut trailer, len - TRAILER_LEN)?;
+        let word = |index: usize| {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&trailer[index * 8..(index + 1) * 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let (header_offset, header_len, header_checksum) = (word(0), word(1), word(2));
+        if &trailer[24..] != MAGIC || header_offset.saturating_add(header_len) > len - TRAILER_LEN {
+            return Err(invalid("bad trailer".to_string()));
+        }
+
+        let mut bytes = vec![0u8; usize::try_from(header_len)?];
+        file.read_exact_at(&mut bytes, header_offset)?;
+        if checksum(&bytes) != header_checksum {
+            return Err(invalid("header checksum mismatch".to_string()));
+        }
+        let header = serde_json::from_slice(&bytes).map_err(|err| invalid(err.to_string()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            header,
+            checksum: header_checksum,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub const fn header(&self) -> &ImageHeader {
+        &self.header
+    }
+
+    /// The checksum of the header, which deltas refer to their parent by.
+    pub const fn checksum(&self) -> u64 {
+        self.checksum
+    }
+
+    /// Read the data of a page of this image, checking its checksum.
+    pub fn read_page(
+        &self,
+        entry: &PageEntry,
+    ) -> Result<Vec<u8>, Error> {
+        let mut page = vec![0u8; PAGESIZE];
+        self.file.read_exact_at(&mut page, entry.offset)?;
+        if checksum(&page) != entry.checksum {
+            return Err(Error::InvalidImage {
+                path:   self.path.clone(),
+                reason: format!("checksum mismatch of page {:#x}", entry.addr),
+            });
+        }
+        Ok(page)
+    }
+
+    /// Check the checksum of every page.
+    pub fn verify(&self) -> Result<(), Error> {
+        for entry in self.header.pages.iter() {
+            self.read_page(entry)?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Check that `images` are a base image followed by its deltas in order.
+pub fn verify_chain(images: &[Image]) -> Result<(), Error> {
+    for (index, image) in images.iter().enumerate() {
+        let header = image.header();
+        let reason = match (index, header.kind, index.checked_sub(1).map(|parent| &images[parent])) {
+            (0, ImageKind::Base, _) => None,
+            (0, ImageKind::Delta, _) => Some("the chain does not start with a base image".to_string()),
+            (_, ImageKind::Base, _) => Some("a base image can only start a chain".to_string()),
+            (_, ImageKind::Delta, Some(parent)) if header.pid != parent.header().pid => {
+                Some(format!("pid {} differs from the parent pid {}", header.pid, parent.header().pid))
+            }
+            (_, ImageKind::Delta, Some(parent))
+                if header.parent != Some(parent.checksum()) || header.sequence != parent.header().sequence + 1 =>
+            {
+                Some(format!("not a delta of {:?}", parent.path()))
+            }
+            (_, ImageKind::Delta, _) => None,
+        };
+        if let Some(reason) = reason {
+            return Err(Error::InvalidImage {
+                path: image.path().to_path_buf(),
+                reason,
+            });
+        }
+    }
+    Ok(())
+}
+
+
+/// Writes page data followed by the header and trailer.
+struct ImageWriter {
+    out:    BufWriter<File>,
+    offset: u64,
+    pages:  Vec<PageEntry>,
+}
+
+
+impl ImageWriter {
+    fn create(path: &Path) -> Result<Self, Error> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        Ok(Self {
+            out,
+            offset: MAGIC.len() as u64,
+            pages: Vec::new(),
+        })
+    }
+
+    fn push_page(
+        &mut self,
+        addr: usize,
+        page: &[u8],
+    ) -> Result<(), Error> {
+        debug_assert_eq!(page.len(), PAGESIZE);
+        self.out.write_all(page)?;
+        self.pages.push(PageEntry {
+            addr,
+            offset: self.offset,
+            checksum: checksum(page),
+        });
+        self.offset += page.len() as u64;
+        Ok(())
+    }
+
+    fn finish(
+        mut self,
+        mut header: ImageHeader,
+    ) -> Result<(), Error> {
+        header.pages = self.pages;
+        let bytes = serde_json::to_vec(&header).map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+        self.out.write_all(&bytes)?;
+        self.out.write_all(&self.offset.to_le_bytes())?;
+        self.out.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.out.write_all(&checksum(&bytes).to_le_bytes())?;
+        self.out.write_all(MAGIC)?;
+
+        let file = self.out.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+
+/// Capture the regions starting at `regions` of the process of `vm` into an
+/// image at `path`. Without a `parent` a base image of the present and
+/// swapped pages is written, otherwise a delta of the pages that are
+/// soft-dirty since the parent was captured. Either way the soft-dirty bits
+/// of the process are cleared.
+///
+/// With `quiesce` the process is stopped for the whole capture. Without it
+/// pages written during the capture may be captured half way through the
+/// write, and a delta misses a page first written between the scan of its
+/// soft-dirty bits and the clear until the page is written again.
+pub fn capture<P: AsRef<Path>>(
+    vm: &ProcessVMA,
+    regions: &[usize],
+    parent: Option<&Image>,
+    quiesce: Option<QuiesceMethod>,
+    path: P,
+) -> Result<Image, Error> {
+    let path = path.as_ref();
+    let kind = if parent.is_some() { ImageKind::Delta } else { ImageKind::Base };
+    let _quiesce = quiesce.map(|method| Quiesce::new(vm.pid(), method)).transpose()?;
+
+    // a base image clears first so that writes racing with the capture are
+    // in the next delta, a delta clears once the dirty pages are known
+    if kind == ImageKind::Base {
+        vm.clear_refs()?;
+    }
+
+    let mut layout = Vec::new();
+    let mut selected = Vec::new();
+    let mut absent = Vec::new();
+    for addr in regions {
+        let region = vm.region(*addr).ok_or(Error::RegionNotFound { addr: *addr })?;
+        let mapped = region.region();
+        if !mapped.perms().contains(Perm::Read) {
+            debug!("skipping unreadable region {}", mapped.addr_range());
+            continue;
+        }
+
+        let mut pages = Vec::new();
+        let mut empty = Vec::new();
+        for page in region.try_iter(None)? {
+            let page = page?;
+            let has_contents = page.pte.is_present() || page.pte.is_swapped();
+            if has_contents && (kind == ImageKind::Base || page.pte.is_soft_dirty()) {
+                pages.push(page.addr_range);
+            } else if !has_contents && kind == ImageKind::Delta {
+                empty.push(page.addr_range);
+            }
+        }
+        layout.push(mapped.clone());
+        selected.extend(coalesce_ranges(pages));
+        absent.extend(coalesce_ranges(empty));
+    }
+
+    if kind == ImageKind::Delta {
+        vm.clear_refs()?;
+    }
+
+    let mut remote = RemoteMemory::new(vm.pid());
+    let mut writer = ImageWriter::create(path)?;
+    let mut buf = vec![0u8; CHUNK_PAGES * PAGESIZE];
+    for range in selected {
+        for start in (range.start()..range.end()).step_by(CHUNK_PAGES * PAGESIZE) {
+            let chunk = AddressRange::new(start, range.end().min(start + CHUNK_PAGES * PAGESIZE));
+            let missing = remote.read_ranges(&[chunk], &mut buf)?.missing;
+            for (index, page) in buf[..chunk.len()].chunks_exact(PAGESIZE).enumerate() {
+                let addr = chunk.start() + index * PAGESIZE;
+                if missing.iter().any(|missing| missing.contains(addr)) {
+                    warn!("page {:#x} of pid {} was unmapped during the capture", addr, vm.pid());
+                    continue;
+                }
+                writer.push_page(addr, page)?;
+            }
+        }
+    }
+
+    let (sequence, parent) = match parent {
+        Some(parent) => (parent.header().sequence + 1, Some(parent.checksum())),
+        None => (0, None),
+    };
+    writer.finish(ImageHeader {
+        pid: vm.pid(),
+        kind,
+        sequence,
+        parent,
+        created: unix_time(),
+        layout,
+        pages: Vec::new(),
+        absent,
+    })?;
+
+    let image = Image::open(path)?;
+    info!(
+        "captured {:?} image {:?} of pid {}, {} pages",
+        kind,
+        path,
+        vm.pid(),
+        image.header().pages.len()
+    );
+    Ok(image)
+}
+
+
+/// Flatten a chain of images into one base image at `path`, after verifying
+/// the chain and every page. The result has the layout of the last image and
+/// only holds the pages still inside it and not absent from a later delta.
+pub fn merge<P: AsRef<Path>>(
+    images: &[Image],
+    path: P,
+) -> Result<Image, Error> {
+    verify_chain(images)?;
+    let last = match images.last() {
+        Some(last) => last,
+        None => {
+            return Err(Error::InvalidImage {
+                path:   path.as_ref().to_path_buf(),
+                reason: "no images to merge".to_string(),
+            })
+        }
+    };
+
+    let mut pages = BTreeMap::new();
+    for image in images {
+        for range in image.header().absent.iter() {
+            let absent = pages.range(range.start()..range.end()).map(|(addr, _page)| *addr).collect::<Vec<_>>();
+            for addr in absent {
+                pages.remove(&addr);
+            }
+        }
+        for entry in image.header().pages.iter() {
+            pages.insert(entry.addr, (image, entry));
+        }
+    }
+
+    let layout = &last.header().layout;
+    let is_mapped = |addr: usize| layout.iter().any(|region| region.addr_range().contains(addr));
+
+    let mut writer = ImageWriter::create(path.as_ref())?;
+    for (addr, (image, entry)) in pages.into_iter().filter(|(addr, _page)| is_mapped(*addr)) {
+        writer.push_page(addr, &image.read_page(entry)?)?;
+    }
+    writer.finish(ImageHeader {
+        pid:      last.header().pid,
+        kind:     ImageKind::Base,
+        sequence: last.header().sequence,
+        parent:   None,
+        created:  unix_time(),
+        layout:   layout.clone(),
+        pages:    Vec::new(),
+        absent:   Vec::new(),
+    })?;
+
+    Image::open(path)
+}
+
+
+/// A directory of the images of one chain, named by sequence number.
+#[derive(Clone, Debug)]
+pub struct ChainDir {
+    path: PathBuf,
+}
+
+
+impl ChainDir {
+    const EXTENSION: &'static str = "img";
+
+    /// Open the directory at `path`, creating it if needed.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        std::fs::create_dir_all(path.as_ref())?;
+        Self::open(path)
+    }
+
+    /// Open the existing directory at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        if !path.as_ref().is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{:?} is not a directory", path.as_ref()),
+            )
+            .into());
+        }
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    /// The paths of the images in chain order.
+    pub fn images(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut images = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().map(|ext| ext == Self::EXTENSION).unwrap_or(false) {
+                images.push(path);
+            }
+        }
+        images.sort();
+        Ok(images)
+    }
+
+    /// Capture the next image of the chain: a base image when the directory
+    /// is empty and a delta of the last image otherwise.
+    pub fn checkpoint(
+        &self,
+        vm: &ProcessVMA,
+        regions: &[usize],
+        quiesce: Option<QuiesceMethod>,
+    ) -> Result<Image, Error> {
+        let parent = match self.images()?.last() {
+            Some(path) => Some(Image::open(path)?),
+            None => None,
+        };
+        if let Some(parent) = parent.as_ref() {
+            if parent.header().pid != vm.pid() {
+                return Err(Error::InvalidImage {
+                    path:   parent.path().to_path_buf(),
+                    reason: format!("the chain is of pid {}, not {}", parent.header().pid, vm.pid()),
+                });
+            }
+        }
+
+        let sequence = parent.as_ref().map(|parent| parent.header().sequence + 1).unwrap_or(0);
+        let path = self.path.join(format!("{:08}.{}", sequence, Self::EXTENSION));
+        capture(vm, regions, parent.as_ref(), quiesce, path)
+    }
+}
+
+
+#[test]
+fn test_merged_chain_holds_latest_pages() {
+    use crate::mmapfile::{
+        Advice,
+        MmapFile,
+    };
+
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let dir = std::env::temp_dir().join(format!("beholder-checkpoint-test-{}", std::process::id()));
+    assert!(ChainDir::open(&dir).is_err());
+    let chain = ChainDir::create(&dir).unwrap();
+
+    let mut map = MmapFile::anonymous_private(4 * PAGESIZE).unwrap();
+    for index in 0..4 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+    let vm = ProcessVMA::this_process().unwrap();
+
+    assert!(matches!(
+        chain.checkpoint(&vm, &[start], Some(QuiesceMethod::Signal)),
+        Err(Error::Quiesce { .. })
+    ));
+    let base = chain.checkpoint(&vm, &[start], None).unwrap();
+    assert_eq!(base.header().kind, ImageKind::Base);
+    let addrs = base.header().pages.iter().map(|entry| entry.addr).collect::<Vec<_>>();
+    for index in 0..4 {
+        assert!(addrs.contains(&(start + index * PAGESIZE)));
+    }
+
+    map.page_mut(2).unwrap().write::<u64>(0, 0xdead_beef);
+    map.advise(3..4, Advice::DontNeed).unwrap();
+    let delta = chain.checkpoint(&vm, &[start], None).unwrap();
+    assert_eq!(delta.header().kind, ImageKind::Delta);
+    assert_eq!(delta.header().parent, Some(base.checksum()));
+    assert_eq!(delta.header().absent, vec![AddressRange::new(start + 3 * PAGESIZE, start + 4 * PAGESIZE)]);
+
+    let images = chain.images().unwrap().iter().map(|path| Image::open(path).unwrap()).collect::<Vec<_>>();
+    let merged = merge(&images, dir.join("merged")).unwrap();
+    merged.verify().unwrap();
+    let entry = merged.header().pages.iter().find(|entry| entry.addr == start + 2 * PAGESIZE).unwrap();
+    let page = merged.read_page(entry).unwrap();
+    let merged_addrs = merged.header().pages.iter().map(|entry| entry.addr).collect::<Vec<_>>();
+    assert_eq!(merged_addrs, vec![start, start + PAGESIZE, start + 2 * PAGESIZE]);
+    if soft_dirty.tracked() {
+        let delta_addrs = delta.header().pages.iter().map(|entry| entry.addr).collect::<Vec<_>>();
+        assert_eq!(delta_addrs, vec![start + 2 * PAGESIZE]);
+        assert_eq!(page[..8], 0xdead_beef_u64.to_ne_bytes());
+    }
+
+    // flip the bits of the first byte of the first page
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(merged.path()).unwrap();
+    let mut byte = [0u8];
+    file.read_exact_at(&mut byte, MAGIC.len() as u64).unwrap();
+    file.write_all_at(&[!byte[0]], MAGIC.len() as u64).unwrap();
+    assert!(matches!(merged.verify(), Err(Error::InvalidImage { .. })));
+    assert!(matches!(verify_chain(&images[1..]), Err(Error::InvalidImage { .. })));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,515 @@
+//! Page content hashing to tell real modifications from spurious dirtiness.
+//!
+//! The soft-dirty bit is set by any write fault, even one that stores the
+//! bytes already there, and for every page of a VMA after it is merged or
+//! moved. A [`ContentTracker`] hashes the contents of the tracked pages when
+//! tracking starts and, on each collect, re-hashes only the soft-dirty pages:
+//! a page whose hash changed was modified, a page whose hash is the same was
+//! spuriously dirty.
+//!
+//! Given a memory budget the tracker also keeps shadow copies of as many
+//! tracked pages as fit, and diffs each dirty page against its shadow to find
+//! which bytes and cache lines changed, see [`PageChange`].
+use std::collections::BTreeMap;
+
+use crate::{
+    checkpoint::checksum,
+    deps::{
+        log::debug,
+        serde,
+    },
+    error::Error,
+    maps::column::{
+        AddressRange,
+        Perm,
+    },
+    pagemaps::{
+        coalesce_ranges,
+        PageSize,
+        ProcessVMA,
+    },
+    remote::RemoteMemory,
+};
+
+
+pub const PAGESIZE: usize = PageSize::Normal as usize;
+pub const CACHELINE: usize = 64;
+/// pages read at once
+const CHUNK_PAGES: usize = 256;
+
+
+/// How a [`ContentTracker`] reads the tracked pages.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum HashSource {
+    /// dereference the pages directly, only for regions of the calling
+    /// process that stay mapped while tracked, e.g. of a
+    /// [`crate::mmapfile::MmapFile`]
+    InProcess,
+    /// `process_vm_readv(2)`, see [`RemoteMemory`]
+    Remote,
+}
+
+
+/// Classification of the soft-dirty pages of a collect.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ContentStats {
+    pub dirty:         u64,
+    /// dirty pages whose contents changed
+    pub modified:      u64,
+    /// dirty pages whose contents are the same as before
+    pub spurious:      u64,
+    /// dirty pages without a previous hash, e.g. newly populated
+    pub untracked:     u64,
+    /// dirty pages that were unmapped before they could be read
+    pub unreadable:    u64,
+    /// dirty pages diffed against a shadow copy
+    pub shadowed:      u64,
+    /// bytes that differ from the shadow copies
+    pub changed_bytes: u64,
+    /// cache lines with a changed byte
+    pub changed_lines: u64,
+}
+
+
+impl ContentStats {
+    /// The fraction of the dirty pages that did not change, the share of
+    /// replication bandwidth spent on pages that need no copy.
+    pub fn false_dirty_ratio(&self) -> f64 {
+        if self.dirty == 0 {
+            0.0
+        } else {
+            self.spurious as f64 / self.dirty as f64
+        }
+    }
+
+    /// The bytes of the shadowed dirty pages per changed byte, how much a
+    /// page granular copy writes over a byte granular delta. `None` when no
+    /// byte changed.
+    pub fn write_amplification(&self) -> Option<f64> {
+        if self.changed_bytes == 0 {
+            None
+        } else {
+            Some((self.shadowed * PAGESIZE as u64) as f64 / self.changed_bytes as f64)
+        }
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &ContentStats,
+    ) {
+        self.dirty += other.dirty;
+        self.modified += other.modified;
+        self.spurious += other.spurious;
+        self.untracked += other.untracked;
+        self.unreadable += other.unreadable;
+        self.shadowed += other.shadowed;
+        self.changed_bytes += other.changed_bytes;
+        self.changed_lines += other.changed_lines;
+    }
+}
+
+
+/// Where a modified page differs from its shadow copy.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PageChange {
+    pub addr:          usize,
+    pub changed_bytes: usize,
+    /// bit `n` is set when cache line `n` of the page changed
+    pub lines:         u64,
+}
+
+
+impl PageChange {
+    pub fn diff(
+        addr: usize,
+        shadow: &[u8],
+        page: &[u8],
+    ) -> Self {
+        assert_eq!(shadow.len(), PAGESIZE);
+        assert_eq!(page.len(), PAGESIZE);
+
+        let mut change = PageChange {
+            addr,
+            changed_bytes: 0,
+            lines: 0,
+        };
+        for (line, (old, new)) in shadow.chunks(CACHELINE).zip(page.chunks(CACHELINE)).enumerate() {
+            let changed = old.iter().zip(new).filter(|(old, new)| old != new).count();
+            if changed > 0 {
+                change.changed_bytes += changed;
+                change.lines |= 1 << line;
+            }
+        }
+        change
+    }
+
+    pub const fn changed_lines(&self) -> u32 {
+        self.lines.count_ones()
+    }
+}
+
+
+/// The outcome of [`ContentTracker::collect`], per region by start address.
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ContentReport {
+    pub regions: BTreeMap<usize, ContentStats>,
+    pub total:   ContentStats,
+    /// the modified pages with a shadow copy in address order
+    pub changes: Vec<PageChange>,
+}
+
+
+/// Tracks the contents of the pages of some regions of a process.
+///
+/// Starting and collecting clear the soft-dirty bits of the whole process.
+/// Starting and collecting are unsafe because of [`HashSource::InProcess`],
+/// which dereferences the tracked pages directly.
+#[derive(Debug)]
+pub struct ContentTracker {
+    pid:         usize,
+    source:      HashSource,
+    remote:      RemoteMemory,
+    /// the tracked address ranges, the regions when tracking started
+    tracked:     Vec<AddressRange>,
+    /// hash of every tracked page by address
+    hashes:      BTreeMap<usize, u64>,
+    /// shadow copies of the first pages to fit the budget by address
+    shadows:     BTreeMap<usize, Box<[u8]>>,
+    /// pages to keep shadow copies of at most
+    max_shadows: usize,
+}
+
+
+impl ContentTracker {
+    /// Start tracking the regions starting at `regions`: clear the soft-dirty
+    /// bits and hash every present or swapped page.
+    ///
+    /// # Safety
+    /// With [`HashSource::InProcess`] the tracked regions must stay mapped
+    /// and readable until the tracker is dropped.
+    pub unsafe fn start(
+        vm: &ProcessVMA,
+        regions: &[usize],
+        source: HashSource,
+    ) -> Result<Self, Error> {
+        Self::with_shadows(vm, regions, source, 0)
+    }
+
+    /// Like [`ContentTracker::start`], also keeping shadow copies of up to
+    /// `budget` bytes of pages, newly populated pages included while the
+    /// budget lasts.
+    ///
+    /// # Safety
+    /// See [`ContentTracker::start`].
+    pub unsafe fn with_shadows(
+        vm: &ProcessVMA,
+        regions: &[usize],
+        source: HashSource,
+        budget: usize,
+    ) -> Result<Self, Error> {
+        if source == HashSource::InProcess && vm.pid() != std::process::id() as usize {
+            return Err(Error::NotCallingProcess { pid: vm.pid() });
+        }
+
+        let mut tracked = Vec::new();
+        for addr in regions {
+            let region = vm.region(*addr).ok_or(Error::RegionNotFound { addr: *addr })?;
+            if region.region().perms().contains(Perm::Read) {
+                tracked.push(*region.region().addr_range());
+            }
+        }
+
+        let mut this = Self {
+            pid: vm.pid(),
+            source,
+            remote: RemoteMemory::new(vm.pid()),
+            tracked,
+            hashes: BTreeMap::new(),
+            shadows: BTreeMap::new(),
+            max_shadows: budget / PAGESIZE,
+        };
+
+        vm.clear_refs()?;
+        let pages = this.scan(vm, false)?;
+        let Self {
+            source,
+            remote,
+            hashes,
+            shadows,
+            max_shadows,
+            ..
+        } = &mut this;
+        for (_region, pages) in pages {
+            read_pages(*source, remote, &pages, |addr, page| {
+                if let Some(page) = page {
+                    hashes.insert(addr, checksum(page));
+                    shadow(shadows, *max_shadows, addr, page);
+                }
+            })?;
+        }
+        debug!(
+            "tracking {} pages of pid {}, {} shadowed",
+            this.hashes.len(),
+            this.pid,
+            this.shadows.len()
+        );
+        Ok(this)
+    }
+
+    pub const fn pid(&self) -> usize {
+        self.pid
+    }
+
+    /// Number of pages with a known hash.
+    pub fn pages(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Number of pages with a shadow copy.
+    pub fn shadowed_pages(&self) -> usize {
+        self.shadows.len()
+    }
+
+    /// Re-hash the soft-dirty pages of the tracked regions, classify them,
+    /// diff the shadowed ones and clear the soft-dirty bits.
+    ///
+    /// The pages are hashed before the bits are cleared, a page written in
+    /// between is not reported until it is written again. Holding a
+    /// [`crate::quiesce::Quiesce`] across the collect closes that window.
+    ///
+    /// # Safety
+    /// See [`ContentTracker::start`].
+    pub unsafe fn collect(
+        &mut self,
+        vm: &ProcessVMA,
+    ) -> Result<ContentReport, Error> {
+        let pages = self.scan(vm, true)?;
+
+        let Self {
+            source,
+            remote,
+            hashes,
+            shadows,
+            max_shadows,
+            ..
+        } = self;
+        let mut report = ContentReport::default();
+        for (region, pages) in pages {
+            let stats = report.regions.entry(region).or_default();
+            let changes = &mut report.changes;
+            read_pages(*source, remote, &pages, |addr, page| {
+                stats.dirty += 1;
+                let page = match page {
+                    Some(page) => page,
+                    None => {
+                        stats.unreadable += 1;
+                        hashes.remove(&addr);
+                        shadows.remove(&addr);
+                        return;
+                    }
+                };
+
+                let hash = checksum(page);
+                match hashes.insert(addr, hash) {
+                    Some(previous) if hash == previous => stats.spurious += 1,
+                    Some(_previous) => stats.modified += 1,
+                    None => stats.untracked += 1,
+                }
+                if let Some(shadow) = shadows.get(&addr) {
+                    let change = PageChange::diff(addr, shadow, page);
+                    stats.shadowed += 1;
+                    stats.changed_bytes += change.changed_bytes as u64;
+                    stats.changed_lines += u64::from(change.changed_lines());
+                    if change.changed_bytes > 0 {
+                        changes.push(change);
+                    }
+                }
+                shadow(shadows, *max_shadows, addr, page);
+            })?;
+            report.total.merge(stats);
+        }
+        vm.clear_refs()?;
+        Ok(report)
+    }
+
+    /// The page addresses of the tracked ranges by region start, the
+    /// soft-dirty ones only when `dirty_only`.
+    fn scan(
+        &self,
+        vm: &ProcessVMA,
+        dirty_only: bool,
+    ) -> Result<BTreeMap<usize, Vec<usize>>, Error> {
+        let mut pages = BTreeMap::new();
+        for range in self.tracked.iter() {
+            for region in vm.regions_overlapping(range) {
+                let start = region.region().addr_range().start();
+                for page in region.try_iter(Some(PageSize::Normal))? {
+                    let page = page?;
+                    let addr = page.addr_range.start();
+                    let has_contents = page.pte.is_present() || page.pte.is_swapped();
+                    if range.contains(addr) && has_contents && (!dirty_only || page.pte.is_soft_dirty()) {
+                        pages.entry(start).or_insert_with(Vec::new).push(addr);
+                    }
+                }
+            }
+        }
+        Ok(pages)
+    }
+}
+
+
+/// Keep a copy of `page` as the shadow of `addr` if it has one or there are
+/// fewer than `max_shadows`, only the pages kept are copied.
+fn shadow(
+    shadows: &mut BTreeMap<usize, Box<[u8]>>,
+    max_shadows: usize,
+    addr: usize,
+    page: &[u8],
+) {
+    let room = shadows.len() < max_shadows;
+    match shadows.get_mut(&addr) {
+        Some(shadow) => shadow.copy_from_slice(page),
+        None if room => {
+            shadows.insert(addr, Box::from(page));
+        }
+        None => {}
+    }
+}
+
+
+/// Read the sorted pages at `addrs` from `source`, `visit` gets `None` for a
+/// page that could not be read.
+fn read_pages<F>(
+    source: HashSource,
+    remote: &mut RemoteMemory,
+    addrs: &[usize],
+    mut visit: F,
+) -> Result<(), Error>
+where
+    F: FnMut(usize, Option<&[u8]>),
+{
+    let ranges = coalesce_ranges(addrs.iter().map(|addr| AddressRange::new(*addr, addr + PAGESIZE)));
+    let mut buf = vec![0u8; CHUNK_PAGES * PAGESIZE];
+    for range in ranges {
+        for start in (range.start()..range.end()).step_by(CHUNK_PAGES * PAGESIZE) {
+            let chunk = AddressRange::new(start, range.end().min(start + CHUNK_PAGES * PAGESIZE));
+            let buf = &mut buf[..chunk.len()];
+            let missing = match source {
+                HashSource::InProcess => {
+                    // the callers of start and collect guarantee the tracked
+                    // regions stay mapped
+                    let mapped = unsafe { std::slice::from_raw_parts(chunk.start() as *const u8, chunk.len()) };
+                    buf.copy_from_slice(mapped);
+                    Vec::new()
+                }
+                HashSource::Remote => remote.read_ranges(&[chunk], buf)?.missing,
+            };
+
+            for (index, page) in buf.chunks_exact(PAGESIZE).enumerate() {
+                let addr = chunk.start() + index * PAGESIZE;
+                if missing.iter().any(|missing| missing.contains(addr)) {
+                    visit(addr, None);
+                } else {
+                    visit(addr, Some(page));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[test]
+fn test_rewritten_pages_are_spurious() {
+    use crate::mmapfile::MmapFile;
+
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = MmapFile::anonymous_private(4 * PAGESIZE).unwrap();
+    for index in 0..4 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+
+    for source in [HashSource::InProcess, HashSource::Remote].iter() {
+        let vm = ProcessVMA::this_process().unwrap();
+        // the map outlives the tracker
+        let mut tracker = unsafe { ContentTracker::start(&vm, &[start], *source) }.unwrap();
+        assert_eq!(tracker.pages(), 4);
+
+        let value = map.page_mut(0).unwrap().read::<u64>(0);
+        map.page_mut(0).unwrap().write::<u64>(0, value);
+        map.page_mut(1).unwrap().write::<u64>(0, value + 10);
+
+        let report = unsafe { tracker.collect(&vm) }.unwrap();
+        if soft_dirty.tracked() {
+            assert_eq!(report.total.dirty, 2);
+            assert_eq!(report.total.modified, 1);
+            assert_eq!(report.total.spurious, 1);
+            assert_eq!(report.total.false_dirty_ratio(), 0.5);
+        }
+    }
+
+    let mut child = std::process::Command::new("sleep").arg("10").spawn().unwrap();
+    let vm = ProcessVMA::with_pid(child.id() as usize).unwrap();
+    let addr = vm.maps().iter().map(|(addr, _region)| *addr).next().unwrap();
+    assert!(matches!(
+        unsafe { ContentTracker::start(&vm, &[addr], HashSource::InProcess) },
+        Err(Error::NotCallingProcess { .. })
+    ));
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let stats = ContentStats {
+        dirty: 4,
+        modified: 1,
+        spurious: 3,
+        ..ContentStats::default()
+    };
+    assert_eq!(stats.false_dirty_ratio(), 0.75);
+}
+
+
+#[test]
+fn test_shadowed_pages_are_diffed() {
+    use crate::mmapfile::MmapFile;
+
+    let shadow = vec![0u8; PAGESIZE];
+    let mut page = shadow.clone();
+    page[0] = 1;
+    page[63] = 1;
+    page[64] = 1;
+    page[PAGESIZE - 1] = 1;
+    let change = PageChange::diff(0x1000, &shadow, &page);
+    assert_eq!(change.changed_bytes, 4);
+    assert_eq!(change.lines, 0b11 | 1 << 63);
+    assert_eq!(change.changed_lines(), 3);
+
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = MmapFile::anonymous_private(4 * PAGESIZE).unwrap();
+    for index in 0..4 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+
+    let vm = ProcessVMA::this_process().unwrap();
+    let mut tracker = unsafe { ContentTracker::with_shadows(&vm, &[start], HashSource::Remote, 2 * PAGESIZE) }.unwrap();
+    assert_eq!(tracker.shadowed_pages(), 2);
+
+    map.page_mut(0).unwrap().write::<u64>(128, u64::max_value());
+    map.page_mut(3).unwrap().write::<u64>(0, 0);
+
+    let report = unsafe { tracker.collect(&vm) }.unwrap();
+    if soft_dirty.tracked() {
+        assert_eq!(report.total.dirty, 2);
+        assert_eq!(report.total.modified, 2);
+        // only the first two pages fit the budget
+        assert_eq!(report.changes.len(), 1);
+        assert_eq!(report.changes[0].addr, start);
+        assert_eq!(report.changes[0].changed_bytes, 8);
+        assert_eq!(report.changes[0].lines, 1 << 2);
+        assert_eq!(report.total.changed_lines, 1);
+        assert_eq!(report.total.write_amplification(), Some((report.total.shadowed * 4096) as f64 / 8.0));
+    }
+    // the shadows are copied in place, never beyond the budget
+    assert_eq!(tracker.shadowed_pages(), 2);
+}
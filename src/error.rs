@@ -1,4 +1,7 @@
-use crate::deps::thiserror;
+use crate::deps::{
+    nix,
+    thiserror,
+};
 
 
 
@@ -19,6 +22,54 @@ pub enum Error {
         backtrace: std::backtrace::Backtrace,
     },
 
+    #[error("a system call failed: {source}")]
+    Nix {
+        #[from]
+        source: nix::Error,
+        #[cfg(feature = "nightly")]
+        backtrace: std::backtrace::Backtrace,
+    },
+
+    #[error("no mapped region contains address {addr:#x}")]
+    RegionNotFound { addr: usize },
+
+    #[error("the mapping is shared by {handles} handles, the operation requires a unique handle")]
+    MappingShared { handles: usize },
+
+    #[error("{path:?} is not on a hugetlbfs mount, a {page_size} byte page size was requested")]
+    NotHugetlbfs {
+        path:      std::path::PathBuf,
+        page_size: usize,
+    },
+
+    #[error("page size mismatch for {path:?}: requested {requested} bytes but found {actual} bytes")]
+    PageSizeMismatch {
+        path:      std::path::PathBuf,
+        requested: usize,
+        actual:    usize,
+    },
+
+    #[error("no free {page_size} byte huge pages to map {len} bytes, see /proc/sys/vm/nr_hugepages")]
+    HugePagesUnavailable {
+        page_size: usize,
+        len:       usize,
+    },
+
+    #[error("unable to stop pid {pid}: {reason}")]
+    Quiesce { pid: usize, reason: String },
+
+    #[error("unable to estimate the working set of pid {pid}: {reason}")]
+    WorkingSet { pid: usize, reason: String },
+
+    #[error("pid {pid} is not the calling process, its memory cannot be read in-process")]
+    NotCallingProcess { pid: usize },
+
+    #[error("invalid checkpoint image {path:?}: {reason}")]
+    InvalidImage {
+        path:   std::path::PathBuf,
+        reason: String,
+    },
+
     #[error("parsing {typename} from {value:?}, reason: {reason:}")]
     Parse {
         value:    String,
@@ -0,0 +1,385 @@
+//! Page hotness over repeated sampling rounds.
+//!
+//! A [`HotnessTracker`] samples the accessed state and the soft-dirty bit of
+//! every page of some regions once per round, see [`crate::wss`], and counts
+//! per page in how many rounds it was present, accessed and written. The
+//! counters take three bytes per page and hold [`MAX_ROUNDS`] rounds, they are
+//! only allocated for chunks of [`COUNTER_CHUNK`] pages with a page that was
+//! present or swapped, so sparse regions stay cheap to track. Once enough
+//! rounds were sampled, [`HotnessTracker::classify`] turns the counts into a
+//! hot, warm or cold class per page by the fraction of the rounds the page
+//! was accessed or written in.
+use std::{
+    collections::BTreeMap,
+    fmt,
+};
+
+use crate::{
+    deps::{
+        log::debug,
+        serde,
+    },
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        coalesce_ranges,
+        PageSize,
+        ProcessVMA,
+    },
+    wss::{
+        AccessSampler,
+        WssMethod,
+    },
+};
+
+
+pub const PAGESIZE: usize = PageSize::Normal as usize;
+/// pages of a region whose counters are allocated together
+pub const COUNTER_CHUNK: usize = 64;
+/// rounds a [`HotnessTracker`] samples at most, what its counters hold
+pub const MAX_ROUNDS: usize = u8::MAX as usize;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Hotness {
+    Cold,
+    Warm,
+    Hot,
+}
+
+
+impl fmt::Display for Hotness {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            Hotness::Cold => "cold",
+            Hotness::Warm => "warm",
+            Hotness::Hot => "hot",
+        }
+        .fmt(f)
+    }
+}
+
+
+/// The fractions of the rounds a page must be accessed or written in to be
+/// hot or warm, any other page that was present is cold.
+#[derive(Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HotnessThresholds {
+    pub hot:  f64,
+    pub warm: f64,
+}
+
+
+impl Default for HotnessThresholds {
+    fn default() -> Self {
+        Self { hot: 0.5, warm: 0.1 }
+    }
+}
+
+
+impl HotnessThresholds {
+    pub fn classify(
+        &self,
+        ratio: f64,
+    ) -> Hotness {
+        if ratio >= self.hot {
+            Hotness::Hot
+        } else if ratio >= self.warm && ratio > 0.0 {
+            Hotness::Warm
+        } else {
+            Hotness::Cold
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct PageCounter {
+    present:  u8,
+    /// rounds the page was accessed or written in
+    accessed: u8,
+    written:  u8,
+}
+
+
+/// The counters of the pages of one region.
+#[derive(Clone, Debug)]
+struct RegionCounters {
+    range:  AddressRange,
+    /// the counters by chunk index, a chunk starts at page index
+    /// `chunk * COUNTER_CHUNK` of the region
+    chunks: BTreeMap<usize, Box<[PageCounter; COUNTER_CHUNK]>>,
+}
+
+
+impl RegionCounters {
+    fn new(range: AddressRange) -> Self {
+        Self {
+            range,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// The counter of the page at `addr`, allocating its chunk if needed.
+    fn counter_mut(
+        &mut self,
+        addr: usize,
+    ) -> &mut PageCounter {
+        let index = (addr - self.range.start()) / PAGESIZE;
+        let chunk = self
+            .chunks
+            .entry(index / COUNTER_CHUNK)
+            .or_insert_with(|| Box::new([PageCounter::default(); COUNTER_CHUNK]));
+        &mut chunk[index % COUNTER_CHUNK]
+    }
+
+    /// The allocated counters with the address of their page, in address
+    /// order.
+    fn iter(&self) -> impl Iterator<Item = (usize, &PageCounter)> + '_ {
+        let start = self.range.start();
+        self.chunks.iter().flat_map(move |(chunk, counters)| {
+            counters
+                .iter()
+                .enumerate()
+                .map(move |(offset, counter)| (start + (chunk * COUNTER_CHUNK + offset) * PAGESIZE, counter))
+        })
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HotnessHistogram {
+    pub hot:     u64,
+    pub warm:    u64,
+    pub cold:    u64,
+    /// pages written in at least one round
+    pub written: u64,
+}
+
+
+impl HotnessHistogram {
+    pub fn add(
+        &mut self,
+        hotness: Hotness,
+    ) {
+        match hotness {
+            Hotness::Hot => self.hot += 1,
+            Hotness::Warm => self.warm += 1,
+            Hotness::Cold => self.cold += 1,
+        }
+    }
+
+    pub const fn pages(&self) -> u64 {
+        self.hot + self.warm + self.cold
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &HotnessHistogram,
+    ) {
+        self.hot += other.hot;
+        self.warm += other.warm;
+        self.cold += other.cold;
+        self.written += other.written;
+    }
+}
+
+
+/// The outcome of [`HotnessTracker::classify`], per region by start address.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HotnessReport {
+    pub rounds:      usize,
+    pub regions:     BTreeMap<usize, HotnessHistogram>,
+    pub total:       HotnessHistogram,
+    /// the cold pages of each region, coalesced
+    pub cold_ranges: BTreeMap<usize, Vec<AddressRange>>,
+}
+
+
+/// Counts the accesses and writes of the pages of some regions of a process
+/// over sampling rounds.
+///
+/// Every round resets the accessed state and the soft-dirty bits of the
+/// whole process.
+#[derive(Debug)]
+pub struct HotnessTracker {
+    pid:     usize,
+    method:  WssMethod,
+    regions: BTreeMap<usize, RegionCounters>,
+    rounds:  usize,
+    sampler: AccessSampler,
+}
+
+
+impl HotnessTracker {
+    /// Start tracking the regions starting at `regions`. Fails unless the
+    /// page flags are readable, see [`crate::wss`].
+    pub fn new(
+        pid: usize,
+        regions: &[usize],
+        method: WssMethod,
+    ) -> Result<Self, Error> {
+        crate::wss::require_page_flags(pid)?;
+        let vm = ProcessVMA::with_pid(pid)?;
+        let mut counters = BTreeMap::new();
+        for addr in regions {
+            let region = vm.region(*addr).ok_or(Error::RegionNotFound { addr: *addr })?;
+            counters.insert(*addr, RegionCounters::new(*region.region().addr_range()));
+        }
+
+        Ok(Self {
+            pid,
+            method,
+            regions: counters,
+            rounds: 0,
+            sampler: AccessSampler::reset(&vm, regions, method)?,
+        })
+    }
+
+    pub const fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub const fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// Count the pages accessed and written since the previous round, then
+    /// reset their state for the next one. Fails once [`MAX_ROUNDS`] rounds
+    /// were sampled.
+    pub fn sample(&mut self) -> Result<(), Error> {
+        if self.rounds >= MAX_ROUNDS {
+            return Err(Error::WorkingSet {
+                pid:    self.pid,
+                reason: format!("the page counters hold {} rounds at most", MAX_ROUNDS),
+            });
+        }
+        let vm = ProcessVMA::with_pid(self.pid)?;
+        let mut live = Vec::new();
+        for (addr, counters) in self.regions.iter_mut() {
+            let range = counters.range;
+            // a region may be split, grown or unmapped since the start
+            for region in vm.regions_overlapping(&range) {
+                live.push(region.region().addr_range().start());
+                for page in region.try_iter(Some(PageSize::Normal))? {
+                    let page = page?;
+                    let addr = page.addr_range.start();
+                    if !range.contains(addr) {
+                        continue;
+                    }
+
+                    // pages that were never populated need no counter
+                    if !(page.pte.is_present() || page.pte.is_swapped()) {
+                        continue;
+                    }
+
+                    let accessed = page.pte.is_present() && self.sampler.accessed(&page)?;
+                    let written = page.pte.is_soft_dirty();
+                    let counter = counters.counter_mut(addr);
+                    counter.present = counter.present.saturating_add(1);
+                    if accessed || written {
+                        counter.accessed = counter.accessed.saturating_add(1);
+                    }
+                    if written {
+                        counter.written = counter.written.saturating_add(1);
+                    }
+                }
+            }
+        }
+
+        self.rounds += 1;
+        debug!("sampled round {} of pid {}", self.rounds, self.pid);
+        self.sampler = AccessSampler::reset(&vm, &live, self.method)?;
+        Ok(())
+    }
+
+    /// Classify the pages that were present in any round. Pages never present
+    /// have nothing to classify and are left out.
+    pub fn classify(
+        &self,
+        thresholds: &HotnessThresholds,
+    ) -> HotnessReport {
+        let rounds = self.rounds.max(1);
+        let mut report = HotnessReport {
+            rounds: self.rounds,
+            ..HotnessReport::default()
+        };
+
+        for (addr, counters) in self.regions.iter() {
+            let mut histogram = HotnessHistogram::default();
+            let mut cold = Vec::new();
+            for (start, counter) in counters.iter() {
+                if counter.present == 0 {
+                    continue;
+                }
+                let hotness = thresholds.classify(counter.accessed as f64 / rounds as f64);
+                histogram.add(hotness);
+                histogram.written += (counter.written > 0) as u64;
+                if hotness == Hotness::Cold {
+                    cold.push(AddressRange::new(start, start + PAGESIZE));
+                }
+            }
+
+            report.total.merge(&histogram);
+            report.regions.insert(*addr, histogram);
+            let cold = coalesce_ranges(cold);
+            if !cold.is_empty() {
+                report.cold_ranges.insert(*addr, cold);
+            }
+        }
+        report
+    }
+}
+
+
+#[test]
+fn test_untouched_pages_are_cold() {
+    use crate::mmapfile::MmapFile;
+
+    let thresholds = HotnessThresholds::default();
+    assert_eq!(thresholds.classify(1.0), Hotness::Hot);
+    assert_eq!(thresholds.classify(0.2), Hotness::Warm);
+    assert_eq!(thresholds.classify(0.0), Hotness::Cold);
+    assert_eq!(HotnessThresholds { hot: 0.5, warm: 0.0 }.classify(0.0), Hotness::Cold);
+
+    let _guard = crate::pagemaps::soft_dirty_test_guard();
+    let mut map = MmapFile::anonymous_private(8 * PAGESIZE).unwrap();
+    // pages 0 to 3 are populated, 4 to 7 never present
+    for index in 0..4 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+
+    let mut tracker = HotnessTracker::new(std::process::id() as usize, &[start], WssMethod::Referenced).unwrap();
+    for _round in 0..3 {
+        tracker.sample().unwrap();
+    }
+    assert_eq!(tracker.rounds(), 3);
+    tracker.rounds = MAX_ROUNDS;
+    assert!(matches!(tracker.sample(), Err(Error::WorkingSet { .. })));
+    tracker.rounds = 3;
+
+    let report = tracker.classify(&thresholds);
+    let histogram = report.regions[&start];
+    assert_eq!(histogram.pages(), 4);
+    assert_eq!(histogram.cold, 4);
+    assert_eq!(histogram.written, 0);
+    assert_eq!(report.total, histogram);
+    assert_eq!(report.cold_ranges[&start], vec![AddressRange::new(start, start + 4 * PAGESIZE)]);
+    // the populated pages share one chunk of counters
+    assert_eq!(tracker.regions[&start].chunks.len(), 1);
+
+    // a large region allocates no more chunks than it has populated pages,
+    // including those of neighbours its mapping may have merged with
+    let mut large = MmapFile::anonymous_private(64 << 20).unwrap();
+    large.page_mut(1000).unwrap().write::<u64>(0, 1);
+    let page = large.addr_range().start() + 1000 * PAGESIZE;
+    let large_start = large.addr_range().start();
+    let mut tracker = HotnessTracker::new(std::process::id() as usize, &[large_start], WssMethod::Referenced).unwrap();
+    tracker.sample().unwrap();
+    let report = tracker.classify(&thresholds);
+    assert!(tracker.regions[&large_start].chunks.len() as u64 <= report.regions[&large_start].pages());
+    assert!(report.cold_ranges[&large_start].iter().any(|range| range.contains(page)));
+}
@@ -0,0 +1,93 @@
+//! Information about the running kernel.
+use std::{
+    fmt,
+    str::FromStr,
+};
+
+use crate::{
+    deps::{
+        lazy_static::lazy_static,
+        log::warn,
+        nix::sys::utsname::uname,
+        serde,
+    },
+    error::Error,
+};
+
+
+/// The `major.minor.patch` triple of a kernel release string such as
+/// `5.4.0-1045-aws`. Anything after the numeric prefix is ignored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub struct KernelVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+
+impl KernelVersion {
+    pub const fn new(
+        major: u32,
+        minor: u32,
+        patch: u32,
+    ) -> Self {
+        Self { major, minor, patch }
+    }
+
+    /// The version of the running kernel from `uname(2)`. If the release
+    /// string cannot be parsed every version check is assumed to pass.
+    pub fn running() -> KernelVersion {
+        lazy_static! {
+            static ref RUNNING: KernelVersion = {
+                let utsname = uname();
+                KernelVersion::from_str(utsname.release()).unwrap_or_else(|err| {
+                    warn!("unable to determine the kernel version, reason: {}", err);
+                    KernelVersion::new(u32::max_value(), 0, 0)
+                })
+            };
+        }
+
+        *RUNNING
+    }
+}
+
+
+impl FromStr for KernelVersion {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let numeric = value
+            .trim()
+            .split(|ch: char| !(ch.is_ascii_digit() || ch == '.'))
+            .next()
+            .unwrap_or("");
+
+        let mut parts = numeric.split('.').map(str::parse::<u32>);
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), patch) => {
+                Ok(KernelVersion::new(
+                    major,
+                    minor,
+                    patch.and_then(Result::ok).unwrap_or(0),
+                ))
+            }
+            _ => {
+                Err(Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<KernelVersion>(),
+                    reason:   "kernel release was not in the form MAJOR.MINOR[.PATCH]".to_string(),
+                })
+            }
+        }
+    }
+}
+
+
+impl fmt::Display for KernelVersion {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
@@ -30,9 +30,26 @@
 //!     20. NOPAGE
 //!     21. KSM
 //!     22. THP
-//!     23. BALLOON
+//!     23. BALLOON (OFFLINE since 5.0)
 //!     24. ZERO_PAGE
 //!     25. IDLE
+//!     26. PGTABLE
+//!
+//!    The raw kernel page flags are reported as well, mostly of interest for
+//!    kernel debugging and not part of the stable ABI (from
+//!    include/linux/kernel-page-flags.h):
+//!
+//!     32. RESERVED
+//!     33. MLOCKED
+//!     34. MAPPEDTODISK (OWNER_2 since 6.12)
+//!     35. PRIVATE
+//!     36. PRIVATE_2
+//!     37. OWNER_PRIVATE
+//!     38. ARCH
+//!     39. UNCACHED
+//!     40. SOFTDIRTY
+//!     41. ARCH_2
+//!     42. ARCH_3
 //!
 //!  * /proc/kpagecgroup.  This file contains a 64-bit inode number of the
 //!    memory cgroup each page is charged to, indexed by PFN. Only available when
@@ -116,15 +133,29 @@
 use std::{
     convert::TryFrom,
     fmt,
-    io::Read,
+    fs::File,
+    io::{
+        BufReader,
+        Read,
+    },
+    mem,
+    num::NonZeroU64,
+    ops::{
+        Bound,
+        RangeBounds,
+    },
+    str::FromStr,
 };
 
 use crate::{
     deps::{
         derive_more,
+        lazy_static::lazy_static,
+        log::warn,
         serde,
     },
     error::Error,
+    kernel::KernelVersion,
 };
 
 #[derive(
@@ -134,7 +165,8 @@ use crate::{
     PartialEq,
     Eq,
     Ord,
-    derive_more::Display,
+    Hash,
+    Default,
     derive_more::From,
     derive_more::Into,
     derive_more::Binary,
@@ -143,13 +175,48 @@ use crate::{
     serde::Serialize,
     serde::Deserialize,
 )]
+#[serde(into = "SerdeKPageFlags", from = "SerdeKPageFlags")]
 #[repr(transparent)]
 pub struct KPageFlags(u64);
 
 
+/// Serialized form of [`KPageFlags`]: the raw value alongside the names of
+/// the flags it decodes to on the running kernel, with any unknown bits as a
+/// hex literal like in the [`fmt::Display`] output. A bare integer is also
+/// accepted when deserializing.
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+enum SerdeKPageFlags {
+    Named { value: u64, flags: Vec<String> },
+    Raw(u64),
+}
+
+
+impl From<KPageFlags> for SerdeKPageFlags {
+    fn from(flags: KPageFlags) -> Self {
+        SerdeKPageFlags::Named {
+            value: flags.0,
+            flags: FlagTable::running().names(flags),
+        }
+    }
+}
+
+
+impl From<SerdeKPageFlags> for KPageFlags {
+    fn from(repr: SerdeKPageFlags) -> Self {
+        match repr {
+            SerdeKPageFlags::Named { value, .. } | SerdeKPageFlags::Raw(value) => KPageFlags(value),
+        }
+    }
+}
+
+
 impl KPageFlags {
     const ACTIVE_BIT: u32 = 6;
     const ANON_BIT: u32 = 12;
+    const ARCH_2_BIT: u32 = 41;
+    const ARCH_3_BIT: u32 = 42;
+    const ARCH_BIT: u32 = 38;
     const BALLOON_BIT: u32 = 23;
     const BUDDY_BIT: u32 = 10;
     const COMPOUND_HEAD_BIT: u32 = 15;
@@ -162,23 +229,268 @@ impl KPageFlags {
     const KSM_BIT: u32 = 21;
     const LOCKED_BIT: u32 = 0;
     const LRU_BIT: u32 = 5;
+    const MAPPEDTODISK_BIT: u32 = 34;
+    const MLOCKED_BIT: u32 = 33;
     const MMAP_BIT: u32 = 11;
     const NOPAGE_BIT: u32 = 20;
+    const OFFLINE_BIT: u32 = 23;
+    const OWNER_2_BIT: u32 = 34;
+    const OWNER_PRIVATE_BIT: u32 = 37;
+    const PGTABLE_BIT: u32 = 26;
+    const PRIVATE_2_BIT: u32 = 36;
+    const PRIVATE_BIT: u32 = 35;
     const RECLAIM_BIT: u32 = 9;
     const REFERENCED_BIT: u32 = 2;
+    const RESERVED_BIT: u32 = 32;
     const SLAB_BIT: u32 = 7;
+    const SOFTDIRTY_BIT: u32 = 40;
     const SWAPBACKED_BIT: u32 = 14;
     const SWAPCACHE_BIT: u32 = 13;
     const THP_BIT: u32 = 22;
+    const UNCACHED_BIT: u32 = 39;
     const UNEVICTABLE_BIT: u32 = 18;
     const UPTODATE_BIT: u32 = 3;
     const WRITEBACK_BIT: u32 = 8;
     const ZERO_PAGE_BIT: u32 = 24;
 
+    /// Every flag bit known to this crate, in bit order, named as in the
+    /// kernel's `include/uapi/linux/kernel-page-flags.h` (bits 0-26) and
+    /// `include/linux/kernel-page-flags.h` (bits 32 and up) without the `KPF_`
+    /// prefix.
+    ///
+    /// A bit can appear twice when the kernel renamed it; use
+    /// [`FlagTable::for_kernel`] to get the names for a given kernel.
+    pub const FLAGS: [FlagInfo; 40] = [
+        FlagInfo::documented("LOCKED", KPageFlags::LOCKED_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("ERROR", KPageFlags::ERROR_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("REFERENCED", KPageFlags::REFERENCED_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("UPTODATE", KPageFlags::UPTODATE_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("DIRTY", KPageFlags::DIRTY_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("LRU", KPageFlags::LRU_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("ACTIVE", KPageFlags::ACTIVE_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("SLAB", KPageFlags::SLAB_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("WRITEBACK", KPageFlags::WRITEBACK_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("RECLAIM", KPageFlags::RECLAIM_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("BUDDY", KPageFlags::BUDDY_BIT, KernelVersion::new(2, 6, 25)),
+        FlagInfo::documented("MMAP", KPageFlags::MMAP_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("ANON", KPageFlags::ANON_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("SWAPCACHE", KPageFlags::SWAPCACHE_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("SWAPBACKED", KPageFlags::SWAPBACKED_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("COMPOUND_HEAD", KPageFlags::COMPOUND_HEAD_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("COMPOUND_TAIL", KPageFlags::COMPOUND_TAIL_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("HUGE", KPageFlags::HUGE_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("UNEVICTABLE", KPageFlags::UNEVICTABLE_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("HWPOISON", KPageFlags::HWPOISON_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("NOPAGE", KPageFlags::NOPAGE_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("KSM", KPageFlags::KSM_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::documented("THP", KPageFlags::THP_BIT, KernelVersion::new(3, 4, 0)),
+        FlagInfo::documented("BALLOON", KPageFlags::BALLOON_BIT, KernelVersion::new(3, 18, 0))
+            .until(KernelVersion::new(5, 0, 0)),
+        FlagInfo::documented("OFFLINE", KPageFlags::OFFLINE_BIT, KernelVersion::new(5, 0, 0)),
+        FlagInfo::documented("ZERO_PAGE", KPageFlags::ZERO_PAGE_BIT, KernelVersion::new(4, 0, 0)),
+        FlagInfo::documented("IDLE", KPageFlags::IDLE_BIT, KernelVersion::new(4, 3, 0)),
+        FlagInfo::documented("PGTABLE", KPageFlags::PGTABLE_BIT, KernelVersion::new(4, 18, 0)),
+        FlagInfo::internal("RESERVED", KPageFlags::RESERVED_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("MLOCKED", KPageFlags::MLOCKED_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("MAPPEDTODISK", KPageFlags::MAPPEDTODISK_BIT, KernelVersion::new(2, 6, 32))
+            .until(KernelVersion::new(6, 12, 0)),
+        FlagInfo::internal("OWNER_2", KPageFlags::OWNER_2_BIT, KernelVersion::new(6, 12, 0)),
+        FlagInfo::internal("PRIVATE", KPageFlags::PRIVATE_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("PRIVATE_2", KPageFlags::PRIVATE_2_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("OWNER_PRIVATE", KPageFlags::OWNER_PRIVATE_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("ARCH", KPageFlags::ARCH_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("UNCACHED", KPageFlags::UNCACHED_BIT, KernelVersion::new(2, 6, 32)),
+        FlagInfo::internal("SOFTDIRTY", KPageFlags::SOFTDIRTY_BIT, KernelVersion::new(4, 2, 0)),
+        FlagInfo::internal("ARCH_2", KPageFlags::ARCH_2_BIT, KernelVersion::new(5, 10, 0)),
+        FlagInfo::internal("ARCH_3", KPageFlags::ARCH_3_BIT, KernelVersion::new(6, 10, 0)),
+    ];
+
+    pub const ACTIVE: KPageFlags = KPageFlags::from_bit(KPageFlags::ACTIVE_BIT);
+    pub const ANON: KPageFlags = KPageFlags::from_bit(KPageFlags::ANON_BIT);
+    pub const BALLOON: KPageFlags = KPageFlags::from_bit(KPageFlags::BALLOON_BIT);
+    pub const BUDDY: KPageFlags = KPageFlags::from_bit(KPageFlags::BUDDY_BIT);
+    pub const COMPOUND_HEAD: KPageFlags = KPageFlags::from_bit(KPageFlags::COMPOUND_HEAD_BIT);
+    pub const COMPOUND_TAIL: KPageFlags = KPageFlags::from_bit(KPageFlags::COMPOUND_TAIL_BIT);
+    pub const DIRTY: KPageFlags = KPageFlags::from_bit(KPageFlags::DIRTY_BIT);
+    pub const ERROR: KPageFlags = KPageFlags::from_bit(KPageFlags::ERROR_BIT);
+    pub const HUGE: KPageFlags = KPageFlags::from_bit(KPageFlags::HUGE_BIT);
+    pub const HWPOISON: KPageFlags = KPageFlags::from_bit(KPageFlags::HWPOISON_BIT);
+    pub const IDLE: KPageFlags = KPageFlags::from_bit(KPageFlags::IDLE_BIT);
+    pub const KSM: KPageFlags = KPageFlags::from_bit(KPageFlags::KSM_BIT);
+    pub const LOCKED: KPageFlags = KPageFlags::from_bit(KPageFlags::LOCKED_BIT);
+    pub const LRU: KPageFlags = KPageFlags::from_bit(KPageFlags::LRU_BIT);
+    pub const MMAP: KPageFlags = KPageFlags::from_bit(KPageFlags::MMAP_BIT);
+    pub const NOPAGE: KPageFlags = KPageFlags::from_bit(KPageFlags::NOPAGE_BIT);
+    pub const RECLAIM: KPageFlags = KPageFlags::from_bit(KPageFlags::RECLAIM_BIT);
+    pub const REFERENCED: KPageFlags = KPageFlags::from_bit(KPageFlags::REFERENCED_BIT);
+    pub const SLAB: KPageFlags = KPageFlags::from_bit(KPageFlags::SLAB_BIT);
+    pub const SWAPBACKED: KPageFlags = KPageFlags::from_bit(KPageFlags::SWAPBACKED_BIT);
+    pub const SWAPCACHE: KPageFlags = KPageFlags::from_bit(KPageFlags::SWAPCACHE_BIT);
+    pub const THP: KPageFlags = KPageFlags::from_bit(KPageFlags::THP_BIT);
+    pub const UNEVICTABLE: KPageFlags = KPageFlags::from_bit(KPageFlags::UNEVICTABLE_BIT);
+    pub const UPTODATE: KPageFlags = KPageFlags::from_bit(KPageFlags::UPTODATE_BIT);
+    pub const WRITEBACK: KPageFlags = KPageFlags::from_bit(KPageFlags::WRITEBACK_BIT);
+    pub const ZERO_PAGE: KPageFlags = KPageFlags::from_bit(KPageFlags::ZERO_PAGE_BIT);
+    pub const OFFLINE: KPageFlags = KPageFlags::from_bit(KPageFlags::OFFLINE_BIT);
+    pub const PGTABLE: KPageFlags = KPageFlags::from_bit(KPageFlags::PGTABLE_BIT);
+    pub const RESERVED: KPageFlags = KPageFlags::from_bit(KPageFlags::RESERVED_BIT);
+    pub const MLOCKED: KPageFlags = KPageFlags::from_bit(KPageFlags::MLOCKED_BIT);
+    pub const MAPPEDTODISK: KPageFlags = KPageFlags::from_bit(KPageFlags::MAPPEDTODISK_BIT);
+    pub const OWNER_2: KPageFlags = KPageFlags::from_bit(KPageFlags::OWNER_2_BIT);
+    pub const PRIVATE: KPageFlags = KPageFlags::from_bit(KPageFlags::PRIVATE_BIT);
+    pub const PRIVATE_2: KPageFlags = KPageFlags::from_bit(KPageFlags::PRIVATE_2_BIT);
+    pub const OWNER_PRIVATE: KPageFlags = KPageFlags::from_bit(KPageFlags::OWNER_PRIVATE_BIT);
+    pub const ARCH: KPageFlags = KPageFlags::from_bit(KPageFlags::ARCH_BIT);
+    pub const UNCACHED: KPageFlags = KPageFlags::from_bit(KPageFlags::UNCACHED_BIT);
+    pub const SOFTDIRTY: KPageFlags = KPageFlags::from_bit(KPageFlags::SOFTDIRTY_BIT);
+    pub const ARCH_2: KPageFlags = KPageFlags::from_bit(KPageFlags::ARCH_2_BIT);
+    pub const ARCH_3: KPageFlags = KPageFlags::from_bit(KPageFlags::ARCH_3_BIT);
+
+    /// The kernel-internal page flags (bits 32 and up). These are not part of
+    /// the stable ABI and may be renamed or repurposed between releases.
+    pub const INTERNAL_MASK: KPageFlags = KPageFlags::new(!0u64 << 32);
+
+    /// \[IO related page flags\]: ERROR, UPTODATE, DIRTY, WRITEBACK
+    pub const IO_MASK: KPageFlags = KPageFlags::ERROR
+        .union(KPageFlags::UPTODATE)
+        .union(KPageFlags::DIRTY)
+        .union(KPageFlags::WRITEBACK);
+    /// \[LRU related page flags\]: LRU, ACTIVE, UNEVICTABLE, REFERENCED,
+    /// RECLAIM, MMAP, ANON, SWAPCACHE, SWAPBACKED
+    pub const LRU_MASK: KPageFlags = KPageFlags::LRU
+        .union(KPageFlags::ACTIVE)
+        .union(KPageFlags::UNEVICTABLE)
+        .union(KPageFlags::REFERENCED)
+        .union(KPageFlags::RECLAIM)
+        .union(KPageFlags::MMAP)
+        .union(KPageFlags::ANON)
+        .union(KPageFlags::SWAPCACHE)
+        .union(KPageFlags::SWAPBACKED);
+    /// Flags describing huge and compound pages: COMPOUND_HEAD,
+    /// COMPOUND_TAIL, HUGE, THP
+    pub const COMPOUND_MASK: KPageFlags = KPageFlags::COMPOUND_HEAD
+        .union(KPageFlags::COMPOUND_TAIL)
+        .union(KPageFlags::HUGE)
+        .union(KPageFlags::THP);
+
     pub const fn new(n: u64) -> Self {
         Self(n)
     }
 
+    const fn from_bit(bit: u32) -> Self {
+        Self(1u64 << bit)
+    }
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Every flag known to this crate on any kernel version.
+    pub const fn all() -> Self {
+        let mut all = 0;
+        let mut i = 0;
+        while i < KPageFlags::FLAGS.len() {
+            all |= 1u64 << KPageFlags::FLAGS[i].bit;
+            i += 1;
+        }
+        Self(all)
+    }
+
+    pub const fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// true if every flag of `other` is also set in `self`
+    pub const fn contains(
+        &self,
+        other: KPageFlags,
+    ) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// true if any flag of `other` is also set in `self`
+    pub const fn intersects(
+        &self,
+        other: KPageFlags,
+    ) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    pub const fn union(
+        self,
+        other: KPageFlags,
+    ) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(
+        self,
+        other: KPageFlags,
+    ) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn difference(
+        self,
+        other: KPageFlags,
+    ) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Flags set in `self` that the running kernel does not define, see
+    /// [`FlagTable::running`].
+    pub fn unknown(&self) -> Self {
+        FlagTable::running().unknown(*self)
+    }
+
+    pub fn insert(
+        &mut self,
+        other: KPageFlags,
+    ) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(
+        &mut self,
+        other: KPageFlags,
+    ) {
+        self.0 &= !other.0;
+    }
+
+    /// Iterate over the flags set in `self` that the running kernel defines,
+    /// one flag at a time, in bit order.
+    pub fn iter(&self) -> Iter<'static> {
+        FlagTable::running().iter(*self)
+    }
+
+    /// Like [`KPageFlags::iter`] but paired with the name of each flag.
+    pub fn iter_names(&self) -> impl Iterator<Item = (&'static str, KPageFlags)> {
+        FlagTable::running().iter_names(*self)
+    }
+
+    /// The name of a single flag on the running kernel, `None` if `self` is
+    /// not exactly one known flag.
+    pub fn name(&self) -> Option<&'static str> {
+        match self.0.count_ones() {
+            1 => FlagTable::running().name(self.0.trailing_zeros()),
+            _ => None,
+        }
+    }
+
+    /// Look up a single flag by its (case insensitive) name. Names from any
+    /// kernel version are accepted, e.g. both `BALLOON` and `OFFLINE`.
+    pub fn from_name(name: &str) -> Option<KPageFlags> {
+        let name = name.trim();
+        KPageFlags::FLAGS
+            .iter()
+            .find(|info| info.name.eq_ignore_ascii_case(name))
+            .map(|info| KPageFlags::from_bit(info.bit))
+    }
+
     pub const fn locked(&self) -> bool {
         const MASK: u64 = 1u64 << KPageFlags::LOCKED_BIT;
         self.0 & MASK != 0
@@ -308,6 +620,259 @@ impl KPageFlags {
         const MASK: u64 = 1u64 << KPageFlags::IDLE_BIT;
         self.0 & MASK != 0
     }
+
+    /// Same bit as [`KPageFlags::balloon`], renamed in Linux 5.0
+    pub const fn offline(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::OFFLINE_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn pgtable(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::PGTABLE_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn reserved(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::RESERVED_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn mlocked(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::MLOCKED_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn mappedtodisk(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::MAPPEDTODISK_BIT;
+        self.0 & MASK != 0
+    }
+
+    /// Same bit as [`KPageFlags::mappedtodisk`], renamed in Linux 6.12
+    pub const fn owner_2(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::OWNER_2_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn private(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::PRIVATE_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn private_2(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::PRIVATE_2_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn owner_private(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::OWNER_PRIVATE_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn arch(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::ARCH_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn uncached(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::UNCACHED_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn softdirty(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::SOFTDIRTY_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn arch_2(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::ARCH_2_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn arch_3(&self) -> bool {
+        const MASK: u64 = 1u64 << KPageFlags::ARCH_3_BIT;
+        self.0 & MASK != 0
+    }
+}
+
+
+/// Describes one bit of `/proc/kpageflags` and the kernel versions that
+/// report it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FlagInfo {
+    pub name:     &'static str,
+    pub bit:      u32,
+    /// first kernel release exporting the flag under this name
+    pub since:    KernelVersion,
+    /// first kernel release where the bit was renamed or dropped
+    pub until:    Option<KernelVersion>,
+    /// one of the kernel hacking flags (bits 32 and up) which are not part of
+    /// the stable ABI
+    pub internal: bool,
+}
+
+
+impl FlagInfo {
+    const fn documented(
+        name: &'static str,
+        bit: u32,
+        since: KernelVersion,
+    ) -> Self {
+        Self {
+            name,
+            bit,
+            since,
+            until: None,
+            internal: false,
+        }
+    }
+
+    const fn internal(
+        name: &'static str,
+        bit: u32,
+        since: KernelVersion,
+    ) -> Self {
+        Self {
+            name,
+            bit,
+            since,
+            until: None,
+            internal: true,
+        }
+    }
+
+    const fn until(
+        mut self,
+        version: KernelVersion,
+    ) -> Self {
+        self.until = Some(version);
+        self
+    }
+
+    pub fn available_in(
+        &self,
+        version: &KernelVersion,
+    ) -> bool {
+        self.since <= *version && self.until.map(|until| *version < until).unwrap_or(true)
+    }
+}
+
+
+/// The flag names in effect for one kernel version, indexed by bit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlagTable {
+    version: KernelVersion,
+    names:   [Option<&'static FlagInfo>; 64],
+}
+
+
+impl FlagTable {
+    pub fn for_kernel(version: KernelVersion) -> Self {
+        let mut names = [None; 64];
+        for info in KPageFlags::FLAGS.iter().filter(|info| info.available_in(&version)) {
+            names[info.bit as usize] = Some(info);
+        }
+        Self { version, names }
+    }
+
+    /// The table for the kernel this process is running on.
+    pub fn running() -> &'static FlagTable {
+        lazy_static! {
+            static ref RUNNING: FlagTable = FlagTable::for_kernel(KernelVersion::running());
+        }
+        &*RUNNING
+    }
+
+    pub const fn version(&self) -> &KernelVersion {
+        &self.version
+    }
+
+    pub fn info(
+        &self,
+        bit: u32,
+    ) -> Option<&'static FlagInfo> {
+        self.names.get(bit as usize).copied().flatten()
+    }
+
+    pub fn name(
+        &self,
+        bit: u32,
+    ) -> Option<&'static str> {
+        self.info(bit).map(|info| info.name)
+    }
+
+    /// Every flag defined by this kernel version.
+    pub fn mask(&self) -> KPageFlags {
+        self.names.iter().flatten().map(|info| KPageFlags::from_bit(info.bit)).collect()
+    }
+
+    /// Flags set in `flags` that this kernel version does not define.
+    pub fn unknown(
+        &self,
+        flags: KPageFlags,
+    ) -> KPageFlags {
+        flags.difference(self.mask())
+    }
+
+    /// Iterate over the flags set in `flags` that this kernel version
+    /// defines, one flag at a time, in bit order.
+    pub fn iter(
+        &self,
+        flags: KPageFlags,
+    ) -> Iter {
+        Iter {
+            flags,
+            table: self,
+            index: 0,
+        }
+    }
+
+    /// Like [`FlagTable::iter`] but paired with the name of each flag.
+    pub fn iter_names(
+        &self,
+        flags: KPageFlags,
+    ) -> impl Iterator<Item = (&'static str, KPageFlags)> + '_ {
+        self.iter(flags).map(move |flag| (self.name(flag.0.trailing_zeros()).unwrap_or("?"), flag))
+    }
+
+    /// The names of the flags set in `flags`, followed by the unknown bits as
+    /// one hex literal, the terms of the [`fmt::Display`] output.
+    pub fn names(
+        &self,
+        flags: KPageFlags,
+    ) -> Vec<String> {
+        let unknown = self.unknown(flags);
+        self.iter_names(flags)
+            .map(|(name, _flag)| name.to_string())
+            .chain(Some(unknown).filter(|unknown| !unknown.is_empty()).map(|unknown| format!("{:#x}", unknown.0)))
+            .collect()
+    }
+
+    /// Format `flags` with the names of this kernel version.
+    pub const fn display(
+        &self,
+        flags: KPageFlags,
+    ) -> DisplayFlags {
+        DisplayFlags { flags, table: self }
+    }
+}
+
+
+/// [`KPageFlags`] formatted with the names of a given [`FlagTable`], see
+/// [`FlagTable::display`].
+#[derive(Copy, Clone, Debug)]
+pub struct DisplayFlags<'a> {
+    flags: KPageFlags,
+    table: &'a FlagTable,
+}
+
+
+impl fmt::Display for DisplayFlags<'_> {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        f.write_str(&self.table.names(self.flags).join(","))
+    }
 }
 
 
@@ -325,89 +890,444 @@ impl fmt::Debug for KPageFlags {
         &self,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        let mut bits = Vec::with_capacity(8);
-        if self.locked() {
-            bits.push("LOCKED");
-        }
-        if self.error() {
-            bits.push("ERROR");
-        }
-        if self.referenced() {
-            bits.push("REFERENCED");
-        }
-        if self.uptodate() {
-            bits.push("UPTODATE");
-        }
-        if self.dirty() {
-            bits.push("DIRTY");
-        }
-        if self.lru() {
-            bits.push("LRU");
-        }
-        if self.active() {
-            bits.push("ACTIVE");
-        }
-        if self.slab() {
-            bits.push("SLAB");
-        }
-        if self.writeback() {
-            bits.push("WRITEBACK");
-        }
-        if self.reclaim() {
-            bits.push("RECLAIM");
-        }
-        if self.buddy() {
-            bits.push("BUDDY");
-        }
-        if self.mmap() {
-            bits.push("MMAP");
-        }
-        if self.anon() {
-            bits.push("ANON");
-        }
-        if self.swapcache() {
-            bits.push("SWAPCACHE");
-        }
-        if self.swapbacked() {
-            bits.push("SWAPBACKED");
-        }
-        if self.compound_head() {
-            bits.push("COMPOUND_HEAD");
-        }
-        if self.compound_tail() {
-            bits.push("COMPOUND_TAIL");
-        }
-        if self.huge() {
-            bits.push("HUGE");
-        }
-        if self.unevictable() {
-            bits.push("UNEVICTABLE");
-        }
-        if self.hwpoison() {
-            bits.push("HWPOISON");
+        let bits = FlagTable::running().names(*self);
+
+        f.debug_struct("KPageFlags")
+            .field("value", &crate::fmt::Binary(&self.0))
+            .field("bits", &bits.as_slice())
+            .finish()
+    }
+}
+
+
+/// Comma separated flag names, e.g. `ANON,SWAPBACKED,THP`, the same syntax
+/// accepted by the [`FromStr`] impl.
+impl fmt::Display for KPageFlags {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        FlagTable::running().display(*self).fmt(f)
+    }
+}
+
+
+/// Parse a comma separated list of flag names (case insensitive), or a hex
+/// literal such as `0x1800`.
+impl FromStr for KPageFlags {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut flags = KPageFlags::empty();
+
+        for term in value.split(',').map(str::trim).filter(|term| !term.is_empty()) {
+            let flag = if let Some(hex) = term.strip_prefix("0x") {
+                u64::from_str_radix(hex, 16).map(KPageFlags::new).ok()
+            } else {
+                KPageFlags::from_name(term)
+            };
+
+            flags.insert(flag.ok_or_else(|| {
+                Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<KPageFlags>(),
+                    reason:   format!("unknown page flag {:?}", term),
+                }
+            })?);
         }
-        if self.nopage() {
-            bits.push("NOPAGE");
+
+        Ok(flags)
+    }
+}
+
+
+impl std::ops::BitOr for KPageFlags {
+    type Output = KPageFlags;
+
+    fn bitor(
+        self,
+        other: KPageFlags,
+    ) -> KPageFlags {
+        self.union(other)
+    }
+}
+
+
+impl std::ops::BitAnd for KPageFlags {
+    type Output = KPageFlags;
+
+    fn bitand(
+        self,
+        other: KPageFlags,
+    ) -> KPageFlags {
+        self.intersection(other)
+    }
+}
+
+
+impl std::ops::Sub for KPageFlags {
+    type Output = KPageFlags;
+
+    fn sub(
+        self,
+        other: KPageFlags,
+    ) -> KPageFlags {
+        self.difference(other)
+    }
+}
+
+
+impl std::ops::BitOrAssign for KPageFlags {
+    fn bitor_assign(
+        &mut self,
+        other: KPageFlags,
+    ) {
+        self.insert(other)
+    }
+}
+
+
+impl std::iter::FromIterator<KPageFlags> for KPageFlags {
+    fn from_iter<I: IntoIterator<Item = KPageFlags>>(iter: I) -> Self {
+        iter.into_iter().fold(KPageFlags::empty(), KPageFlags::union)
+    }
+}
+
+
+/// Iterator over the documented flags set in a [`KPageFlags`], see
+/// [`KPageFlags::iter`].
+pub struct Iter<'a> {
+    flags: KPageFlags,
+    table: &'a FlagTable,
+    index: u32,
+}
+
+
+impl Iterator for Iter<'_> {
+    type Item = KPageFlags;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < 64 {
+            let bit = self.index;
+            self.index += 1;
+            if self.table.info(bit).is_some() && self.flags.contains(KPageFlags::from_bit(bit)) {
+                return Some(KPageFlags::from_bit(bit));
+            }
         }
-        if self.ksm() {
-            bits.push("KSM");
+        None
+    }
+}
+
+
+/// A page flag predicate.
+///
+/// Written as `|` separated alternatives, each of which is a comma separated
+/// list of flag names where a `!` (or `~`) prefix means the flag must be
+/// clear. `ANON,THP,!LRU|HUGE` matches anonymous transparent huge pages that
+/// are not on an LRU list, as well as any hugetlb page.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlagExpr {
+    alternatives: Vec<FlagTerm>,
+}
+
+
+/// One alternative of a [`FlagExpr`]: all of `required` set and none of
+/// `forbidden`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlagTerm {
+    pub required:  KPageFlags,
+    pub forbidden: KPageFlags,
+}
+
+
+impl FlagTerm {
+    pub const fn matches(
+        &self,
+        flags: &KPageFlags,
+    ) -> bool {
+        flags.contains(self.required) && !flags.intersects(self.forbidden)
+    }
+}
+
+
+impl FlagExpr {
+    pub fn new(
+        required: KPageFlags,
+        forbidden: KPageFlags,
+    ) -> Self {
+        Self {
+            alternatives: vec![FlagTerm { required, forbidden }],
         }
-        if self.thp() {
-            bits.push("THP");
+    }
+
+    /// Add an alternative to the predicate.
+    pub fn or(
+        mut self,
+        required: KPageFlags,
+        forbidden: KPageFlags,
+    ) -> Self {
+        self.alternatives.push(FlagTerm { required, forbidden });
+        self
+    }
+
+    pub fn alternatives(&self) -> &[FlagTerm] {
+        self.alternatives.as_slice()
+    }
+
+    /// An empty expression matches every page.
+    pub fn matches(
+        &self,
+        flags: &KPageFlags,
+    ) -> bool {
+        self.alternatives.is_empty() || self.alternatives.iter().any(|term| term.matches(flags))
+    }
+}
+
+
+impl FromStr for FlagExpr {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut expr = FlagExpr::default();
+
+        for alternative in value.split('|').map(str::trim).filter(|alt| !alt.is_empty()) {
+            let mut term = FlagTerm::default();
+
+            for name in alternative.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                let (negated, name) = match name.strip_prefix(|ch| ch == '!' || ch == '~') {
+                    Some(name) => (true, name),
+                    None => (false, name),
+                };
+
+                let flag = KPageFlags::from_str(name).map_err(|_err| {
+                    Error::Parse {
+                        value:    value.to_string(),
+                        typename: std::any::type_name::<FlagExpr>(),
+                        reason:   format!("unknown page flag {:?}", name),
+                    }
+                })?;
+
+                if negated {
+                    term.forbidden.insert(flag);
+                } else {
+                    term.required.insert(flag);
+                }
+            }
+
+            expr.alternatives.push(term);
         }
-        if self.balloon() {
-            bits.push("BALLOON");
+
+        Ok(expr)
+    }
+}
+
+
+impl fmt::Display for FlagExpr {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        for (i, term) in self.alternatives.iter().enumerate() {
+            if i > 0 {
+                f.write_str("|")?;
+            }
+
+            let required = term.required.iter_names().map(|(name, _)| name.to_string());
+            let forbidden = term.forbidden.iter_names().map(|(name, _)| format!("!{}", name));
+            f.write_str(&required.chain(forbidden).collect::<Vec<_>>().join(","))?;
         }
-        if self.zero_page() {
-            bits.push("ZERO_PAGE");
+        Ok(())
+    }
+}
+
+
+/// One entry of `/proc/kpageflags` joined with the same entry of
+/// `/proc/kpagecount`.
+#[derive(Copy, Clone, Debug, serde::Serialize)]
+pub struct PhysicalPage {
+    pub pfn:        u64,
+    pub flags:      KPageFlags,
+    pub kpagecount: Option<u64>,
+}
+
+
+/// Iterate over the physical page frames in `range` (PFNs, not addresses).
+///
+/// An unbounded end scans to the end of `/proc/kpageflags`. Reading
+/// `/proc/kpageflags` requires CAP_SYS_ADMIN; if `/proc/kpagecount` cannot be
+/// opened the counts are reported as `None`.
+pub fn scan_physical<R>(range: R) -> Result<PhysicalScan, Error>
+where
+    R: RangeBounds<u64>,
+{
+    const ENTRY_SIZE: u64 = mem::size_of::<u64>() as u64;
+
+    let start = match range.start_bound() {
+        Bound::Included(pfn) => *pfn,
+        Bound::Excluded(pfn) => pfn.saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+
+    let end = match range.end_bound() {
+        Bound::Included(pfn) => Some(pfn.saturating_add(1)),
+        Bound::Excluded(pfn) => Some(*pfn),
+        Bound::Unbounded => None,
+    };
+
+    let offset = NonZeroU64::new(start * ENTRY_SIZE);
+    let kpageflags_reader = crate::io::new_buffered_file_reader(crate::paths::proc_kpageflags_path(), offset)?;
+    let kpagecount_reader = match crate::io::new_buffered_file_reader(crate::paths::proc_kpagecount_path(), offset) {
+        Ok(reader) => Some(reader),
+        Err(err) => {
+            warn!(
+                "page counts disabled, unable to read {:?}, reason: {:?}",
+                crate::paths::proc_kpagecount_path(),
+                err
+            );
+            None
         }
-        if self.idle() {
-            bits.push("IDLE");
+    };
+
+    Ok(PhysicalScan {
+        pfn: start,
+        end,
+        kpageflags_reader,
+        kpagecount_reader,
+    })
+}
+
+
+pub struct PhysicalScan {
+    pfn:               u64,
+    end:               Option<u64>,
+    kpageflags_reader: BufReader<File>,
+    kpagecount_reader: Option<BufReader<File>>,
+}
+
+
+impl PhysicalScan {
+    fn next_physical_page(&mut self) -> Result<Option<PhysicalPage>, Error> {
+        if self.end.map(|end| self.pfn >= end).unwrap_or(false) {
+            return Ok(None);
         }
 
-        f.debug_struct("KPageFlags")
-            .field("value", &crate::fmt::Binary(&self.0))
-            .field("bits", &bits.as_slice())
-            .finish()
+        let rdr: &mut dyn Read = &mut self.kpageflags_reader;
+        let flags = match KPageFlags::try_from(rdr) {
+            Ok(flags) => flags,
+            // reached the end of physical memory
+            Err(Error::IO { source, .. }) if source.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let kpagecount = match self.kpagecount_reader.as_mut() {
+            Some(reader) => Some(crate::io::read_u64(reader)?),
+            None => None,
+        };
+
+        let page = PhysicalPage {
+            pfn: self.pfn,
+            flags,
+            kpagecount,
+        };
+        self.pfn += 1;
+
+        Ok(Some(page))
+    }
+}
+
+
+impl Iterator for PhysicalScan {
+    type Item = Result<PhysicalPage, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_physical_page().transpose()
     }
 }
+
+
+#[test]
+fn test_flag_expr() {
+    let expr = FlagExpr::from_str("anon, THP,!lru|huge").unwrap();
+    let anon_thp = KPageFlags::ANON | KPageFlags::THP;
+    let anon_thp_lru = anon_thp | KPageFlags::LRU;
+    let anon = KPageFlags::ANON;
+
+    assert!(expr.matches(&anon_thp));
+    assert!(!expr.matches(&anon_thp_lru));
+    assert!(!expr.matches(&anon));
+    assert!(expr.matches(&(anon_thp_lru | KPageFlags::HUGE)));
+    assert_eq!(expr.to_string(), "ANON,THP,!LRU|HUGE");
+    assert!(FlagExpr::from_str("").unwrap().matches(&anon));
+    assert!(FlagExpr::from_str("ANON,NOT_A_FLAG").is_err());
+}
+
+
+#[test]
+fn test_flag_set_operations() {
+    // the names depend on the kernel version, pin one instead of the running
+    let table = FlagTable::for_kernel(KernelVersion::new(6, 12, 3));
+    let flags = KPageFlags::from_str("ANON,swapbacked,0x400000").unwrap();
+
+    assert!(flags.contains(KPageFlags::ANON | KPageFlags::SWAPBACKED));
+    assert!(flags.intersects(KPageFlags::LRU_MASK));
+    assert!(!flags.intersects(KPageFlags::IO_MASK));
+    assert_eq!(
+        table.iter(flags).collect::<Vec<_>>(),
+        vec![KPageFlags::ANON, KPageFlags::SWAPBACKED, KPageFlags::THP]
+    );
+    assert_eq!(table.display(flags).to_string(), "ANON,SWAPBACKED,THP");
+    assert_eq!(KPageFlags::from_str(&table.display(flags).to_string()).unwrap(), flags);
+    assert_eq!(table.display(flags - KPageFlags::THP).to_string(), "ANON,SWAPBACKED");
+
+    // unknown bits are one hex literal in every representation
+    let unknown = KPageFlags::new(1 << 50 | 1 << 12);
+    assert_eq!(table.unknown(unknown), KPageFlags::new(1 << 50));
+    assert_eq!(table.display(unknown).to_string(), "ANON,0x4000000000000");
+    assert_eq!(table.names(unknown), vec!["ANON", "0x4000000000000"]);
+    assert_eq!(table.display(KPageFlags::new(1 << 50)).to_string(), "0x4000000000000");
+    assert_eq!(table.display(KPageFlags::empty()).to_string(), "");
+    assert_eq!(FlagTable::running().names(unknown), format!("{}", unknown).split(',').collect::<Vec<_>>());
+    assert!(format!("{:?}", unknown).contains("\"0x4000000000000\""));
+}
+
+
+#[test]
+fn test_flag_table_versions() {
+    let old = FlagTable::for_kernel(KernelVersion::new(4, 14, 0));
+    let new = FlagTable::for_kernel(KernelVersion::new(6, 12, 3));
+
+    assert_eq!(old.name(23), Some("BALLOON"));
+    assert_eq!(new.name(23), Some("OFFLINE"));
+    assert_eq!(old.name(26), None);
+    assert_eq!(new.name(26), Some("PGTABLE"));
+    assert_eq!(old.name(34), Some("MAPPEDTODISK"));
+    assert_eq!(new.name(34), Some("OWNER_2"));
+    assert!(!old.mask().contains(KPageFlags::ARCH_2));
+    assert!(new.mask().contains(KPageFlags::ARCH_2 | KPageFlags::SOFTDIRTY));
+    assert_eq!(KPageFlags::from_name("balloon"), KPageFlags::from_name("OFFLINE"));
+}
+
+
+#[test]
+fn test_scan_physical_histogram() {
+    use std::collections::BTreeMap;
+
+    // reading /proc/kpageflags needs CAP_SYS_ADMIN
+    if std::fs::File::open(crate::paths::proc_kpageflags_path()).is_err() {
+        eprintln!("skipping, {:?} is not readable", crate::paths::proc_kpageflags_path());
+        return;
+    }
+
+    let pages = scan_physical(0..64).unwrap().collect::<Result<Vec<_>, Error>>().unwrap();
+    assert_eq!(pages.len(), 64);
+    assert!(pages.iter().enumerate().all(|(index, page)| page.pfn == index as u64));
+
+    let mut histogram = BTreeMap::<u64, u64>::new();
+    for page in pages.iter() {
+        *histogram.entry(page.flags.bits()).or_default() += 1;
+    }
+    assert_eq!(histogram.values().sum::<u64>(), pages.len() as u64);
+
+    // an inclusive end reads one frame more
+    assert_eq!(scan_physical(16..=31).unwrap().count(), 16);
+}
@@ -6,17 +6,36 @@ pub(crate) mod deps {
     pub use lazy_static;
     pub use libc;
     pub use log;
+    pub use lz4_flex;
     pub use nix;
     pub use serde;
+    pub use serde_json;
     pub use thiserror;
 }
 
 mod fmt;
 mod io;
+mod sys;
 
+pub mod census;
+pub mod checkpoint;
+pub mod content;
 pub mod error;
+pub mod hotness;
+pub mod kernel;
 pub mod kpageflags;
 pub mod maps;
 pub mod mmapfile;
+pub mod numa;
 pub mod pagemaps;
 pub mod paths;
+pub mod precopy;
+pub mod quiesce;
+pub mod reclaim;
+pub mod remote;
+pub mod replicate;
+pub mod residency;
+pub mod stats;
+pub mod swaps;
+pub mod thp;
+pub mod wss;
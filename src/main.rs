@@ -12,19 +12,73 @@ use std::{
     str::FromStr,
 };
 
-use nix::sys::ptrace::Options;
-
 use crate::deps::{
     beholder::{
+        census::PageCensus,
+        checkpoint::{
+            ChainDir,
+            Image,
+        },
+        content::{
+            ContentStats,
+            ContentTracker,
+            HashSource,
+        },
+        hotness::{
+            HotnessHistogram,
+            HotnessThresholds,
+            HotnessTracker,
+        },
+        kpageflags::{
+            FlagExpr,
+            KPageFlags,
+        },
+        maps::{
+            column::{
+                AddressRange,
+                Perm,
+            },
+            MappingKind,
+        },
         mmapfile::{
             MmapFile,
             MmapOptions,
         },
+        numa::NodeCounts,
         pagemaps::{
+            coalesce_ranges,
             PageDescriptor,
             PageSize,
             ProcessVMA,
         },
+        precopy::PrecopyConfig,
+        quiesce::{
+            Quiesce,
+            QuiesceMethod,
+        },
+        reclaim::{
+            PageResidency,
+            ReclaimAdvice,
+        },
+        remote::RemoteMemory,
+        replicate::Replicator,
+        residency::{
+            fincore,
+            fincore_range,
+        },
+        stats::{
+            RegionStats,
+            StatsField,
+        },
+        swaps::{
+            SwapTotals,
+            Swaps,
+        },
+        thp::ThpReport,
+        wss::{
+            WssMethod,
+            WssStats,
+        },
     },
     log::{
         debug,
@@ -65,6 +119,113 @@ mod cli {
     pub fn parse_hex(number: &str) -> Result<usize, Box<dyn std::error::Error>> {
         Ok(usize::from_str_radix(number, 16)?)
     }
+
+    /// Parse a byte size such as `4096`, `64K`, `1.5GiB` or `10MB`. The
+    /// binary (KiB) and the short (K) suffixes are powers of 1024, the
+    /// decimal ones (KB) powers of 1000.
+    pub fn parse_size(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let value = value.trim();
+        let split = value
+            .find(|ch: char| !(ch.is_ascii_digit() || ch == '.'))
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number = number.parse::<f64>()?;
+        let scale = match unit.trim() {
+            "" | "B" => 1u64,
+            "K" | "KiB" => 1 << 10,
+            "M" | "MiB" => 1 << 20,
+            "G" | "GiB" => 1 << 30,
+            "T" | "TiB" => 1 << 40,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            "TB" => 1_000_000_000_000,
+            _ => return Err(format!("unknown size unit {:?} in {:?}", unit, value).into()),
+        };
+        Ok((number * scale as f64) as u64)
+    }
+
+    /// Parse a bandwidth in bytes per second such as `1GiB/s` or `500MB/s`.
+    pub fn parse_bandwidth(value: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let bandwidth = parse_size(value.trim().trim_end_matches("/s"))?;
+        if bandwidth == 0 {
+            return Err("the bandwidth must not be zero".into());
+        }
+        Ok(bandwidth)
+    }
+
+    /// Parse a number of hotness rounds, which the page counters limit.
+    pub fn parse_rounds(value: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let rounds = value.trim().parse::<usize>()?;
+        if rounds > beholder::hotness::MAX_ROUNDS {
+            return Err(format!("at most {} rounds are supported", beholder::hotness::MAX_ROUNDS).into());
+        }
+        Ok(rounds)
+    }
+
+    /// Parse a duration such as `500ms`, `1s`, `1.5s` or `2m`, a bare number
+    /// is in seconds.
+    pub fn parse_duration(value: &str) -> Result<std::time::Duration, Box<dyn std::error::Error>> {
+        let value = value.trim();
+        let split = value
+            .find(|ch: char| !(ch.is_ascii_digit() || ch == '.'))
+            .unwrap_or(value.len());
+        let (number, unit) = value.split_at(split);
+        let number = number.parse::<f64>()?;
+        let secs = match unit {
+            "ms" => number / 1000.0,
+            "" | "s" => number,
+            "m" => number * 60.0,
+            _ => return Err(format!("unknown duration unit {:?} in {:?}", unit, value).into()),
+        };
+        Ok(std::time::Duration::from_secs_f64(secs))
+    }
+
+    pub fn print_stats_header(numa: bool) {
+        if numa {
+            print!("{:<16}", "nodes");
+        }
+        println!(
+            "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8}  {}",
+            "pages",
+            "present",
+            "absent",
+            "swapped",
+            "dirty",
+            "clean",
+            "exclusive",
+            "file",
+            "thp",
+            "huge",
+            "ksm",
+            "group"
+        );
+    }
+
+    pub fn print_stats_row(
+        stats: &beholder::stats::RegionStats,
+        nodes: Option<&beholder::numa::NodeCounts>,
+        name: &str,
+    ) {
+        if let Some(nodes) = nodes {
+            print!("{:<16}", nodes.to_string());
+        }
+        println!(
+            "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8} {:>8} {:>8}  {}",
+            stats.pages,
+            stats.present,
+            stats.not_present,
+            stats.swapped,
+            stats.soft_dirty,
+            stats.clean(),
+            stats.exclusive,
+            stats.file,
+            stats.thp,
+            stats.huge,
+            stats.ksm,
+            name
+        );
+    }
 }
 
 macro_rules! panic_on_err {
@@ -100,6 +261,64 @@ impl FromStr for Data {
 }
 
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum GroupBy {
+    Region,
+    Pathname,
+    Kind,
+}
+
+
+impl FromStr for GroupBy {
+    type Err = crate::deps::beholder::error::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "region" => Ok(GroupBy::Region),
+            "pathname" => Ok(GroupBy::Pathname),
+            "kind" => Ok(GroupBy::Kind),
+            bad_value => {
+                Err(crate::deps::beholder::error::Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<GroupBy>(),
+                    reason:   "value was not one of: region, pathname, kind".to_string(),
+                })
+            }
+        }
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum BackingKind {
+    File,
+    AnonPrivate,
+    AnonShared,
+    Memfd,
+}
+
+
+impl FromStr for BackingKind {
+    type Err = crate::deps::beholder::error::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim() {
+            "file" => Ok(BackingKind::File),
+            "anon-private" => Ok(BackingKind::AnonPrivate),
+            "anon-shared" => Ok(BackingKind::AnonShared),
+            "memfd" => Ok(BackingKind::Memfd),
+            bad_value => {
+                Err(crate::deps::beholder::error::Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<BackingKind>(),
+                    reason:   "value was not one of: file, anon-private, anon-shared, memfd".to_string(),
+                })
+            }
+        }
+    }
+}
+
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 enum Assert {
     Off,
@@ -178,6 +397,19 @@ enum Command {
     DirtyCounts(DirtyCounts),
     Print(Print),
     Demo(Demo),
+    PageTypes(PageTypes),
+    Thp(Thp),
+    Fincore(Fincore),
+    Swap(Swap),
+    Replicate(Replicate),
+    Dump(Dump),
+    Checkpoint(Checkpoint),
+    Precopy(Precopy),
+    Track(Track),
+    ContentStats(ContentStatsCmd),
+    Wss(Wss),
+    Hotness(HotnessCmd),
+    Reclaim(Reclaim),
 }
 
 
@@ -194,6 +426,18 @@ struct Print {
 
     #[structopt(long)]
     page_size: Option<PageSize>,
+
+    /// only print pages whose kpageflags match, e.g. ANON,THP,!LRU
+    #[structopt(long)]
+    flags: Option<FlagExpr>,
+
+    /// show the NUMA policy of each region and the node of each page
+    #[structopt(long)]
+    numa: bool,
+
+    /// show which pages of file-backed regions are in the page cache
+    #[structopt(long)]
+    residency: bool,
 }
 
 
@@ -207,6 +451,361 @@ struct DirtyCounts {
 
     #[structopt(long)]
     page_size: Option<PageSize>,
+
+    /// only count pages whose kpageflags match, e.g. ANON,THP,!LRU
+    #[structopt(long)]
+    flags: Option<FlagExpr>,
+
+    /// break the counts down by: region, pathname, kind
+    #[structopt(short, long)]
+    group_by: Option<GroupBy>,
+
+    /// order groups by this counter, largest first
+    #[structopt(long, default_value = "dirty")]
+    sort: StatsField,
+
+    /// only print the first N groups after sorting
+    #[structopt(long)]
+    top: Option<usize>,
+
+    /// count the present pages on each NUMA node
+    #[structopt(long)]
+    numa: bool,
+
+    /// stop the process while scanning: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+}
+
+
+/// Histogram of the kpageflags of every physical page frame, in the style
+/// of the kernel's tools/vm/page-types.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct PageTypes {
+    /// first page frame number to scan (hex)
+    #[structopt(long, parse(try_from_str = cli::parse_hex))]
+    pfn_start: Option<usize>,
+
+    /// stop scanning before this page frame number (hex)
+    #[structopt(long, parse(try_from_str = cli::parse_hex))]
+    pfn_end: Option<usize>,
+
+    /// only count pages matching the flags: `|` separated alternatives of
+    /// comma separated flags, all of which must match, `!` negates a flag,
+    /// e.g. ANON,THP,!LRU|HUGE for anonymous THPs off the LRU or any hugetlb
+    /// page
+    #[structopt(short, long)]
+    bits: Option<FlagExpr>,
+}
+
+
+/// Transparent huge page coverage of each region in 2 MiB aligned ranges.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Thp {
+    #[structopt(short, long)]
+    pid: Option<usize>,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// list every aligned range, not only the per region totals
+    #[structopt(long)]
+    ranges: bool,
+
+    /// MADV_COLLAPSE the fully populated ranges not yet backed by a THP
+    #[structopt(long)]
+    collapse: bool,
+}
+
+
+/// Page cache residency of files, like fincore(1).
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Fincore {
+    #[structopt(required = true, parse(from_os_str))]
+    files: Vec<PathBuf>,
+
+    /// list the resident byte ranges of each file
+    #[structopt(long)]
+    ranges: bool,
+}
+
+
+/// Swapped out pages of each region and the swap area holding them.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Swap {
+    #[structopt(short, long)]
+    pid: Option<usize>,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+}
+
+
+/// Keep a replica of a file up to date from the soft-dirty pages of the
+/// shared mappings of it in a process.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Replicate {
+    #[structopt(short, long)]
+    pid: usize,
+
+    /// the file mapped by the process
+    #[structopt(long, parse(from_os_str))]
+    path: PathBuf,
+
+    /// the replica, created or truncated and then fully copied
+    #[structopt(long, parse(from_os_str))]
+    to: PathBuf,
+
+    /// time between rounds, e.g. 500ms, 1s, 2m
+    #[structopt(long, default_value = "1s", parse(try_from_str = cli::parse_duration))]
+    interval: std::time::Duration,
+
+    /// stop after N rounds instead of running until killed
+    #[structopt(long)]
+    rounds: Option<usize>,
+
+    /// stop the process while scanning: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+}
+
+
+/// Write the contents of the pages of a process to a file, back to back in
+/// address order, and list where each range of pages was written.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Dump {
+    #[structopt(short, long)]
+    pid: Option<usize>,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// only dump the soft-dirty pages, otherwise every present or swapped page
+    #[structopt(long)]
+    dirty_only: bool,
+
+    #[structopt(short, long, parse(from_os_str))]
+    output: PathBuf,
+
+    /// stop the process while scanning: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+}
+
+
+/// Capture the next image of a checkpoint chain: a base image of the
+/// selected regions into an empty --dir, then deltas of the soft-dirty pages.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Checkpoint {
+    #[structopt(short, long)]
+    pid: Option<usize>,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// directory holding the images of the chain
+    #[structopt(long, default_value = "beholder-checkpoint", parse(from_os_str))]
+    dir: PathBuf,
+
+    /// stop the process while capturing: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+
+    #[structopt(subcommand)]
+    action: Option<CheckpointAction>,
+}
+
+
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+enum CheckpointAction {
+    /// Print and verify the images of a chain, the images of --dir by default
+    Inspect {
+        #[structopt(parse(from_os_str))]
+        images: Vec<PathBuf>,
+
+        /// list the pages of every image
+        #[structopt(long)]
+        pages: bool,
+    },
+    /// Flatten a chain into one verified base image
+    Merge {
+        #[structopt(parse(from_os_str))]
+        images: Vec<PathBuf>,
+
+        #[structopt(short, long, parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+
+/// Model an iterative pre-copy migration of a process: scan its real dirty
+/// pages each round and wait for as long as the round would take to transfer.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Precopy {
+    #[structopt(short, long)]
+    pid: usize,
+
+    /// transfer rate, e.g. 1GiB/s, 100MB/s
+    #[structopt(long, default_value = "1GiB/s", parse(try_from_str = cli::parse_bandwidth))]
+    bandwidth: u64,
+
+    /// rounds to run at most, including the stop-and-copy round
+    #[structopt(long, default_value = "10")]
+    max_rounds: usize,
+
+    /// stop once a round could be copied with the process stopped in this time
+    #[structopt(long, default_value = "50ms", parse(try_from_str = cli::parse_duration))]
+    downtime_target: std::time::Duration,
+    /// stop the process while each round is scanned: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+}
+
+
+/// Track the soft-dirty pages of a process over rounds and hash their
+/// contents to tell the pages really modified from the spuriously dirty ones.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Track {
+    #[structopt(short, long)]
+    pid: usize,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// time between rounds, e.g. 500ms, 1s, 2m
+    #[structopt(long, default_value = "1s", parse(try_from_str = cli::parse_duration))]
+    interval: std::time::Duration,
+
+    /// stop after N rounds instead of running until killed
+    #[structopt(long)]
+    rounds: Option<usize>,
+
+    /// stop the process while scanning: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+
+    /// memory for shadow copies to diff the modified pages against, e.g. 64M
+    #[structopt(long, default_value = "0", parse(try_from_str = cli::parse_size))]
+    shadow_budget: u64,
+
+    /// print a row per region under each round
+    #[structopt(long)]
+    per_region: bool,
+
+    /// list the changed bytes and cache lines of the shadowed pages under
+    /// each round
+    #[structopt(long)]
+    changes: bool,
+}
+
+
+/// Count the zero, duplicate and compressible pages of a process, per region
+/// and in total.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct ContentStatsCmd {
+    #[structopt(short, long)]
+    pid: usize,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// compress every N-th page to estimate the compression ratio
+    #[structopt(long, default_value = "16")]
+    sample_every: usize,
+
+    /// stop the process while reading: ptrace, sigstop, freezer
+    #[structopt(long)]
+    quiesce: Option<QuiesceMethod>,
+}
+
+
+/// Estimate the accessed and the written working set of a process over an
+/// interval.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Wss {
+    #[structopt(short, long)]
+    pid: usize,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// the interval to measure, e.g. 500ms, 10s
+    #[structopt(long, default_value = "1s", parse(try_from_str = cli::parse_duration))]
+    duration: std::time::Duration,
+
+    /// how to find the accessed pages: referenced, idle
+    #[structopt(long, default_value = "referenced")]
+    method: WssMethod,
+}
+
+
+/// Classify the pages of a process as hot, warm or cold by how many sampling
+/// rounds they were accessed or written in.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct HotnessCmd {
+    #[structopt(short, long)]
+    pid: usize,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// time between rounds, e.g. 500ms, 1s, 2m
+    #[structopt(long, default_value = "1s", parse(try_from_str = cli::parse_duration))]
+    interval: std::time::Duration,
+
+    /// rounds to sample, 255 at most
+    #[structopt(long, default_value = "10", parse(try_from_str = cli::parse_rounds))]
+    rounds: usize,
+
+    /// fraction of the rounds a hot page is accessed in at least
+    #[structopt(long, default_value = "0.5")]
+    hot: f64,
+
+    /// fraction of the rounds a warm page is accessed in at least
+    #[structopt(long, default_value = "0.1")]
+    warm: f64,
+
+    /// how to find the accessed pages: idle, or referenced, which only sees
+    /// the writes, so pages that are only read are reported cold
+    #[structopt(long, default_value = "idle")]
+    method: WssMethod,
+
+    /// list the cold ranges of each region
+    #[structopt(long)]
+    cold_ranges: bool,
+}
+
+
+/// Reclaim the ranges of a process that were neither accessed nor written
+/// for a window with process_madvise.
+#[derive(Clone, Debug, StructOpt, PartialEq)]
+struct Reclaim {
+    #[structopt(short, long)]
+    pid: usize,
+
+    #[structopt(short, long, parse(try_from_str = cli::parse_hex))]
+    region: Option<usize>,
+
+    /// how long a range must be untouched, e.g. 60s, 5m
+    #[structopt(long, default_value = "60s", parse(try_from_str = cli::parse_duration))]
+    cold_for: std::time::Duration,
+
+    /// time between sampling rounds within the window
+    #[structopt(long, default_value = "1s", parse(try_from_str = cli::parse_duration))]
+    interval: std::time::Duration,
+
+    /// pageout or cold
+    #[structopt(long, default_value = "pageout")]
+    advice: ReclaimAdvice,
+
+    /// how to find the accessed pages: idle, or referenced, which misses the
+    /// accesses through the page tables and so may reclaim active pages
+    #[structopt(long, default_value = "idle")]
+    method: WssMethod,
+
+    /// only report what would be reclaimed
+    #[structopt(long)]
+    dry_run: bool,
 }
 
 
@@ -221,11 +820,17 @@ struct Demo {
     #[structopt(long, default_value = "3")]
     loops: usize,
 
+    /// page size of the mapping, huge and giga need the file backing on a
+    /// hugetlbfs mount or anon-private
     #[structopt(long)]
     page_size: Option<PageSize>,
 
     #[structopt(long, default_value = "panic")]
     assert: Assert,
+
+    /// memory to track: file (at --path), anon-private, anon-shared, memfd
+    #[structopt(long, default_value = "file")]
+    backing: BackingKind,
 }
 
 
@@ -245,6 +850,14 @@ fn init_process_vma(
     vm
 }
 
+/// Stop process `pid` until the returned guard is dropped, if requested.
+fn quiesce(
+    pid: usize,
+    method: Option<QuiesceMethod>,
+) -> Option<Quiesce> {
+    method.map(|method| Quiesce::new(pid, method).unwrap_or_else(panic_on_err!()))
+}
+
 fn list_regions(
     vm: &ProcessVMA,
     only_region: Option<usize>,
@@ -261,27 +874,122 @@ fn dirty_counts_command(
     args: &Args,
     cmd: &DirtyCounts,
 ) {
-    let (mut dirty, mut clean) = (0, 0);
-
     let mut vm = init_process_vma(cmd.pid, args.debug);
+    let _quiesce = quiesce(vm.pid(), cmd.quiesce);
     let regions = list_regions(&vm, cmd.region);
 
+    let mut per_region = std::collections::BTreeMap::new();
     for addr in regions.into_iter() {
         let region = vm.region(addr).unwrap();
+        let mut stats = RegionStats::new();
+        let mut present_addrs = Vec::new();
 
         let page_iter = region.try_iter(cmd.page_size).unwrap_or_else(panic_on_err!());
-
-        for page_parse_result in page_iter {
-            let p = page_parse_result.unwrap_or_else(panic_on_err!());
-            if p.pte.is_soft_dirty() {
-                dirty += 1;
-            } else {
-                clean += 1;
+        for page_result in page_iter {
+            let page = page_result.unwrap_or_else(panic_on_err!());
+            if cmd.flags.as_ref().map(|expr| page.matches(expr)).unwrap_or(true) {
+                stats.add_page(&page);
+                if cmd.numa && page.pte.is_present() {
+                    present_addrs.push(page.addr_range.start());
+                }
             }
         }
+
+        let mut nodes = NodeCounts::new();
+        if cmd.numa {
+            let page_nodes = vm.page_nodes(&present_addrs).unwrap_or_else(panic_on_err!());
+            page_nodes.into_iter().flatten().for_each(|node| nodes.add(node, 1));
+        }
+
+        per_region.insert(addr, (stats, nodes));
+    }
+
+    let mut total = RegionStats::new();
+    let mut total_nodes = NodeCounts::new();
+    for (stats, nodes) in per_region.values() {
+        total += stats;
+        total_nodes += nodes;
     }
+    let numa_column = |nodes| if cmd.numa { Some(nodes) } else { None };
+
+    let group_by = match cmd.group_by {
+        Some(group_by) => group_by,
+        None => {
+            println!("dirty: {}\nclean: {}", total.soft_dirty, total.clean());
+            if cmd.numa {
+                total_nodes
+                    .iter()
+                    .for_each(|(node, pages)| println!("N{}: {}", node, pages));
+            }
+            return;
+        }
+    };
 
-    println!("dirty: {}\nclean: {}", dirty, clean);
+    let mut groups: Vec<(String, RegionStats, NodeCounts)> = match group_by {
+        GroupBy::Region => {
+            per_region
+                .iter()
+                .map(|(addr, (stats, nodes))| {
+                    let region = vm.maps().region(*addr).unwrap();
+                    let name = format!("{} {}", region.addr_range(), region.pathname());
+                    (name, *stats, nodes.clone())
+                })
+                .collect()
+        }
+        GroupBy::Pathname => {
+            vm.maps()
+                .pathnames()
+                .filter_map(|pathname| {
+                    let scanned = vm
+                        .maps()
+                        .addrs_for_pathname(pathname.as_str())
+                        .unwrap_or(&[])
+                        .iter()
+                        .filter_map(|addr_range| per_region.get(&addr_range.start()))
+                        .collect::<Vec<_>>();
+
+                    if scanned.is_empty() {
+                        return None;
+                    }
+
+                    let mut stats = RegionStats::new();
+                    let mut nodes = NodeCounts::new();
+                    for (region_stats, region_nodes) in scanned.into_iter() {
+                        stats += region_stats;
+                        nodes += region_nodes;
+                    }
+
+                    let name = match pathname.as_str() {
+                        "" => "[anonymous]".to_string(),
+                        path => path.to_string(),
+                    };
+                    Some((name, stats, nodes))
+                })
+                .collect()
+        }
+        GroupBy::Kind => {
+            let mut by_kind = std::collections::BTreeMap::<MappingKind, (RegionStats, NodeCounts)>::new();
+            for (addr, (stats, nodes)) in per_region.iter() {
+                let kind = vm.maps().region(*addr).unwrap().kind();
+                let entry = by_kind.entry(kind).or_default();
+                entry.0 += stats;
+                entry.1 += nodes;
+            }
+            by_kind
+                .into_iter()
+                .map(|(kind, (stats, nodes))| (kind.to_string(), stats, nodes))
+                .collect()
+        }
+    };
+
+    // groups can be emptied out by --flags
+    beholder::stats::rank_groups(&mut groups, cmd.sort, cmd.top);
+
+    cli::print_stats_header(cmd.numa);
+    for (name, stats, nodes) in groups.iter() {
+        cli::print_stats_row(stats, numa_column(nodes), name);
+    }
+    cli::print_stats_row(&total, numa_column(&total_nodes), "[total]");
 }
 
 
@@ -289,6 +997,9 @@ fn print_command(
     args: &Args,
     cmd: &Print,
 ) {
+    /// pages per move_pages(2) query with --numa
+    const NODE_BATCH: usize = 1024;
+
     let print_maps = cmd
         .select
         .as_ref()
@@ -302,27 +1013,771 @@ fn print_command(
 
     let mut vm = init_process_vma(cmd.pid, args.debug);
     let regions = list_regions(&vm, cmd.region);
+    let numa_maps = if cmd.numa {
+        Some(vm.numa_maps().unwrap_or_else(panic_on_err!()))
+    } else {
+        None
+    };
 
     for addr in regions.into_iter() {
         let region = vm
             .region(addr)
             .unwrap_or_else(|| panic!("no such region with starting address {:x}", addr));
+        let mapped = region.region();
+        let residency = if cmd.residency && mapped.kind() == MappingKind::File {
+            let offset = usize::from(mapped.offset()) as u64;
+            let len = mapped.addr_range().len() as u64;
+            fincore_range(mapped.pathname().as_str(), offset, len)
+                .map_err(|err| warn!("unable to read page cache residency of {}: {}", mapped.pathname(), err))
+                .ok()
+        } else {
+            None
+        };
+
         if print_maps {
             cli::println(&region, args.verbose);
+            if let Some(numa_region) = numa_maps.as_ref().and_then(|numa_maps| numa_maps.region(addr)) {
+                println!("policy={} {}", numa_region.policy, numa_region.nodes);
+            }
+            if let Some(residency) = residency.as_ref() {
+                println!("resident={}/{}", residency.resident(), residency.pages());
+            }
         }
 
         if !print_pages {
             continue;
         }
 
-        let pages_iter = region.try_iter(cmd.page_size).unwrap_or_else(panic_on_err!());
-        for page_result in pages_iter {
-            let page = page_result.unwrap_or_else(panic_on_err!());
-            cli::println(&page, args.verbose);
+        let print_page = |page: &PageDescriptor, node: Option<Option<u32>>| {
+            match node {
+                Some(Some(node)) => print!("node={} ", node),
+                Some(None) => print!("node=- "),
+                None => (),
+            }
+            if cmd.residency {
+                match residency
+                    .as_ref()
+                    .zip(page.offset)
+                    .and_then(|(residency, offset)| residency.is_resident(offset as u64))
+                {
+                    Some(true) => print!("cached=y "),
+                    Some(false) => print!("cached=n "),
+                    None => print!("cached=- "),
+                }
+            }
+            cli::println(page, args.verbose);
+        };
+
+        // with --numa the nodes are looked up with one move_pages(2) call per
+        // batch, otherwise every page is printed as soon as it is read
+        let mut batch = Vec::new();
+        let print_batch = |batch: &mut Vec<PageDescriptor>| {
+            let addrs = batch.iter().map(|page| page.addr_range.start()).collect::<Vec<_>>();
+            let nodes = vm.page_nodes(&addrs).unwrap_or_else(panic_on_err!());
+            for (page, node) in batch.drain(..).zip(nodes) {
+                print_page(&page, Some(node));
+            }
+        };
+
+        let pages = region
+            .try_iter(cmd.page_size)
+            .unwrap_or_else(panic_on_err!())
+            .map(|page_result| page_result.unwrap_or_else(panic_on_err!()))
+            .filter(|page| cmd.flags.as_ref().map(|expr| page.matches(expr)).unwrap_or(true));
+        for page in pages {
+            if !cmd.numa {
+                print_page(&page, None);
+                continue;
+            }
+            batch.push(page);
+            if batch.len() == NODE_BATCH {
+                print_batch(&mut batch);
+            }
+        }
+        if !batch.is_empty() {
+            print_batch(&mut batch);
+        }
+    }
+}
+
+fn page_types_command(
+    args: &Args,
+    cmd: &PageTypes,
+) {
+    const PAGE_SIZE: u64 = PageSize::Normal as u64;
+    const MB: f64 = (1 << 20) as f64;
+
+    let start = cmd.pfn_start.unwrap_or(0) as u64;
+    let scan = match cmd.pfn_end {
+        Some(end) => beholder::kpageflags::scan_physical(start..(end as u64)),
+        None => beholder::kpageflags::scan_physical(start..),
+    }
+    .unwrap_or_else(panic_on_err!());
+
+    let mut histogram = std::collections::BTreeMap::<u64, u64>::new();
+    for page_result in scan {
+        let page = page_result.unwrap_or_else(panic_on_err!());
+        if cmd.bits.as_ref().map(|bits| bits.matches(&page.flags)).unwrap_or(true) {
+            *histogram.entry(page.flags.bits()).or_default() += 1;
+        }
+    }
+
+    println!(
+        "{:>18} {:>12} {:>10}  {}",
+        "flags", "page-count", "MiB", "symbolic-flags"
+    );
+    for (bits, count) in histogram.iter() {
+        println!(
+            "{:#018x} {:>12} {:>10.2}  {}",
+            bits,
+            count,
+            (count * PAGE_SIZE) as f64 / MB,
+            KPageFlags::new(*bits)
+        );
+    }
+
+    let total = histogram.values().sum::<u64>();
+    println!("{:>18} {:>12} {:>10.2}", "total", total, (total * PAGE_SIZE) as f64 / MB);
+}
+
+
+fn thp_command(
+    args: &Args,
+    cmd: &Thp,
+) {
+    let vm = init_process_vma(cmd.pid, args.debug);
+    let regions = list_regions(&vm, cmd.region);
+
+    let print_report = |report: &ThpReport, name: &str| {
+        println!(
+            "{:>8} {:>10} {:>8} {:>10} {:>10} {:>11}  {}",
+            report.ranges.len(),
+            report.thp_bytes() >> 10,
+            report.heads(),
+            report.small_bytes() >> 10,
+            report.candidates().count(),
+            report.page_table_savings() >> 10,
+            name
+        );
+        if cmd.ranges {
+            report.ranges.iter().for_each(|range| println!("    {}", range));
         }
+    };
+
+    println!(
+        "{:>8} {:>10} {:>8} {:>10} {:>10} {:>11}  {}",
+        "ranges", "thp-KB", "heads", "4k-KB", "candidates", "pt-saved-KB", "region"
+    );
+
+    let (mut attempted, mut collapsed) = (0, 0);
+    for addr in regions.into_iter() {
+        let region = vm
+            .region(addr)
+            .unwrap_or_else(|| panic!("no such region with starting address {:x}", addr));
+        let report = ThpReport::from_region(&region).unwrap_or_else(panic_on_err!());
+        if report.ranges.iter().all(|range| range.present == 0) {
+            continue;
+        }
+
+        let name = format!("{} {}", region.region().addr_range(), region.region().pathname());
+        print_report(&report, &name);
+
+        if !cmd.collapse || report.candidates().count() == 0 {
+            continue;
+        }
+
+        let candidates = report.candidates().map(|range| range.range).collect::<Vec<_>>();
+        for outcome in beholder::thp::collapse(vm.pid(), candidates.iter()).unwrap_or_else(panic_on_err!()) {
+            attempted += 1;
+            match outcome.result {
+                Ok(()) => {
+                    collapsed += 1;
+                    println!("    collapsed {}", outcome.range);
+                }
+                Err(err) => println!("    failed {}: {}", outcome.range, err),
+            }
+        }
+
+        let after = ThpReport::from_region(&region).unwrap_or_else(panic_on_err!());
+        print_report(&after, &format!("{} [after collapse]", name));
+    }
+
+    if cmd.collapse {
+        println!("collapsed {} of {} candidate ranges", collapsed, attempted);
     }
 }
 
+
+fn fincore_command(
+    args: &Args,
+    cmd: &Fincore,
+) {
+    println!("{:>10} {:>10} {:>12}  {}", "resident", "pages", "size", "file");
+    for path in cmd.files.iter() {
+        let file = match fincore(path) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("skipping {:?}: {}", path, err);
+                continue;
+            }
+        };
+
+        println!(
+            "{:>10} {:>10} {:>12}  {}",
+            file.residency.resident(),
+            file.residency.pages(),
+            file.size,
+            file.path.display()
+        );
+        if cmd.ranges {
+            for range in file.residency.resident_ranges() {
+                println!("    {:#x}-{:#x}", range.start, range.end);
+            }
+        }
+    }
+}
+
+
+fn swap_command(
+    args: &Args,
+    cmd: &Swap,
+) {
+    const PAGE_SIZE: u64 = PageSize::Normal as u64;
+
+    let swaps = Swaps::load().unwrap_or_else(panic_on_err!());
+    // /proc/swaps has no type column, the position is only a guess
+    println!(
+        "{:>6} {:<10} {:>12} {:>12} {:>9}  {}",
+        "type?", "kind", "size-KB", "used-KB", "priority", "area"
+    );
+    for (swap_type, device) in swaps.devices().iter().enumerate() {
+        println!(
+            "{:>6} {:<10} {:>12} {:>12} {:>9}  {}",
+            swap_type,
+            device.kind,
+            device.size_kb,
+            device.used_kb,
+            device.priority,
+            device.filename.display()
+        );
+    }
+    println!();
+
+    let vm = init_process_vma(cmd.pid, args.debug);
+    let regions = list_regions(&vm, cmd.region);
+
+    let area_name = |swap_type: u8| {
+        match swaps.guess_device(swap_type) {
+            Some(device) => format!("type{}(unverified:{})", swap_type, device.filename.display()),
+            None => format!("type{}", swap_type),
+        }
+    };
+    let print_row = |totals: &SwapTotals, name: &str| {
+        let areas = totals
+            .iter()
+            .map(|(swap_type, pages)| format!("{}={}", area_name(swap_type), pages))
+            .collect::<Vec<_>>();
+        println!(
+            "{:>10} {:>10}  {:<24}  {}",
+            totals.total(),
+            (totals.total() * PAGE_SIZE) >> 10,
+            areas.join(","),
+            name
+        );
+    };
+
+    println!("{:>10} {:>10}  {:<24}  {}", "swapped", "KB", "areas", "region");
+    let mut total = SwapTotals::new();
+    for addr in regions.into_iter() {
+        let region = vm
+            .region(addr)
+            .unwrap_or_else(|| panic!("no such region with starting address {:x}", addr));
+        let totals = SwapTotals::from_region(&region).unwrap_or_else(panic_on_err!());
+        if totals.total() > 0 {
+            print_row(
+                &totals,
+                &format!("{} {}", region.region().addr_range(), region.region().pathname()),
+            );
+        }
+        total.merge(&totals);
+    }
+    print_row(&total, "[total]");
+}
+
+
+fn replicate_command(
+    args: &Args,
+    cmd: &Replicate,
+) {
+    let mut replicator = Replicator::new(cmd.pid, &cmd.path, &cmd.to).unwrap_or_else(panic_on_err!());
+    println!(
+        "replicating {} of pid {} to {}",
+        replicator.source().display(),
+        cmd.pid,
+        cmd.to.display()
+    );
+
+    println!("{:>8} {:>8} {:>8} {:>12} {:>12}", "round", "mappings", "ranges", "copied", "file-size");
+    let mut copied = 0;
+    let mut round = 0;
+    while cmd.rounds.map(|rounds| round < rounds).unwrap_or(true) {
+        std::thread::sleep(cmd.interval);
+
+        let stats = replicator.round(cmd.quiesce).unwrap_or_else(panic_on_err!());
+        round = stats.round;
+        copied += stats.bytes_copied;
+        println!(
+            "{:>8} {:>8} {:>8} {:>12} {:>12}",
+            stats.round, stats.mappings, stats.ranges, stats.bytes_copied, stats.file_len
+        );
+    }
+    println!("copied {} bytes", copied);
+}
+
+
+fn dump_command(
+    args: &Args,
+    cmd: &Dump,
+) {
+    use std::io::Write;
+
+    let vm = init_process_vma(cmd.pid, args.debug);
+    let _quiesce = quiesce(vm.pid(), cmd.quiesce);
+    let regions = list_regions(&vm, cmd.region);
+    let file = std::fs::File::create(&cmd.output).unwrap_or_else(panic_on_err!());
+    let mut out = std::io::BufWriter::new(file);
+
+    // read in chunks, a region may be larger than the memory of beholder
+    let mut remote = RemoteMemory::new(vm.pid());
+    let mut buf = vec![0u8; 256 * PageSize::Normal as usize];
+
+    println!("{:>12} {:>12}  {:<33}  {}", "offset", "size", "range", "region");
+    let mut offset = 0;
+    let mut missing = 0;
+    for addr in regions.into_iter() {
+        let region = vm
+            .region(addr)
+            .unwrap_or_else(|| panic!("no such region with starting address {:x}", addr));
+        let mapped = region.region();
+        if !mapped.perms().contains(Perm::Read) {
+            debug!("skipping unreadable region {}", mapped.addr_range());
+            continue;
+        }
+
+        let pages = region
+            .try_iter(None)
+            .unwrap_or_else(panic_on_err!())
+            .map(|page_result| page_result.unwrap_or_else(panic_on_err!()))
+            .filter(|page| {
+                if cmd.dirty_only {
+                    page.pte.is_soft_dirty()
+                } else {
+                    page.pte.is_present() || page.pte.is_swapped()
+                }
+            })
+            .map(|page| page.addr_range)
+            .collect::<Vec<_>>();
+
+        for range in coalesce_ranges(pages) {
+            for start in (range.start()..range.end()).step_by(buf.len()) {
+                let chunk = AddressRange::new(start, range.end().min(start + buf.len()));
+                let read = region
+                    .read_pages(&mut remote, &[chunk], &mut buf)
+                    .unwrap_or_else(panic_on_err!());
+                for gone in read.missing.iter() {
+                    warn!("could not read {}, written as zeros", gone);
+                    missing += gone.len();
+                }
+                out.write_all(&buf[..chunk.len()]).unwrap_or_else(panic_on_err!());
+            }
+
+            println!(
+                "{:>#12x} {:>12}  {:<33}  {}",
+                offset,
+                range.len(),
+                range.to_string(),
+                mapped.pathname()
+            );
+            offset += range.len();
+        }
+    }
+    out.flush().unwrap_or_else(panic_on_err!());
+    println!("dumped {} bytes to {}, {} bytes missing", offset, cmd.output.display(), missing);
+}
+
+
+fn checkpoint_command(
+    args: &Args,
+    cmd: &Checkpoint,
+) {
+    let open_images = |paths: &[PathBuf]| {
+        let paths = if paths.is_empty() {
+            let chain = ChainDir::open(&cmd.dir).unwrap_or_else(panic_on_err!());
+            chain.images().unwrap_or_else(panic_on_err!())
+        } else {
+            paths.to_vec()
+        };
+        paths
+            .iter()
+            .map(|path| Image::open(path).unwrap_or_else(panic_on_err!()))
+            .collect::<Vec<_>>()
+    };
+    let print_image = |image: &Image| {
+        let header = image.header();
+        println!(
+            "{:>8} {:<6} {:>8} {:>8} {:>12}  {}",
+            header.sequence,
+            format!("{:?}", header.kind),
+            header.pid,
+            header.layout.len(),
+            header.pages.len(),
+            image.path().display()
+        );
+    };
+    let print_header = || println!("{:>8} {:<6} {:>8} {:>8} {:>12}  {}", "sequence", "kind", "pid", "regions", "pages", "image");
+
+    match &cmd.action {
+        None => {
+            let vm = init_process_vma(cmd.pid, args.debug);
+            let regions = list_regions(&vm, cmd.region);
+            let chain = ChainDir::create(&cmd.dir).unwrap_or_else(panic_on_err!());
+            let image = chain.checkpoint(&vm, &regions, cmd.quiesce).unwrap_or_else(panic_on_err!());
+            print_header();
+            print_image(&image);
+        }
+        Some(CheckpointAction::Inspect { images, pages }) => {
+            let images = open_images(images);
+            print_header();
+            for image in images.iter() {
+                print_image(image);
+                if *pages {
+                    for entry in image.header().pages.iter() {
+                        println!("    {:#x} offset={:#x} checksum={:016x}", entry.addr, entry.offset, entry.checksum);
+                    }
+                }
+                match image.verify() {
+                    Ok(()) => {}
+                    Err(err) => println!("    FAILED: {}", err),
+                }
+            }
+            match beholder::checkpoint::verify_chain(&images) {
+                Ok(()) => println!("chain of {} images verified", images.len()),
+                Err(err) => println!("chain FAILED: {}", err),
+            }
+        }
+        Some(CheckpointAction::Merge { images, output }) => {
+            let images = open_images(images);
+            let merged = beholder::checkpoint::merge(&images, output).unwrap_or_else(panic_on_err!());
+            print_header();
+            print_image(&merged);
+        }
+    }
+}
+
+
+fn precopy_command(
+    args: &Args,
+    cmd: &Precopy,
+) {
+    const CURVE_WIDTH: u64 = 50;
+
+    let config = PrecopyConfig {
+        bandwidth:       cmd.bandwidth,
+        max_rounds:      cmd.max_rounds.max(1),
+        downtime_target: cmd.downtime_target,
+    };
+    let report = beholder::precopy::run(cmd.pid, &config, cmd.quiesce).unwrap_or_else(panic_on_err!());
+
+    let largest = report.rounds.iter().map(|round| round.bytes).max().unwrap_or(0).max(report.stop_copy.bytes).max(1);
+    println!(
+        "{:>6} {:>10} {:>14} {:>12} {:>10}  {}",
+        "round", "pages", "bytes", "transfer-ms", "scan-ms", "curve"
+    );
+    let rounds = report.rounds.iter().map(|round| (round, "")).chain(std::iter::once((&report.stop_copy, " stop-and-copy")));
+    for (round, label) in rounds {
+        let bar = "#".repeat(((round.bytes * CURVE_WIDTH + largest - 1) / largest) as usize);
+        println!(
+            "{:>6} {:>10} {:>14} {:>12.1} {:>10.1}  {}{}",
+            round.round,
+            round.pages,
+            round.bytes,
+            round.transfer_time.as_secs_f64() * 1e3,
+            round.scan_time.as_secs_f64() * 1e3,
+            bar,
+            label
+        );
+    }
+
+    println!(
+        "{} after {} rounds: pre-copied {} bytes, stop-and-copy {} bytes, downtime estimate {:.1}ms (target {:.1}ms)",
+        if report.converged { "converged" } else { "did not converge" },
+        report.rounds.len() + 1,
+        report.precopy_bytes(),
+        report.stop_copy.bytes,
+        report.downtime().as_secs_f64() * 1e3,
+        config.downtime_target.as_secs_f64() * 1e3
+    );
+}
+
+
+fn track_command(
+    args: &Args,
+    cmd: &Track,
+) {
+    let vm = init_process_vma(Some(cmd.pid), args.debug);
+    let regions = list_regions(&vm, cmd.region);
+    let mut tracker = {
+        let _quiesce = quiesce(cmd.pid, cmd.quiesce);
+        // the remote source only reads through process_vm_readv
+        unsafe { ContentTracker::with_shadows(&vm, &regions, HashSource::Remote, cmd.shadow_budget as usize) }
+            .unwrap_or_else(panic_on_err!())
+    };
+    println!(
+        "tracking {} pages of pid {}, {} with shadow copies",
+        tracker.pages(),
+        cmd.pid,
+        tracker.shadowed_pages()
+    );
+
+    let print_row = |label: &str, stats: &ContentStats| {
+        let amplification = stats
+            .write_amplification()
+            .map(|factor| format!("{:.1}x", factor))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:>18} {:>8} {:>8} {:>8} {:>9} {:>10} {:>11.1}% {:>12} {:>8} {:>10}",
+            label,
+            stats.dirty,
+            stats.modified,
+            stats.spurious,
+            stats.untracked,
+            stats.unreadable,
+            stats.false_dirty_ratio() * 100.0,
+            stats.changed_bytes,
+            stats.changed_lines,
+            amplification
+        );
+    };
+
+    println!(
+        "{:>18} {:>8} {:>8} {:>8} {:>9} {:>10} {:>12} {:>12} {:>8} {:>10}",
+        "round", "dirty", "modified", "spurious", "untracked", "unreadable", "false-dirty", "changed", "lines", "write-amp"
+    );
+    let mut round = 0;
+    while cmd.rounds.map(|rounds| round < rounds).unwrap_or(true) {
+        std::thread::sleep(cmd.interval);
+        round += 1;
+
+        let report = {
+            let _quiesce = quiesce(cmd.pid, cmd.quiesce);
+            let vm = init_process_vma(Some(cmd.pid), false);
+            unsafe { tracker.collect(&vm) }.unwrap_or_else(panic_on_err!())
+        };
+        print_row(&round.to_string(), &report.total);
+        if cmd.per_region {
+            for (addr, stats) in report.regions.iter() {
+                print_row(&format!("{:x}", addr), stats);
+            }
+        }
+        if cmd.changes {
+            for change in report.changes.iter() {
+                println!(
+                    "    {:#x} changed={} lines={} mask={:016x}",
+                    change.addr,
+                    change.changed_bytes,
+                    change.changed_lines(),
+                    change.lines
+                );
+            }
+        }
+    }
+}
+
+
+fn content_stats_command(
+    args: &Args,
+    cmd: &ContentStatsCmd,
+) {
+    let vm = init_process_vma(Some(cmd.pid), args.debug);
+    let regions = list_regions(&vm, cmd.region);
+    let report = {
+        let _quiesce = quiesce(cmd.pid, cmd.quiesce);
+        beholder::census::census(&vm, &regions, cmd.sample_every).unwrap_or_else(panic_on_err!())
+    };
+
+    let print_row = |stats: &PageCensus, name: &str| {
+        let ratio = stats
+            .compression_ratio()
+            .map(|ratio| format!("{:.2}", ratio))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}  {}",
+            stats.pages,
+            stats.zero,
+            stats.zero_page,
+            stats.duplicate,
+            stats.ksm,
+            stats.merge_potential(),
+            ratio,
+            name
+        );
+    };
+
+    println!(
+        "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}  {}",
+        "pages", "zero", "zero-page", "duplicate", "ksm", "mergeable", "compress", "region"
+    );
+    for (addr, stats) in report.regions.iter() {
+        if stats.pages > 0 {
+            let region = vm.maps().region(*addr).expect("the census only covers mapped regions");
+            print_row(stats, &format!("{} {}", region.addr_range(), region.pathname()));
+        }
+    }
+    print_row(&report.total, "total");
+}
+
+
+fn wss_command(
+    args: &Args,
+    cmd: &Wss,
+) {
+    const MB: f64 = (1 << 20) as f64;
+
+    let vm = init_process_vma(Some(cmd.pid), args.debug);
+    let regions = list_regions(&vm, cmd.region);
+    let report = beholder::wss::estimate(cmd.pid, &regions, cmd.method, cmd.duration).unwrap_or_else(panic_on_err!());
+
+    let print_row = |stats: &WssStats, name: &str| {
+        println!(
+            "{:>10} {:>10} {:>10} {:>12.2} {:>12.2}  {}",
+            stats.present,
+            stats.accessed,
+            stats.written,
+            stats.accessed_bytes() as f64 / MB,
+            stats.written_bytes() as f64 / MB,
+            name
+        );
+    };
+
+    println!(
+        "working set of pid {} over {:.2}s by {}",
+        cmd.pid,
+        report.duration.as_secs_f64(),
+        report.method
+    );
+    println!(
+        "{:>10} {:>10} {:>10} {:>12} {:>12}  {}",
+        "present", "accessed", "written", "accessed-MB", "written-MB", "region"
+    );
+    for (addr, stats) in report.regions.iter() {
+        if stats.present > 0 || stats.written > 0 {
+            let region = vm.maps().region(*addr).expect("the estimate only covers mapped regions");
+            print_row(stats, &format!("{} {}", region.addr_range(), region.pathname()));
+        }
+    }
+    print_row(&report.total, "total");
+}
+
+
+fn hotness_command(
+    args: &Args,
+    cmd: &HotnessCmd,
+) {
+    let vm = init_process_vma(Some(cmd.pid), args.debug);
+    let regions = list_regions(&vm, cmd.region);
+    let mut tracker = HotnessTracker::new(cmd.pid, &regions, cmd.method).unwrap_or_else(panic_on_err!());
+    for _round in 0..cmd.rounds {
+        std::thread::sleep(cmd.interval);
+        tracker.sample().unwrap_or_else(panic_on_err!());
+    }
+
+    let thresholds = HotnessThresholds {
+        hot:  cmd.hot,
+        warm: cmd.warm,
+    };
+    let report = tracker.classify(&thresholds);
+
+    let print_row = |histogram: &HotnessHistogram, name: &str| {
+        println!(
+            "{:>10} {:>10} {:>10} {:>10}  {}",
+            histogram.hot, histogram.warm, histogram.cold, histogram.written, name
+        );
+    };
+
+    println!("{} rounds of {:.2}s by {}", report.rounds, cmd.interval.as_secs_f64(), cmd.method);
+    println!("{:>10} {:>10} {:>10} {:>10}  {}", "hot", "warm", "cold", "written", "region");
+    for (addr, histogram) in report.regions.iter() {
+        if histogram.pages() == 0 {
+            continue;
+        }
+        let region = vm.maps().region(*addr).expect("the tracker only covers mapped regions");
+        print_row(histogram, &format!("{} {}", region.addr_range(), region.pathname()));
+        if cmd.cold_ranges {
+            for range in report.cold_ranges.get(addr).into_iter().flatten() {
+                println!("{:>10} {}", "", range);
+            }
+        }
+    }
+    print_row(&report.total, "total");
+}
+
+
+fn reclaim_command(
+    args: &Args,
+    cmd: &Reclaim,
+) {
+    let vm = init_process_vma(Some(cmd.pid), args.debug);
+    let regions = list_regions(&vm, cmd.region);
+    let cold = beholder::reclaim::find_cold(cmd.pid, &regions, cmd.method, cmd.cold_for, cmd.interval)
+        .unwrap_or_else(panic_on_err!());
+    let outcomes = beholder::reclaim::reclaim(cmd.pid, &cold, cmd.advice, cmd.dry_run).unwrap_or_else(panic_on_err!());
+
+    let residency = |residency: Option<&PageResidency>| {
+        residency
+            .map(|residency| format!("{:>8} {:>8} {:>8}", residency.present, residency.swapped, residency.absent))
+            .unwrap_or_else(|| format!("{:>8} {:>8} {:>8}", "-", "-", "-"))
+    };
+
+    println!(
+        "{} ranges of pid {} untouched for {:.2}s{}",
+        outcomes.len(),
+        cmd.pid,
+        cmd.cold_for.as_secs_f64(),
+        if cmd.dry_run { ", dry run" } else { "" }
+    );
+    println!(
+        "{:<33}  {:>8} {:>8} {:>8}  {:>8} {:>8} {:>8}  {}",
+        "range", "present", "swapped", "absent", "present", "swapped", "absent", cmd.advice
+    );
+    let mut before = PageResidency::default();
+    let mut after = PageResidency::default();
+    for outcome in outcomes.iter() {
+        before.merge(&outcome.before);
+        if let Some(residency) = outcome.after.as_ref() {
+            after.merge(residency);
+        }
+        let advised = match &outcome.advised {
+            Some(Ok(bytes)) => format!("{} bytes", bytes),
+            Some(Err(err)) => format!("{}", err),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<33}  {}  {}  {}",
+            outcome.range.to_string(),
+            residency(Some(&outcome.before)),
+            residency(outcome.after.as_ref()),
+            advised
+        );
+    }
+    println!(
+        "{:<33}  {}  {}",
+        "total",
+        residency(Some(&before)),
+        residency(Some(&after).filter(|_after| !cmd.dry_run))
+    );
+}
+
+
 /// Mmap a file. For --loops=n times test the softdirty bits are cleared and set as expected using
 /// the behavior defined by --assert=<behavior> to detect a mismatch in expected values.
 fn demo_command(
@@ -335,31 +1790,53 @@ fn demo_command(
     let map_size = page_size * page_count;
     let rounds = 1..=cmd.loops;
 
-    let options = MmapOptions {
-        path:           std::borrow::Cow::Borrowed(path),
-        base_addr:      0 as *mut _,
-        len:            map_size,
-        addr_offset:    0,
-        remove_on_drop: true,
-    };
+    let mut map = match cmd.backing {
+        BackingKind::File => {
+            let options = MmapOptions {
+                path:           std::borrow::Cow::Borrowed(path),
+                base_addr:      0 as *mut _,
+                len:            map_size,
+                addr_offset:    0,
+                remove_on_drop: true,
+                page_size:      Some(cmd.page_size.unwrap_or_default()),
+            };
 
-    let map = MmapFile::new(
-        &options,
-        ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-        MapFlags::MAP_SHARED | MapFlags::MAP_NORESERVE,
-    )
+            MmapFile::new(
+                &options,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED | MapFlags::MAP_NORESERVE,
+            )
+        }
+        BackingKind::AnonPrivate => {
+            match cmd.page_size {
+                Some(page_size @ PageSize::Huge) | Some(page_size @ PageSize::Giga) => {
+                    MmapFile::anonymous_hugetlb(map_size, page_size)
+                }
+                _ => MmapFile::anonymous_private(map_size),
+            }
+        }
+        BackingKind::AnonShared | BackingKind::Memfd if page_size != PageSize::Normal as usize => {
+            Err(crate::deps::beholder::error::Error::Parse {
+                value:    format!("{:?}", cmd.page_size.unwrap_or_default()),
+                typename: std::any::type_name::<PageSize>(),
+                reason:   "anon-shared and memfd backings only map normal pages".to_string(),
+            })
+        }
+        BackingKind::AnonShared => MmapFile::anonymous_shared(map_size),
+        BackingKind::Memfd => MmapFile::memfd("softpte-tracking-demo", map_size, false),
+    }
     .unwrap_or_else(panic_on_err!());
 
-    let map_root = map.as_nonnull().as_ptr();
+    let map_root = map.as_nonnull().as_ptr() as usize;
 
     let mut vm = init_process_vma(None, args.debug);
 
     // closure to run the assert behavior
     let assert_all_region_softdirty_ptes_are = |expected_value: bool| {
-        let region = vm.region(map_root as usize).unwrap_or_else(|| {
+        let region = vm.region(map_root).unwrap_or_else(|| {
             panic!(
                 "could not find region corresponding \
-                to memory mapped file, address={:p}",
+                to memory mapped file, address={:#x}",
                 map_root
             )
         });
@@ -381,13 +1858,15 @@ fn demo_command(
         vm.clear_refs();
         assert_all_region_softdirty_ptes_are(false);
 
-        let mut page_ptr = map_root;
+        // memfd and shared anonymous mappings are made of 4K pages, write the
+        // first of each --page-size page
+        let step = page_size / map.page_size();
         for page_num in 0..cmd.page_count {
-            println!("{:p} [# {:0>3}]: write 'x'", page_ptr, page_num);
-            unsafe {
-                *page_ptr = chars.next().unwrap();
-                page_ptr = page_ptr.add(page_size);
-            };
+            let mut page = map
+                .page_mut(page_num * step)
+                .expect("the demo mapping is writable and has no clones");
+            println!("{:#x} [# {:0>3}]: write 'x'", page.addr(), page_num);
+            page.write(0, chars.next().unwrap());
         }
 
         assert_all_region_softdirty_ptes_are(true);
@@ -417,5 +1896,38 @@ fn main() {
         Command::DirtyCounts(cmd) => dirty_counts_command(&args, cmd),
         Command::Print(cmd) => print_command(&args, cmd),
         Command::Demo(cmd) => demo_command(&args, cmd),
+        Command::PageTypes(cmd) => page_types_command(&args, cmd),
+        Command::Thp(cmd) => thp_command(&args, cmd),
+        Command::Fincore(cmd) => fincore_command(&args, cmd),
+        Command::Swap(cmd) => swap_command(&args, cmd),
+        Command::Replicate(cmd) => replicate_command(&args, cmd),
+        Command::Dump(cmd) => dump_command(&args, cmd),
+        Command::Checkpoint(cmd) => checkpoint_command(&args, cmd),
+        Command::Precopy(cmd) => precopy_command(&args, cmd),
+        Command::Track(cmd) => track_command(&args, cmd),
+        Command::ContentStats(cmd) => content_stats_command(&args, cmd),
+        Command::Wss(cmd) => wss_command(&args, cmd),
+        Command::Hotness(cmd) => hotness_command(&args, cmd),
+        Command::Reclaim(cmd) => reclaim_command(&args, cmd),
     }
 }
+
+
+#[test]
+fn test_parse_size_and_bandwidth() {
+    assert_eq!(cli::parse_size("4096").unwrap(), 4096);
+    assert_eq!(cli::parse_size("64K").unwrap(), 64 << 10);
+    assert_eq!(cli::parse_size("1.5GiB").unwrap(), 3 << 29);
+    assert_eq!(cli::parse_size("10MB").unwrap(), 10_000_000);
+    assert_eq!(cli::parse_size("0").unwrap(), 0);
+    assert!(cli::parse_size("5XB").is_err());
+    assert!(cli::parse_size("GiB").is_err());
+
+    assert_eq!(cli::parse_bandwidth("500MB/s").unwrap(), 500_000_000);
+    assert_eq!(cli::parse_bandwidth("1GiB/s").unwrap(), 1 << 30);
+    assert!(cli::parse_bandwidth("0").is_err());
+    assert!(cli::parse_bandwidth("5XB/s").is_err());
+
+    assert_eq!(cli::parse_rounds("255").unwrap(), 255);
+    assert!(cli::parse_rounds("256").is_err());
+}
@@ -174,6 +174,15 @@ impl fmt::Display for AddressRange {
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
 pub struct PermSet(Vec<Perm>);
 
+impl PermSet {
+    pub fn contains(
+        &self,
+        perm: Perm,
+    ) -> bool {
+        self.0.contains(&perm)
+    }
+}
+
 impl<'a> TryFrom<&'a str> for PermSet {
     type Error = Error;
 
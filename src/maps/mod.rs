@@ -97,7 +97,11 @@ use self::column::{
     PermSet,
 };
 use crate::{
-    deps::{serde, log::warn},
+    deps::{
+        derive_more,
+        log::warn,
+        serde,
+    },
     error::Error,
 };
 use std::{
@@ -154,9 +158,62 @@ impl MappedRegion {
         &self.pathname
     }
 
+    /// The offset into the backing file of the byte mapped at `addr`. Only
+    /// meaningful for file backed regions.
+    pub fn file_offset(
+        &self,
+        addr: usize,
+    ) -> usize {
+        usize::from(self.offset) + (addr - self.addr_range.start())
+    }
+
     pub fn extra(&self) -> &[String] {
         self.extra.as_slice()
     }
+
+    pub fn kind(&self) -> MappingKind {
+        match &self.pathname {
+            PathName::Empty => MappingKind::Anonymous,
+            PathName::Real(_) => MappingKind::File,
+            PathName::Pseudo(name) if name == "[heap]" => MappingKind::Heap,
+            PathName::Pseudo(name) if name.starts_with("[stack") => MappingKind::Stack,
+            PathName::Pseudo(_) => MappingKind::Special,
+        }
+    }
+}
+
+
+/// Coarse classification of a [`MappedRegion`] derived from its pathname
+/// column.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    derive_more::Display,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub enum MappingKind {
+    /// no pathname, e.g. `mmap(MAP_ANONYMOUS)` or the tail of the bss
+    #[display(fmt = "anonymous")]
+    Anonymous,
+    /// backed by a real file on some filesystem
+    #[display(fmt = "file")]
+    File,
+    /// `[heap]`
+    #[display(fmt = "heap")]
+    Heap,
+    /// `[stack]` and, on older kernels, `[stack:<tid>]`
+    #[display(fmt = "stack")]
+    Stack,
+    /// any other pseudo-path such as `[vdso]`, `[vvar]` or `[vsyscall]`
+    #[display(fmt = "special")]
+    Special,
 }
 
 
@@ -277,6 +334,12 @@ impl Maps {
         }
     }
 
+    /// Iterate over the distinct pathnames present in the maps file, in no
+    /// particular order.
+    pub fn pathnames(&self) -> impl Iterator<Item = &PathName> {
+        self.pathname_index.keys()
+    }
+
     /// Get the slice of mapped regions corresponding to the given pathname,
     /// if any exist.
     pub fn addrs_for_pathname<P>(
@@ -451,3 +514,20 @@ ffffffffff600000-ffffffffff601000 r-xp 00000000 00:00 0                  [vsysca
     assert_eq!(pagemap.map.len(), EXAMPLE_PROC_MAPS.lines().count());
     assert_eq!(&format!("{}", pagemap), EXAMPLE_PROC_MAPS);
 }
+
+
+#[test]
+fn test_mapping_kind() {
+    let cases = [
+        ("55a4d1a8c000-55a4d1aad000 rw-p 00000000 00:00 0                          [heap]", MappingKind::Heap),
+        ("7ffce82d7000-7ffce831f000 rw-p 00000000 00:00 0                          [stack]", MappingKind::Stack),
+        ("7fa28b3c0000-7fa28b3c1000 rw-p 00000000 00:00 0", MappingKind::Anonymous),
+        ("7fa28b3be000-7fa28b3bf000 r--p 00023000 103:01 264698                    /usr/lib64/ld-2.26.so", MappingKind::File),
+        ("7ffce83c4000-7ffce83c6000 r-xp 00000000 00:00 0                          [vdso]", MappingKind::Special),
+    ];
+    for (line, kind) in cases.iter() {
+        let region = MappedRegion::try_from(*line).unwrap();
+        assert_eq!(region.kind(), *kind, "{}", line);
+    }
+    assert_eq!(MappingKind::Anonymous.to_string(), "anonymous");
+}
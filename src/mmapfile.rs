@@ -1,30 +1,64 @@
-use crate::deps::{
-    log::{
-        debug,
-        error,
-        info,
-        warn,
+use crate::{
+    deps::{
+        libc,
+        log::{
+            debug,
+            error,
+            info,
+            warn,
+        },
+        nix::{
+            errno::Errno,
+            sys::{
+                memfd::{
+                    memfd_create,
+                    MemFdCreateFlag,
+                },
+                mman::{
+                    mlock,
+                    mmap,
+                    msync,
+                    munlock,
+                    munmap,
+                    MapFlags,
+                    MsFlags,
+                    ProtFlags,
+                },
+                statfs::{
+                    statfs,
+                    HUGETLBFS_MAGIC,
+                },
+            },
+        },
+        serde,
     },
-    nix::sys::mman::{
-        mmap,
-        munmap,
-        MapFlags,
-        ProtFlags,
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        coalesce_ranges,
+        PageDescriptor,
+        PageSize,
+        ProcessVMA,
     },
+    residency::Residency,
 };
 use std::{
     borrow::Cow,
+    ffi::CString,
     fs::{
         File,
         OpenOptions,
     },
-    mem::ManuallyDrop,
-    os::unix::io::AsRawFd,
+    ops::Range,
+    os::unix::io::{
+        AsRawFd,
+        FromRawFd,
+        RawFd,
+    },
     path::{
         Path,
         PathBuf,
     },
-    pin::Pin,
     ptr::NonNull,
     sync::atomic::{
         AtomicBool,
@@ -40,9 +74,68 @@ pub struct MmapOptions<'a> {
     pub len:            crate::deps::libc::size_t,
     pub addr_offset:    crate::deps::libc::off_t,
     pub remove_on_drop: bool,
+    /// When set, `path` must be on a filesystem with this page size (a
+    /// hugetlbfs mount for huge pages) and `len` is rounded up to it.
+    pub page_size:      Option<PageSize>,
 }
 
 impl<'a> MmapOptions<'a> {
+    /// Options for a mapping without a file on disk.
+    fn unbacked(len: usize) -> MmapOptions<'static> {
+        MmapOptions {
+            path: Cow::Owned(PathBuf::new()),
+            base_addr: std::ptr::null_mut(),
+            len,
+            addr_offset: 0,
+            remove_on_drop: false,
+            page_size: None,
+        }
+    }
+
+    /// `len` rounded up to a multiple of the page size.
+    pub fn aligned_len(&self) -> usize {
+        let page_size = self.page_size.unwrap_or_default() as usize;
+        (self.len + page_size - 1) / page_size * page_size
+    }
+
+    /// Check that `path` (or the directory it will be created in) is on a
+    /// filesystem matching the requested page size.
+    fn check_page_size(&self) -> Result<(), Error> {
+        let page_size = match self.page_size {
+            Some(page_size) => page_size as usize,
+            None => return Ok(()),
+        };
+
+        let path = self.path.as_ref();
+        let existing = if path.exists() {
+            path
+        } else {
+            path.parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or(Path::new("."))
+        };
+        let stat = statfs(existing)?;
+        let is_hugetlbfs = stat.filesystem_type() == HUGETLBFS_MAGIC;
+
+        match (page_size, is_hugetlbfs) {
+            (page_size, false) if page_size == PageSize::Normal as usize => Ok(()),
+            (page_size, false) => {
+                Err(Error::NotHugetlbfs {
+                    path: path.to_path_buf(),
+                    page_size,
+                })
+            }
+            (page_size, true) if stat.block_size() as usize != page_size => {
+                Err(Error::PageSizeMismatch {
+                    path:      path.to_path_buf(),
+                    requested: page_size,
+                    actual:    stat.block_size() as usize,
+                })
+            }
+            (_page_size, true) => Ok(()),
+        }
+    }
+
     fn owned<'b>(opts: &MmapOptions<'b>) -> MmapOptions<'static> {
         MmapOptions {
             path:           PathBuf::from(opts.path.clone()).into(),
@@ -50,6 +143,7 @@ impl<'a> MmapOptions<'a> {
             len:            opts.len,
             addr_offset:    opts.addr_offset,
             remove_on_drop: opts.remove_on_drop,
+            page_size:      opts.page_size,
         }
     }
 }
@@ -57,33 +151,55 @@ impl<'a> MmapOptions<'a> {
 unsafe impl Send for MmapOptions<'static> {}
 unsafe impl Sync for MmapOptions<'static> {}
 
+
+/// What the memory of a [`MmapFile`] is backed by.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// a file on disk, created and sized by [`MmapFile::new`]
+    File(PathBuf),
+    /// `MAP_PRIVATE | MAP_ANONYMOUS`
+    AnonymousPrivate,
+    /// `MAP_SHARED | MAP_ANONYMOUS`, shared with child processes after fork
+    AnonymousShared,
+    /// an anonymous file from `memfd_create(2)`. When `inheritable` the fd
+    /// is created without `MFD_CLOEXEC` and survives `execve(2)`.
+    Memfd { name: String, inheritable: bool },
+}
+
+
 struct MmapFileInner {
-    pub path:      PathBuf,
-    pub file:      File,
-    // do not drop this vec as it is created from the
-    // span of the mmap
-    pub buf:       ManuallyDrop<Pin<Vec<u8>>>,
+    pub backing:   Backing,
+    pub file:      Option<File>,
+    // start of the mapping, only dereferenced through the accessors on
+    // MmapFile, Page and PageMut
+    pub ptr:       NonNull<u8>,
+    pub len:       usize,
     pub opts:      MmapOptions<'static>,
     pub flags:     MapFlags,
     pub prot:      ProtFlags,
     pub is_mapped: AtomicBool,
 }
 
+// the mapping is process wide memory, shared access is limited to reads and
+// writes require a unique MmapFile, see MmapFile::page_mut
+unsafe impl Send for MmapFileInner {}
+unsafe impl Sync for MmapFileInner {}
+
 impl MmapFileInner {
     pub fn len(&self) -> usize {
-        self.buf.len()
+        self.len
     }
 
     unsafe fn unmap_memory(&self) -> Result<(), Box<dyn std::error::Error>> {
         unsafe {
-            munmap(self.buf.as_ptr() as *mut u8 as *mut _, self.len()).map_err(|e| {
+            munmap(self.ptr.as_ptr() as *mut _, self.len()).map_err(|e| {
                 error!(
                     "[{}] an unhandled error occurred during the call to munmap({:?}, {}) \
                      unmap memory mapped file at: {:?}  {:?}",
                     MmapFile::TAG,
-                    self.buf.as_ptr(),
+                    self.ptr,
                     self.len(),
-                    self.path,
+                    self.backing,
                     e
                 );
                 e
@@ -99,7 +215,7 @@ impl MmapFileInner {
 
 impl Drop for MmapFileInner {
     fn drop(&mut self) {
-        debug!("[{}::drop] unmapping file {:?}", MmapFile::TAG, self.path);
+        debug!("[{}::drop] unmapping {:?}", MmapFile::TAG, self.backing);
 
         unsafe {
             if self.is_mapped.load(Ordering::SeqCst) {
@@ -110,14 +226,92 @@ impl Drop for MmapFileInner {
             }
         }
 
-        if self.opts.remove_on_drop {
-            std::fs::remove_file(&self.path)
-                .unwrap_or_else(|err| warn!("[{}] could not delete mmap file {:?}", MmapFile::TAG, self.path));
+        match &self.backing {
+            Backing::File(path) if self.opts.remove_on_drop => {
+                std::fs::remove_file(path)
+                    .unwrap_or_else(|err| warn!("[{}] could not delete mmap file {:?}", MmapFile::TAG, path));
+            }
+            _ => {}
+        }
+    }
+}
+
+
+/// Whether [`MmapFile::flush_dirty`] waits for the write back.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum FlushMode {
+    /// `MS_SYNC`
+    Sync,
+    /// `MS_ASYNC`
+    Async,
+}
+
+
+impl FlushMode {
+    fn ms_flags(&self) -> MsFlags {
+        match self {
+            FlushMode::Sync => MsFlags::MS_SYNC,
+            FlushMode::Async => MsFlags::MS_ASYNC,
         }
     }
 }
 
 
+/// What a call to [`MmapFile::flush_dirty`] wrote back.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FlushStats {
+    pub pages_scanned: usize,
+    pub dirty_pages:   usize,
+    /// number of `msync` calls
+    pub ranges:        usize,
+    pub bytes_flushed: usize,
+}
+
+
+/// `madvise(2)` advice for [`MmapFile::advise`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Advice {
+    /// `MADV_WILLNEED`, read the pages in ahead of access
+    WillNeed,
+    /// `MADV_DONTNEED`, drop the pages. Private anonymous pages read back
+    /// as zeros, shared pages are read back from the backing file.
+    DontNeed,
+    /// `MADV_HUGEPAGE`
+    HugePage,
+    /// `MADV_NOHUGEPAGE`
+    NoHugePage,
+    /// `MADV_COLD` (since 5.4), deactivate the pages without reclaiming them
+    Cold,
+    /// `MADV_PAGEOUT` (since 5.4), reclaim the pages now
+    PageOut,
+    /// `MADV_POPULATE_WRITE` (since 5.14), fault the pages in writable
+    PopulateWrite,
+}
+
+
+impl Advice {
+    pub fn as_raw(&self) -> libc::c_int {
+        match self {
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            Advice::DontNeed => libc::MADV_DONTNEED,
+            Advice::HugePage => libc::MADV_HUGEPAGE,
+            Advice::NoHugePage => libc::MADV_NOHUGEPAGE,
+            Advice::Cold => libc::MADV_COLD,
+            Advice::PageOut => libc::MADV_PAGEOUT,
+            Advice::PopulateWrite => libc::MADV_POPULATE_WRITE,
+        }
+    }
+}
+
+
+/// A memory mapping, unmapped when the last clone is dropped.
+///
+/// Clones share the same mapping. Any handle can read it, through
+/// [`MmapFile::pages`], [`MmapFile::read`] or [`MmapFile::as_slice`], but writing
+/// through [`MmapFile::page_mut`] requires the handle to be the only one, so
+/// Rust code never observes a write through an aliased reference. Writes by
+/// other processes sharing the memory are outside of this guarantee, which
+/// is why the typed accessors use volatile reads and writes.
 #[derive(Clone)]
 pub struct MmapFile {
     inner: std::sync::Arc<MmapFileInner>,
@@ -131,7 +325,7 @@ impl MmapFile {
         opts: &MmapOptions<'a>,
         prot: ProtFlags,
         flags: MapFlags,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
+    ) -> Result<Self, Error> {
         debug!(
             "[{}] creating new file backed memory mapping with options: {:?}; flags: {:?}; permissions: {:?}",
             Self::TAG,
@@ -140,24 +334,131 @@ impl MmapFile {
             prot
         );
 
+        opts.check_page_size()?;
+        let mut opts = MmapOptions::owned(opts);
+        // hugetlbfs files can only be mapped in whole huge pages
+        if opts.page_size.is_some() {
+            opts.len = opts.aligned_len();
+        }
+
         let mut file = OpenOptions::new()
             .read(true)
             .append(true)
             .create(true)
             .open(opts.path.as_ref())?;
 
-        file.set_len(opts.len as u64)?;
+        file.set_len(opts.addr_offset as u64 + opts.len as u64)?;
         file.sync_all()?;
 
+        Self::map(
+            Backing::File(PathBuf::from(opts.path.as_ref())),
+            Some(file),
+            &opts,
+            prot,
+            flags,
+        )
+    }
+
+    /// Map private anonymous memory backed by huge pages from the hugetlb
+    /// pool, `len` is rounded up to `page_size`. Fails with
+    /// [`Error::HugePagesUnavailable`] when the pool cannot cover `len`.
+    pub fn anonymous_hugetlb(
+        len: usize,
+        page_size: PageSize,
+    ) -> Result<Self, Error> {
+        let size_flag = match page_size {
+            PageSize::Normal => return Self::anonymous_private(len),
+            PageSize::Huge => MapFlags::MAP_HUGE_2MB,
+            PageSize::Giga => MapFlags::MAP_HUGE_1GB,
+        };
+        let mut opts = MmapOptions::unbacked(len);
+        opts.page_size = Some(page_size);
+        opts.len = opts.aligned_len();
+
+        // no MAP_NORESERVE: an unbacked hugetlb mapping fails with SIGBUS on
+        // first touch, rather than ENOMEM from mmap
+        let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_HUGETLB | size_flag;
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        Self::map(Backing::AnonymousPrivate, None, &opts, prot, flags)
+    }
+
+    /// Map `len` bytes of private anonymous memory.
+    pub fn anonymous_private(len: usize) -> Result<Self, Error> {
+        let flags = MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE;
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        Self::map(
+            Backing::AnonymousPrivate,
+            None,
+            &MmapOptions::unbacked(len),
+            prot,
+            flags,
+        )
+    }
+
+    /// Map `len` bytes of anonymous memory that stays shared with child
+    /// processes after `fork(2)`.
+    pub fn anonymous_shared(len: usize) -> Result<Self, Error> {
+        let flags = MapFlags::MAP_SHARED | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE;
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        Self::map(Backing::AnonymousShared, None, &MmapOptions::unbacked(len), prot, flags)
+    }
+
+    /// Create a `memfd_create(2)` file of `len` bytes and map it shared. Use
+    /// [`MmapFile::raw_fd`] to hand an `inheritable` fd to a child process.
+    pub fn memfd(
+        name: &str,
+        len: usize,
+        inheritable: bool,
+    ) -> Result<Self, Error> {
+        let flags = if inheritable {
+            MemFdCreateFlag::empty()
+        } else {
+            MemFdCreateFlag::MFD_CLOEXEC
+        };
+        let c_name = CString::new(name).map_err(|err| {
+            Error::Parse {
+                value:    name.to_string(),
+                typename: std::any::type_name::<CString>(),
+                reason:   err.to_string(),
+            }
+        })?;
+        let fd = memfd_create(&c_name, flags)?;
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(len as u64)?;
+
+        let backing = Backing::Memfd {
+            name: name.to_string(),
+            inheritable,
+        };
+        let opts = MmapOptions {
+            path: Cow::Owned(PathBuf::from(format!("/proc/self/fd/{}", fd))),
+            ..MmapOptions::unbacked(len)
+        };
+        let flags = MapFlags::MAP_SHARED | MapFlags::MAP_NORESERVE;
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        Self::map(backing, Some(file), &opts, prot, flags)
+    }
+
+    fn map<'a>(
+        backing: Backing,
+        file: Option<File>,
+        opts: &MmapOptions<'a>,
+        prot: ProtFlags,
+        flags: MapFlags,
+    ) -> Result<Self, Error> {
+        let fd = file.as_ref().map(AsRawFd::as_raw_fd).unwrap_or(-1);
         let file_ptr: *mut std::ffi::c_void = unsafe {
-            mmap(
-                opts.base_addr,
-                opts.len,
-                prot,
-                flags,
-                file.as_raw_fd(),
-                opts.addr_offset,
-            )?
+            mmap(opts.base_addr, opts.len, prot, flags, fd, opts.addr_offset).map_err(|err| {
+                match err.as_errno() {
+                    Some(Errno::ENOMEM) if flags.contains(MapFlags::MAP_HUGETLB) => {
+                        Error::HugePagesUnavailable {
+                            page_size: opts.page_size.unwrap_or_default() as usize,
+                            len:       opts.len,
+                        }
+                    }
+                    _ => Error::from(err),
+                }
+            })?
         };
 
         if !(MapFlags::MAP_FIXED & flags).is_empty() {
@@ -168,13 +469,14 @@ impl MmapFile {
             );
         }
 
-        let buf = unsafe { ManuallyDrop::new(Pin::new(Vec::from_raw_parts(file_ptr as *mut u8, opts.len, opts.len))) };
+        let ptr = NonNull::new(file_ptr as *mut u8).expect("mmap returned a null mapping");
 
         Ok(Self {
             inner: std::sync::Arc::new(MmapFileInner {
-                path: PathBuf::from(opts.path.as_ref()),
+                backing,
                 file,
-                buf,
+                ptr,
+                len: opts.len,
                 opts: MmapOptions::owned(opts),
                 flags,
                 prot,
@@ -183,34 +485,478 @@ impl MmapFile {
         })
     }
 
-    pub fn fixed_with_options<'a>(opts: &MmapOptions<'a>) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn fixed_with_options<'a>(opts: &MmapOptions<'a>) -> Result<Self, Error> {
         let flags = MapFlags::MAP_SHARED | MapFlags::MAP_FIXED | MapFlags::MAP_NORESERVE;
         let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
         Self::new(opts, prot, flags)
     }
 
-    pub fn with_options<'a>(opts: &MmapOptions<'a>) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn with_options<'a>(opts: &MmapOptions<'a>) -> Result<Self, Error> {
         let flags = MapFlags::MAP_SHARED | MapFlags::MAP_NORESERVE;
         let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
         Self::new(opts, prot, flags)
     }
 
     pub fn len(&self) -> usize {
-        self.inner.buf.len()
+        self.inner.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.inner.buf.is_empty()
+        self.inner.len() == 0
+    }
+
+    /// The page size the mapping was created with, which [`MmapFile::pages`]
+    /// steps by.
+    pub fn page_size(&self) -> usize {
+        self.inner.opts.page_size.unwrap_or_default() as usize
+    }
+
+    pub fn page_count(&self) -> usize {
+        (self.len() + self.page_size() - 1) / self.page_size()
+    }
+
+    /// No other clone of this `MmapFile` exists, so the mapping may be
+    /// written through this handle.
+    pub fn is_unique(&mut self) -> bool {
+        std::sync::Arc::get_mut(&mut self.inner).is_some()
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.inner.prot.contains(ProtFlags::PROT_WRITE)
+    }
+
+    /// The mapping is private anonymous memory, which no other process and
+    /// no file write can change.
+    pub fn is_private_anonymous(&self) -> bool {
+        self.inner.flags.contains(MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS)
+    }
+
+    /// The whole mapping as a slice, `None` unless it is private anonymous
+    /// memory (see [`MmapFile::is_private_anonymous`]): the memory of a shared
+    /// or file mapping may change while the slice is borrowed.
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        if !self.is_private_anonymous() {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts(self.inner.ptr.as_ptr(), self.len()) })
+    }
+
+    /// Iterate over the pages of the mapping for reading.
+    pub fn pages(&self) -> Pages<'_> {
+        Pages { map: self, index: 0 }
+    }
+
+    pub fn page(
+        &self,
+        index: usize,
+    ) -> Option<Page<'_>> {
+        if index >= self.page_count() {
+            return None;
+        }
+        let offset = index * self.page_size();
+        Some(Page {
+            index,
+            ptr: unsafe { NonNull::new_unchecked(self.inner.ptr.as_ptr().add(offset)) },
+            len: self.page_size().min(self.len() - offset),
+            private: self.is_private_anonymous(),
+            _map: std::marker::PhantomData,
+        })
+    }
+
+    /// Write access to page `index`. Clones of an `MmapFile` share the
+    /// mapping, so this is `None` unless this handle is the only one (see
+    /// [`MmapFile::is_unique`]) and the mapping was created writable.
+    pub fn page_mut(
+        &mut self,
+        index: usize,
+    ) -> Option<PageMut<'_>> {
+        if !self.is_unique() || !self.is_writable() {
+            return None;
+        }
+        let page = self.page(index)?;
+        Some(PageMut {
+            index: page.index,
+            ptr:   page.ptr,
+            len:   page.len,
+            _map:  std::marker::PhantomData,
+        })
+    }
+
+    /// Volatile read of a `T` at byte `offset` into the mapping.
+    pub fn read<T: Plain>(
+        &self,
+        offset: usize,
+    ) -> T {
+        check_access::<T>(offset, self.len());
+        unsafe { std::ptr::read_volatile(self.inner.ptr.as_ptr().add(offset) as *const T) }
+    }
+
+    pub fn backing(&self) -> &Backing {
+        &self.inner.backing
+    }
+
+    /// The path of the backing file, `/proc/self/fd/<fd>` for a memfd and
+    /// `None` for anonymous mappings.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.inner.backing {
+            Backing::File(path) => Some(path),
+            Backing::Memfd { .. } => Some(&self.inner.opts.path),
+            _ => None,
+        }
+    }
+
+    /// The fd of a file or memfd backed mapping.
+    pub fn raw_fd(&self) -> Option<RawFd> {
+        self.inner.file.as_ref().map(AsRawFd::as_raw_fd)
+    }
+
+    /// Which pages of the mapping are resident in memory, see `mincore(2)`.
+    /// Offsets are relative to the start of the backing file.
+    pub fn resident_pages(&self) -> Result<Residency, Error> {
+        let pages = crate::sys::mincore(&self.addr_range())?;
+        Ok(Residency::new(self.inner.opts.addr_offset as u64, pages))
+    }
+
+    /// Write back only the pages written since the last flush.
+    ///
+    /// The soft-dirty bits of the mapping are scanned, the dirty pages are
+    /// coalesced into ranges and each range is passed to `msync(2)`. The
+    /// soft-dirty bits are cleared between the scan and the `msync` calls, so
+    /// a page written after the clear is flushed next time. A page first
+    /// written between the scan and the clear is not: its bit is cleared
+    /// before it was seen, and only the kernel's own write back persists it
+    /// unless it is written again. Call it while no other thread writes the
+    /// mapping when every write must be flushed. Clearing is process wide:
+    /// other soft-dirty trackers in this process are reset too.
+    pub fn flush_dirty(
+        &self,
+        mode: FlushMode,
+    ) -> Result<FlushStats, Error> {
+        let mapping = self.addr_range();
+        let vm = ProcessVMA::this_process()?;
+
+        let mut stats = FlushStats::default();
+        let mut dirty = Vec::new();
+        self.scan_pages(&vm, |page| {
+            stats.pages_scanned += 1;
+            if page.pte.is_soft_dirty() {
+                stats.dirty_pages += 1;
+                dirty.push(page.addr_range);
+            }
+        })?;
+
+        vm.clear_refs()?;
+
+        for range in coalesce_ranges(dirty) {
+            let end = range.end().min(mapping.end());
+            debug!("[{}] msync {:#x}-{:#x} {:?}", Self::TAG, range.start(), end, mode);
+            unsafe {
+                msync(
+                    range.start() as *mut std::ffi::c_void,
+                    end - range.start(),
+                    mode.ms_flags(),
+                )?
+            };
+            stats.ranges += 1;
+            stats.bytes_flushed += end - range.start();
+        }
+
+        Ok(stats)
+    }
+
+    /// Visit every page of the mapping in `vm`, across all of the regions
+    /// it has been split into.
+    fn scan_pages<F>(
+        &self,
+        vm: &ProcessVMA,
+        mut visit: F,
+    ) -> Result<(), Error>
+    where
+        F: FnMut(&PageDescriptor<'_>),
+    {
+        let mapping = self.addr_range();
+        for region in vm.regions_overlapping(&mapping) {
+            for page in region.try_iter(self.inner.opts.page_size)? {
+                let page = page?;
+                if mapping.contains(page.addr_range.start()) {
+                    visit(&page);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The addresses of `pages`, which must be within the mapping.
+    fn pages_range(
+        &self,
+        pages: Range<usize>,
+    ) -> AddressRange {
+        assert!(
+            pages.start <= pages.end && pages.end <= self.page_count(),
+            "pages {:?} are out of bounds of {} pages",
+            pages,
+            self.page_count()
+        );
+        let start = self.inner.ptr.as_ptr() as usize;
+        let end = (start + pages.end * self.page_size()).min(start + self.len());
+        AddressRange::new(start + pages.start * self.page_size(), end)
+    }
+
+    /// Apply `advice` to `pages`. Discarding the pages of a private mapping
+    /// changes what it reads, so [`Advice::DontNeed`] on one requires a
+    /// unique handle like [`MmapFile::page_mut`].
+    pub fn advise(
+        &mut self,
+        pages: Range<usize>,
+        advice: Advice,
+    ) -> Result<(), Error> {
+        let is_private = self.inner.flags.contains(MapFlags::MAP_PRIVATE);
+        if advice == Advice::DontNeed && is_private && !self.is_unique() {
+            return Err(Error::MappingShared {
+                handles: std::sync::Arc::strong_count(&self.inner),
+            });
+        }
+        crate::sys::madvise(&self.pages_range(pages), advice.as_raw())
+    }
+
+    /// Lock `pages` in memory, faulting them in, see `mlock(2)`. Limited by
+    /// `RLIMIT_MEMLOCK` for unprivileged processes.
+    pub fn mlock(
+        &self,
+        pages: Range<usize>,
+    ) -> Result<(), Error> {
+        let range = self.pages_range(pages);
+        debug!("[{}] mlock {}", Self::TAG, range);
+        unsafe { mlock(range.start() as *const std::ffi::c_void, range.len())? };
+        Ok(())
+    }
+
+    pub fn munlock(
+        &self,
+        pages: Range<usize>,
+    ) -> Result<(), Error> {
+        let range = self.pages_range(pages);
+        debug!("[{}] munlock {}", Self::TAG, range);
+        unsafe { munlock(range.start() as *const std::ffi::c_void, range.len())? };
+        Ok(())
     }
 
-    pub fn path(&self) -> &Path {
-        &self.inner.path
+    /// Grow the mapping to `new_len` bytes, rounded up to the page size,
+    /// with `mremap(2)`. The backing file is extended first so the new
+    /// pages are backed. Unless the mapping is `MAP_FIXED` it may move,
+    /// which is why a unique handle is required.
+    ///
+    /// The kernel marks moved pages soft-dirty, so after a move the whole
+    /// mapping reads as dirty until the soft-dirty bits are next cleared and
+    /// a [`MmapFile::flush_dirty`] writes all of it back once.
+    pub fn grow(
+        &mut self,
+        new_len: usize,
+    ) -> Result<(), Error> {
+        let handles = std::sync::Arc::strong_count(&self.inner);
+        let range = self.addr_range();
+        let inner = std::sync::Arc::get_mut(&mut self.inner).ok_or(Error::MappingShared { handles })?;
+
+        let mut opts = MmapOptions::owned(&inner.opts);
+        opts.len = new_len;
+        let new_len = opts.aligned_len();
+        if new_len <= inner.len {
+            return Ok(());
+        }
+
+        if let Some(file) = inner.file.as_ref() {
+            let file_len = inner.opts.addr_offset as u64 + new_len as u64;
+            if file.metadata()?.len() < file_len {
+                file.set_len(file_len)?;
+            }
+        }
+
+        let may_move = !inner.flags.contains(MapFlags::MAP_FIXED);
+        let addr = crate::sys::mremap(&range, new_len, may_move)?;
+        debug!("[{}] remapped {} to {:#x} len={}", Self::TAG, range, addr, new_len);
+
+        inner.ptr = NonNull::new(addr as *mut u8).expect("mremap returned a null mapping");
+        inner.len = new_len;
+        inner.opts.len = new_len;
+        Ok(())
     }
 
+    /// The addresses of the mapping in this process.
+    pub fn addr_range(&self) -> AddressRange {
+        let start = self.inner.ptr.as_ptr() as usize;
+        AddressRange::new(start, start + self.len())
+    }
+
+    /// The start of the mapping. Dereferencing it is subject to the same
+    /// aliasing rules as [`MmapFile::page_mut`].
     #[inline(always)]
     pub fn as_nonnull(&self) -> NonNull<u8> {
-        let array = self.as_ref();
-        unsafe { NonNull::new_unchecked((&array[0]) as *const u8 as *mut u8) }
+        self.inner.ptr
+    }
+}
+
+
+/// Types for which every bit pattern is a valid value, so they can be read
+/// from arbitrary mapped memory.
+///
+/// # Safety
+/// Implementors must be `Copy`, contain no padding, pointers or references,
+/// and be valid for any bit pattern.
+pub unsafe trait Plain: Copy {}
+
+macro_rules! impl_plain {
+    ($($ty:ty),*) => {
+        $(
+            unsafe impl Plain for $ty {}
+        )*
+    };
+}
+
+impl_plain!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+
+fn check_access<T>(
+    offset: usize,
+    len: usize,
+) {
+    assert!(
+        offset
+            .checked_add(std::mem::size_of::<T>())
+            .map(|end| end <= len)
+            .unwrap_or(false),
+        "access of {} bytes at offset {} is out of bounds of {} bytes",
+        std::mem::size_of::<T>(),
+        offset,
+        len
+    );
+    assert_eq!(
+        offset % std::mem::align_of::<T>(),
+        0,
+        "offset {} is not aligned for {}",
+        offset,
+        std::any::type_name::<T>()
+    );
+}
+
+
+/// Read access to one page of a [`MmapFile`].
+#[derive(Copy, Clone, Debug)]
+pub struct Page<'a> {
+    index:   usize,
+    ptr:     NonNull<u8>,
+    len:     usize,
+    /// see [`MmapFile::is_private_anonymous`]
+    private: bool,
+    _map:    std::marker::PhantomData<&'a MmapFile>,
+}
+
+
+impl<'a> Page<'a> {
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn addr(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Volatile read of a `T` at byte `offset` into the page.
+    pub fn read<T: Plain>(
+        &self,
+        offset: usize,
+    ) -> T {
+        check_access::<T>(offset, self.len);
+        unsafe { std::ptr::read_volatile(self.ptr.as_ptr().add(offset) as *const T) }
+    }
+
+    /// The page as a slice, `None` unless the mapping is private anonymous
+    /// memory, see [`MmapFile::as_slice`].
+    pub fn as_slice(&self) -> Option<&'a [u8]> {
+        if !self.private {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) })
+    }
+}
+
+
+/// Write access to one page of a uniquely owned [`MmapFile`], see
+/// [`MmapFile::page_mut`].
+#[derive(Debug)]
+pub struct PageMut<'a> {
+    index: usize,
+    ptr:   NonNull<u8>,
+    len:   usize,
+    _map:  std::marker::PhantomData<&'a mut MmapFile>,
+}
+
+
+impl<'a> PageMut<'a> {
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn addr(&self) -> usize {
+        self.ptr.as_ptr() as usize
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Volatile read of a `T` at byte `offset` into the page.
+    pub fn read<T: Plain>(
+        &self,
+        offset: usize,
+    ) -> T {
+        check_access::<T>(offset, self.len);
+        unsafe { std::ptr::read_volatile(self.ptr.as_ptr().add(offset) as *const T) }
+    }
+
+    /// Volatile write of `value` at byte `offset` into the page. The write
+    /// is never elided, so it always dirties the page.
+    pub fn write<T: Plain>(
+        &mut self,
+        offset: usize,
+        value: T,
+    ) {
+        check_access::<T>(offset, self.len);
+        unsafe { std::ptr::write_volatile(self.ptr.as_ptr().add(offset) as *mut T, value) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+
+pub struct Pages<'a> {
+    map:   &'a MmapFile,
+    index: usize,
+}
+
+
+impl<'a> Iterator for Pages<'a> {
+    type Item = Page<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let page = self.map.page(self.index)?;
+        self.index += 1;
+        Some(page)
     }
 }
 
@@ -221,21 +967,264 @@ impl std::fmt::Debug for MmapFile {
         f: &mut std::fmt::Formatter,
     ) -> std::fmt::Result {
         f.debug_struct(Self::TAG.as_ref())
-            .field("path", &self.inner.path)
-            .field("ptr", &self.inner.buf.as_ptr())
+            .field("backing", &self.inner.backing)
+            .field("ptr", &self.inner.ptr)
             .field("len", &self.len())
             .finish()
     }
 }
 
-impl std::convert::AsRef<[u8]> for MmapFile {
-    fn as_ref(&self) -> &[u8] {
-        &self.inner.buf
+
+/// A shared mapping of `len` bytes of the temp file
+/// `beholder-<name>-test-<pid>.mmap` from `addr_offset`, removed on drop.
+#[cfg(test)]
+pub(crate) fn temp_file_mapping(
+    name: &str,
+    len: usize,
+    addr_offset: usize,
+) -> MmapFile {
+    let path = std::env::temp_dir().join(format!("beholder-{}-test-{}.mmap", name, std::process::id()));
+    let opts = MmapOptions {
+        path:           Cow::Owned(path),
+        base_addr:      std::ptr::null_mut(),
+        len,
+        addr_offset:    addr_offset as crate::deps::libc::off_t,
+        remove_on_drop: true,
+        page_size:      None,
+    };
+    MmapFile::with_options(&opts).unwrap()
+}
+
+#[test]
+fn test_every_backing_is_readable_and_writable() {
+    const LEN: usize = 4 << 10;
+
+    let mut maps = vec![
+        temp_file_mapping("mmapfile", LEN, 0),
+        MmapFile::anonymous_private(LEN).unwrap(),
+        MmapFile::anonymous_shared(LEN).unwrap(),
+        MmapFile::memfd("beholder-test", LEN, false).unwrap(),
+    ];
+
+    for map in maps.iter_mut() {
+        assert_eq!(map.len(), LEN);
+        assert_eq!(map.pages().count(), 1);
+        map.page_mut(0).unwrap().write::<u8>(LEN - 1, b'x');
+        assert_eq!(map.read::<u8>(LEN - 1), b'x', "{:?}", map);
+        assert_eq!(map.page(0).unwrap().read::<u8>(LEN - 1), b'x');
+    }
+
+    // only private anonymous memory cannot change under a borrowed slice
+    assert_eq!(maps.iter().map(|map| map.as_slice().is_some()).collect::<Vec<_>>(), vec![
+        false, true, false, false
+    ]);
+    assert_eq!(maps[1].as_slice().unwrap()[LEN - 1], b'x');
+    assert_eq!(maps[1].page(0).unwrap().as_slice().unwrap()[LEN - 1], b'x');
+    assert!(maps[3].page(0).unwrap().as_slice().is_none());
+
+    let path = maps[0].path().unwrap().to_path_buf();
+    assert!(path.ends_with(format!("beholder-mmapfile-test-{}.mmap", std::process::id())));
+    assert_eq!(maps[1].path(), None);
+    assert_eq!(maps[1].backing(), &Backing::AnonymousPrivate);
+    assert!(maps[1].raw_fd().is_none());
+    assert!(maps[3].raw_fd().is_some());
+    assert_eq!(maps[3].path(), Some(Path::new(&format!("/proc/self/fd/{}", maps[3].raw_fd().unwrap()))));
+    assert_eq!(std::fs::read(maps[3].path().unwrap()).unwrap()[LEN - 1], b'x');
+    drop(maps);
+    assert!(!path.exists());
+}
+
+
+#[test]
+fn test_huge_page_size_requires_hugetlbfs() {
+    let path = std::env::temp_dir().join(format!("beholder-hugetlbfs-test-{}.mmap", std::process::id()));
+    let opts = MmapOptions {
+        path:           Cow::Borrowed(path.as_path()),
+        base_addr:      std::ptr::null_mut(),
+        len:            1 << 20,
+        addr_offset:    0,
+        remove_on_drop: true,
+        page_size:      Some(PageSize::Huge),
+    };
+    assert_eq!(opts.aligned_len(), 2 << 20);
+
+    if statfs(path.parent().unwrap()).unwrap().filesystem_type() != HUGETLBFS_MAGIC {
+        match MmapFile::with_options(&opts) {
+            Err(Error::NotHugetlbfs { page_size, .. }) => assert_eq!(page_size, 2 << 20),
+            other => panic!("expected a NotHugetlbfs error, got {:?}", other),
+        }
+        assert!(!path.exists());
+    }
+}
+
+
+#[test]
+fn test_len_is_kept_without_huge_page_size() {
+    let map = temp_file_mapping("len", 4096 + 100, 0);
+    assert_eq!(map.len(), 4096 + 100);
+    assert_eq!(map.page_count(), 2);
+    assert_eq!(std::fs::metadata(map.path().unwrap()).unwrap().len(), 4096 + 100);
+}
+
+
+#[test]
+fn test_page_mut_requires_unique_handle() {
+    let mut map = MmapFile::anonymous_private(3 * 4096).unwrap();
+    let clone = map.clone();
+    assert!(map.page_mut(0).is_none());
+    drop(clone);
+
+    let mut page = map.page_mut(2).unwrap();
+    page.write::<u64>(8, 0xdead_beef);
+    assert_eq!(page.read::<u64>(8), 0xdead_beef);
+    assert!(map.page_mut(3).is_none());
+    assert_eq!(map.read::<u64>(2 * 4096 + 8), 0xdead_beef);
+    assert_eq!(
+        map.pages().map(|page| page.read::<u64>(8)).collect::<Vec<_>>(),
+        vec![0, 0, 0xdead_beef]
+    );
+}
+
+
+#[test]
+fn test_flush_dirty_accounts_flushed_bytes() {
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = temp_file_mapping("flush", 4 * 4096, 0);
+    map.page_mut(1).unwrap().write::<u8>(0, 1);
+
+    // the page cache state of page 1, `None` without /proc/kpageflags
+    let page_cache_dirty = |map: &MmapFile| {
+        let vm = ProcessVMA::this_process().unwrap();
+        let mut dirty = Vec::new();
+        map.scan_pages(&vm, |page| dirty.push(page.kpageflags.map(|flags| flags.dirty()))).unwrap();
+        dirty[1]
+    };
+    let readable = page_cache_dirty(&map).is_some();
+    if readable {
+        assert_eq!(page_cache_dirty(&map), Some(true));
+    }
+
+    let stats = map.flush_dirty(FlushMode::Sync).unwrap();
+    assert_eq!(stats.pages_scanned, 4);
+    if !soft_dirty.tracked() {
+        return;
+    }
+    assert_eq!(stats, FlushStats {
+        pages_scanned: 4,
+        dirty_pages:   1,
+        ranges:        1,
+        bytes_flushed: 4096,
+    });
+    if readable {
+        assert_eq!(page_cache_dirty(&map), Some(false));
+    }
+    assert_eq!(map.flush_dirty(FlushMode::Sync).unwrap().dirty_pages, 0);
+}
+
+
+#[cfg(test)]
+fn scan_ptes(map: &MmapFile) -> Vec<crate::pagemaps::PageTableEntry> {
+    let vm = ProcessVMA::this_process().unwrap();
+    let mut ptes = Vec::new();
+    map.scan_pages(&vm, |page| ptes.push(page.pte)).unwrap();
+    ptes
+}
+
+
+#[test]
+fn test_advise_dontneed_drops_pages() {
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = temp_file_mapping("advise", 4 * 4096, 0);
+    for index in 0..4 {
+        map.page_mut(index).unwrap().write::<u8>(0, b'x');
+    }
+    ProcessVMA::this_process().unwrap().clear_refs().unwrap();
+
+    map.advise(0..2, Advice::DontNeed).unwrap();
+    let ptes = scan_ptes(&map);
+    assert_eq!(ptes.iter().map(|pte| pte.is_present()).collect::<Vec<_>>(), vec![
+        false, false, true, true
+    ]);
+    assert!(ptes.iter().all(|pte| !pte.is_soft_dirty()));
+
+    // shared file pages are read back from the page cache
+    assert_eq!(map.read::<u8>(0), b'x');
+
+    // writes after the advice are tracked again
+    map.page_mut(1).unwrap().write::<u8>(0, b'y');
+    map.page_mut(3).unwrap().write::<u8>(0, b'y');
+    if soft_dirty.tracked() {
+        assert_eq!(scan_ptes(&map).iter().map(|pte| pte.is_soft_dirty()).collect::<Vec<_>>(), vec![
+            false, true, false, true
+        ]);
+    }
+}
+
+
+#[test]
+fn test_advise_dontneed_on_private_mapping_requires_unique_handle() {
+    let mut map = MmapFile::anonymous_private(2 * 4096).unwrap();
+    map.page_mut(1).unwrap().write::<u8>(0, b'x');
+    let clone = map.clone();
+    match map.advise(0..2, Advice::DontNeed) {
+        Err(Error::MappingShared { handles }) => assert_eq!(handles, 2),
+        other => panic!("expected a MappingShared error, got {:?}", other),
+    }
+    drop(clone);
+
+    map.advise(0..2, Advice::DontNeed).unwrap();
+    assert!(scan_ptes(&map).iter().all(|pte| !pte.is_present()));
+    assert_eq!(map.read::<u8>(4096), 0);
+}
+
+
+#[test]
+fn test_mlock_and_populate_write_fault_pages_in() {
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = temp_file_mapping("mlock", 4 * 4096, 0);
+    let present = |map: &MmapFile| scan_ptes(map).iter().map(|pte| pte.is_present()).collect::<Vec<_>>();
+    assert_eq!(present(&map), vec![false; 4]);
+
+    // locking part of the mapping splits its region
+    map.mlock(0..2).unwrap();
+    assert_eq!(present(&map), vec![true, true, false, false]);
+    map.munlock(0..2).unwrap();
+
+    ProcessVMA::this_process().unwrap().clear_refs().unwrap();
+    map.advise(2..4, Advice::PopulateWrite).unwrap();
+    assert_eq!(present(&map), vec![true; 4]);
+    if soft_dirty.tracked() {
+        assert_eq!(scan_ptes(&map).iter().map(|pte| pte.is_soft_dirty()).collect::<Vec<_>>(), vec![
+            false, false, true, true
+        ]);
     }
 }
 
-impl std::convert::AsMut<[u8]> for MmapFile {
-    fn as_mut(&mut self) -> &mut [u8] {
-        unsafe { std::slice::from_raw_parts_mut(self.inner.buf.as_ptr() as *mut u8, self.len()) }
+
+#[test]
+fn test_grow_extends_backing_file() {
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = temp_file_mapping("grow", 2 * 4096, 0);
+    map.page_mut(1).unwrap().write::<u64>(8, 0xdead_beef);
+
+    let clone = map.clone();
+    assert!(matches!(map.grow(4 * 4096), Err(Error::MappingShared { handles: 2 })));
+    drop(clone);
+
+    map.grow(4 * 4096 - 100).unwrap();
+    assert_eq!(map.len(), 4 * 4096);
+    assert_eq!(std::fs::metadata(map.path().unwrap()).unwrap().len(), 4 * 4096);
+    assert_eq!(map.read::<u64>(4096 + 8), 0xdead_beef);
+
+    // a moved mapping reads as dirty until the bits are cleared
+    ProcessVMA::this_process().unwrap().clear_refs().unwrap();
+    map.page_mut(3).unwrap().write::<u8>(0, b'x');
+    let ptes = scan_ptes(&map);
+    assert_eq!(ptes.len(), 4);
+    assert!(ptes[3].is_present());
+    if soft_dirty.tracked() {
+        assert_eq!(ptes.iter().map(|pte| pte.is_soft_dirty()).collect::<Vec<_>>(), vec![
+            false, false, false, true
+        ]);
     }
 }
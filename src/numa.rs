@@ -0,0 +1,265 @@
+//! NUMA placement of a process' memory.
+//!
+//! `/proc/pid/numa_maps` summarizes the memory policy and the number of pages
+//! on each node for every mapping, one line per mapping:
+//!
+//! ```text
+//! 7f3197600000 default anon=2048 dirty=2048 active=0 N0=2048 kernelpagesize_kB=4
+//! 55b2081f0000 bind:0-1 file=/usr/bin/python3 mapped=3 N0=2 N1=1 kernelpagesize_kB=4
+//! ```
+//!
+//! The node of an individual page comes from `move_pages(2)` called with a
+//! NULL `nodes` array, which only queries the placement.
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    io::BufRead,
+    ops::AddAssign,
+    path::Path,
+};
+
+use crate::{
+    deps::serde,
+    error::Error,
+};
+
+
+/// Pages per NUMA node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NodeCounts(BTreeMap<u32, u64>);
+
+
+impl NodeCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(
+        &mut self,
+        node: u32,
+        pages: u64,
+    ) {
+        *self.0.entry(node).or_default() += pages;
+    }
+
+    pub fn get(
+        &self,
+        node: u32,
+    ) -> u64 {
+        self.0.get(&node).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u64)> + '_ {
+        self.0.iter().map(|(node, pages)| (*node, *pages))
+    }
+}
+
+
+impl AddAssign<&NodeCounts> for NodeCounts {
+    fn add_assign(
+        &mut self,
+        other: &NodeCounts,
+    ) {
+        other.iter().for_each(|(node, pages)| self.add(node, pages));
+    }
+}
+
+
+impl fmt::Display for NodeCounts {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "-");
+        }
+        let counts = self
+            .iter()
+            .map(|(node, pages)| format!("N{}={}", node, pages))
+            .collect::<Vec<_>>();
+        write!(f, "{}", counts.join(","))
+    }
+}
+
+
+/// One line of `/proc/pid/numa_maps`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NumaRegion {
+    pub start:  usize,
+    /// memory policy, e.g. `default`, `prefer:1` or `interleave:0-3`
+    pub policy: String,
+    pub file:   Option<String>,
+    pub nodes:  NodeCounts,
+    /// the remaining `key=value` fields, e.g. `anon`, `dirty`, `mapped`,
+    /// `kernelpagesize_kB`
+    pub fields: BTreeMap<String, u64>,
+    /// bare words such as `heap`, `stack` or `huge`
+    pub tags:   Vec<String>,
+}
+
+
+impl NumaRegion {
+    pub fn field(
+        &self,
+        name: &str,
+    ) -> Option<u64> {
+        self.fields.get(name).copied()
+    }
+
+    pub fn kernel_page_size(&self) -> Option<u64> {
+        self.field("kernelpagesize_kB").map(|kb| kb << 10)
+    }
+}
+
+
+impl<'a> TryFrom<&'a str> for NumaRegion {
+    type Error = Error;
+
+    fn try_from(line: &'a str) -> Result<Self, Self::Error> {
+        let parse_error = |reason: &str| {
+            Error::Parse {
+                value:    line.to_string(),
+                typename: std::any::type_name::<NumaRegion>(),
+                reason:   reason.to_string(),
+            }
+        };
+
+        let mut tokens = line.split_whitespace();
+        let start = tokens
+            .next()
+            .and_then(|addr| usize::from_str_radix(addr, 16).ok())
+            .ok_or_else(|| parse_error("expected a hex start address"))?;
+        let policy = tokens
+            .next()
+            .ok_or_else(|| parse_error("expected a memory policy"))?
+            .to_string();
+
+        let mut region = NumaRegion {
+            start,
+            policy,
+            file: None,
+            nodes: NodeCounts::new(),
+            fields: BTreeMap::new(),
+            tags: Vec::new(),
+        };
+
+        for token in tokens {
+            match token.split_once('=') {
+                Some(("file", path)) => region.file = Some(path.to_string()),
+                Some((key, value)) => {
+                    let value = value
+                        .parse::<u64>()
+                        .map_err(|err| parse_error(&format!("field {}: {}", key, err)))?;
+                    match key.strip_prefix('N').map(str::parse::<u32>) {
+                        Some(Ok(node)) => region.nodes.add(node, value),
+                        _ => {
+                            region.fields.insert(key.to_string(), value);
+                        }
+                    }
+                }
+                None => region.tags.push(token.to_string()),
+            }
+        }
+
+        Ok(region)
+    }
+}
+
+
+/// The parsed `/proc/pid/numa_maps`, indexed by start address like
+/// [`crate::maps::Maps`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct NumaMaps {
+    map: BTreeMap<usize, NumaRegion>,
+}
+
+
+impl NumaMaps {
+    pub fn iter(&self) -> std::collections::btree_map::Iter<'_, usize, NumaRegion> {
+        self.map.iter()
+    }
+
+    /// The entry for the mapping starting at `start`.
+    pub fn region(
+        &self,
+        start: usize,
+    ) -> Option<&NumaRegion> {
+        self.map.get(&start)
+    }
+
+    /// Pages per node summed over every mapping.
+    pub fn nodes(&self) -> NodeCounts {
+        let mut total = NodeCounts::new();
+        self.map.values().for_each(|region| total += &region.nodes);
+        total
+    }
+}
+
+
+impl<'a> TryFrom<&'a mut dyn BufRead> for NumaMaps {
+    type Error = Error;
+
+    fn try_from(reader: &'a mut dyn BufRead) -> Result<Self, Self::Error> {
+        let mut numa_maps = NumaMaps::default();
+        for line in reader.lines() {
+            let region = NumaRegion::try_from(line?.as_str())?;
+            numa_maps.map.insert(region.start, region);
+        }
+        Ok(numa_maps)
+    }
+}
+
+
+impl<'a> TryFrom<&'a Path> for NumaMaps {
+    type Error = Error;
+
+    fn try_from(path: &'a Path) -> Result<Self, Self::Error> {
+        let mut reader = crate::io::new_buffered_file_reader(path, None)?;
+        NumaMaps::try_from(&mut reader as &mut dyn BufRead)
+    }
+}
+
+
+/// The node each page of process `pid` currently resides on, `None` for
+/// pages that are not present.
+pub fn page_nodes(
+    pid: usize,
+    addrs: &[usize],
+) -> Result<Vec<Option<u32>>, Error> {
+    let status = crate::sys::move_pages_query(pid, addrs)?;
+    Ok(status.into_iter().map(|node| u32::try_from(node).ok()).collect())
+}
+
+
+#[test]
+fn test_parse_numa_maps_line() {
+    let line = "55b2081f0000 bind:0-1 file=/usr/bin/python3 mapped=3 N0=2 N1=1 kernelpagesize_kB=4";
+    let region = NumaRegion::try_from(line).unwrap();
+
+    assert_eq!(region.start, 0x55b2081f0000);
+    assert_eq!(region.policy, "bind:0-1");
+    assert_eq!(region.file.as_deref(), Some("/usr/bin/python3"));
+    assert_eq!(region.nodes.get(0), 2);
+    assert_eq!(region.nodes.get(1), 1);
+    assert_eq!(region.nodes.to_string(), "N0=2,N1=1");
+    assert_eq!(region.field("mapped"), Some(3));
+    assert_eq!(region.kernel_page_size(), Some(4096));
+
+    let heap = NumaRegion::try_from("55b20805f000 default heap anon=387 dirty=387 N0=387 kernelpagesize_kB=4").unwrap();
+    assert_eq!(heap.tags, vec!["heap".to_string()]);
+    assert!(NumaRegion::try_from("zzz default").is_err());
+}
+
+
+#[test]
+fn test_page_nodes_of_this_process() {
+    let touched = Box::new([1u8; 4096]);
+    let addr = touched.as_ptr() as usize;
+    let nodes = page_nodes(std::process::id() as usize, &[addr]).unwrap();
+    assert!(nodes[0].is_some());
+}
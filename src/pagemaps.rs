@@ -84,6 +84,7 @@ use std::{
     },
     mem,
     num::NonZeroU64,
+    ops::Range,
     path::PathBuf,
 };
 
@@ -94,16 +95,28 @@ use crate::{
         warn,
     },
     error::Error,
-    kpageflags::KPageFlags,
+    kpageflags::{
+        FlagExpr,
+        KPageFlags,
+    },
     maps::{
         column::{
             AddressRange,
+            Device,
+            Inode,
             PathName,
+            Perm,
             PermSet,
         },
         MappedRegion,
+        MappingKind,
         Maps,
     },
+    numa::NumaMaps,
+    remote::{
+        PageRead,
+        RemoteMemory,
+    },
 };
 use std::str::FromStr;
 
@@ -165,9 +178,14 @@ pub struct PageTableEntry(u64);
 
 
 impl PageTableEntry {
+    const EXCLUSIVE_BIT: u32 = 56;
+    const FILE_BIT: u32 = 61;
     const PFN_BITS: u32 = 55;
     const PRESENT_BIT: u32 = 63;
     const SOFT_DIRTY_BIT: u32 = 55;
+    const SWAPPED_BIT: u32 = 62;
+    const SWAP_TYPE_BITS: u32 = 5;
+    const PFN_MASK: u64 = (1 << PageTableEntry::PFN_BITS) - 1;
 
     pub const fn new(n: u64) -> Self {
         Self(n)
@@ -180,9 +198,27 @@ impl PageTableEntry {
     ///    4.2 the PFN field is zeroed if the user does not have CAP_SYS_ADMIN.
     ///    Reason: information about PFNs helps in exploiting Rowhammer vulnerability.
     /// ```
+    ///
+    /// Swapped entries reuse the PFN bits for the swap location and return
+    /// `None`, see [`PageTableEntry::swap_entry`].
     pub fn page_frame_number(&self) -> Option<std::num::NonZeroU64> {
-        const MASK: u64 = u64::max_value().wrapping_shr(u64::max_value().count_ones() - PageTableEntry::PFN_BITS);
-        std::num::NonZeroU64::new(self.0 & MASK)
+        if self.is_swapped() {
+            return None;
+        }
+        std::num::NonZeroU64::new(self.0 & PageTableEntry::PFN_MASK)
+    }
+
+    /// The swap type and offset of a swapped out page.
+    pub const fn swap_entry(&self) -> Option<SwapEntry> {
+        const TYPE_MASK: u64 = (1 << PageTableEntry::SWAP_TYPE_BITS) - 1;
+        if !self.is_swapped() {
+            return None;
+        }
+        let bits = self.0 & PageTableEntry::PFN_MASK;
+        Some(SwapEntry {
+            swap_type: (bits & TYPE_MASK) as u8,
+            offset:    bits >> PageTableEntry::SWAP_TYPE_BITS,
+        })
     }
 
     pub const fn is_soft_dirty(&self) -> bool {
@@ -194,6 +230,23 @@ impl PageTableEntry {
         const MASK: u64 = 1 << PageTableEntry::PRESENT_BIT;
         self.0 & MASK != 0
     }
+
+    /// page exclusively mapped (since 4.2)
+    pub const fn is_exclusive(&self) -> bool {
+        const MASK: u64 = 1 << PageTableEntry::EXCLUSIVE_BIT;
+        self.0 & MASK != 0
+    }
+
+    /// page is file-page or shared-anon (since 3.5)
+    pub const fn is_file_or_shared_anon(&self) -> bool {
+        const MASK: u64 = 1 << PageTableEntry::FILE_BIT;
+        self.0 & MASK != 0
+    }
+
+    pub const fn is_swapped(&self) -> bool {
+        const MASK: u64 = 1 << PageTableEntry::SWAPPED_BIT;
+        self.0 & MASK != 0
+    }
 }
 
 impl<'a> TryFrom<&'a mut dyn Read> for PageTableEntry {
@@ -214,10 +267,24 @@ impl fmt::Debug for PageTableEntry {
             .field("page_frame_number", &self.page_frame_number())
             .field("soft_dirty", &self.is_soft_dirty())
             .field("present", &self.is_present())
+            .field("swapped", &self.is_swapped())
+            .field("swap_entry", &self.swap_entry())
+            .field("exclusive", &self.is_exclusive())
+            .field("file_or_shared_anon", &self.is_file_or_shared_anon())
             .finish()
     }
 }
 
+/// Location of a swapped out page: bits 0-4 of the PTE hold the swap type,
+/// the index of the swap area (see [`crate::swaps::Swaps::device`]), and bits
+/// 5-54 the offset in pages into that area.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+pub struct SwapEntry {
+    pub swap_type: u8,
+    pub offset:    u64,
+}
+
+
 #[derive(Debug, Clone)]
 pub struct ProcessVMA {
     pid:  usize,
@@ -261,6 +328,37 @@ impl ProcessVMA {
         self.maps.region(addr).map(|region| VMARegion { pid: self.pid, region })
     }
 
+    /// The regions overlapping `range` in address order. A single mapping
+    /// is split into several regions by `mlock(2)`, `madvise(2)` or
+    /// `mprotect(2)` on part of it.
+    pub fn regions_overlapping<'a>(
+        &'a self,
+        range: &'a AddressRange,
+    ) -> impl Iterator<Item = VMARegion<'a>> + 'a {
+        self.maps
+            .primary_index()
+            .range(..range.end())
+            .map(|(_start, region)| region)
+            .filter(move |region| region.addr_range().end() > range.start())
+            .map(move |region| VMARegion { pid: self.pid, region })
+    }
+
+    /// Memory policy and pages per NUMA node of every region, read from
+    /// `/proc/pid/numa_maps`.
+    pub fn numa_maps(&self) -> Result<NumaMaps, Error> {
+        let path = crate::paths::proc_pid_numa_maps_path(Some(self.pid));
+        NumaMaps::try_from(path.as_path())
+    }
+
+    /// The NUMA node of the page at each address, `None` if the page is not
+    /// present. See [`crate::numa::page_nodes`].
+    pub fn page_nodes(
+        &self,
+        addrs: &[usize],
+    ) -> Result<Vec<Option<u32>>, Error> {
+        crate::numa::page_nodes(self.pid, addrs)
+    }
+
     pub fn reload(&mut self) -> Result<(), Error> {
         *self = Self::with_pid(self.pid)?;
         Ok(())
@@ -270,7 +368,20 @@ impl ProcessVMA {
     pub fn clear_refs(&self) -> Result<(), Error> {
         const CLEAR_CMD: &'static str = "4\n";
         debug!("clearing soft-dirty PTE for pid={}", self.pid);
+        self.write_clear_refs(CLEAR_CMD)
+    }
+
+    /// reset the referenced and accessed bits of every page of process with PID
+    pub fn clear_referenced(&self) -> Result<(), Error> {
+        const CLEAR_CMD: &'static str = "1\n";
+        debug!("clearing referenced bits for pid={}", self.pid);
+        self.write_clear_refs(CLEAR_CMD)
+    }
 
+    fn write_clear_refs(
+        &self,
+        cmd: &str,
+    ) -> Result<(), Error> {
         let path = crate::paths::proc_pid_clear_refs(Some(self.pid));
         debug!("opening file: {:?}", path);
         let mut file = std::fs::OpenOptions::new()
@@ -280,7 +391,7 @@ impl ProcessVMA {
             .append(false)
             .open(path)?;
 
-        file.write_all(CLEAR_CMD.as_bytes())?;
+        file.write_all(cmd.as_bytes())?;
 
         Ok(())
     }
@@ -290,7 +401,10 @@ impl ProcessVMA {
 #[derive(Copy, Clone, Debug, serde::Serialize)]
 pub struct PageDescriptor<'a> {
     pub addr_range: AddressRange,
-    pub offset:     usize,
+    /// offset of the page into the backing file, see
+    /// [`MappedRegion::file_offset`], `None` unless the region maps a file:
+    /// the offset column of anonymous regions is the virtual page number
+    pub offset:     Option<usize>,
     pub perms:      &'a PermSet,
     pub pathame:    &'a PathName,
     pub pte:        PageTableEntry,
@@ -299,6 +413,134 @@ pub struct PageDescriptor<'a> {
 }
 
 
+impl<'a> PageDescriptor<'a> {
+    /// Evaluate a page flag predicate against this page. Pages without
+    /// kpageflags (not present, or `/proc/kpageflags` is unreadable) are
+    /// evaluated as having no flags set, so they match expressions made of
+    /// negations only, e.g. `!LRU`.
+    pub fn matches(
+        &self,
+        expr: &FlagExpr,
+    ) -> bool {
+        expr.matches(&self.kpageflags.unwrap_or_else(KPageFlags::empty))
+    }
+}
+
+
+
+/// Merge the adjacent or overlapping ranges of a start ordered sequence, e.g.
+/// the address ranges of consecutive dirty pages.
+pub fn coalesce_ranges<I>(ranges: I) -> Vec<AddressRange>
+where
+    I: IntoIterator<Item = AddressRange>,
+{
+    let mut coalesced: Vec<AddressRange> = Vec::new();
+    for range in ranges {
+        match coalesced.last_mut() {
+            Some(last) if range.start() <= last.end() => {
+                *last = AddressRange::new(last.start(), last.end().max(range.end()))
+            }
+            _ => coalesced.push(range),
+        }
+    }
+    coalesced
+}
+
+
+
+/// Byte ranges of a file written through a shared mapping of it, see
+/// [`VMARegion::dirty_file_ranges`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DirtyFileRanges {
+    pub path:   PathBuf,
+    pub device: Device,
+    pub inode:  Inode,
+    /// sorted, non-adjacent byte ranges of the file
+    pub ranges: Vec<Range<u64>>,
+}
+
+
+impl DirtyFileRanges {
+    /// Total number of dirty bytes.
+    pub fn len(&self) -> u64 {
+        self.ranges.iter().map(|range| range.end - range.start).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+
+
+/// Serializes the tests that clear the process wide soft-dirty bits, or
+/// that expect the bits of their pages to stay set.
+#[cfg(test)]
+pub(crate) fn soft_dirty_test_guard() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+
+/// A test checking soft-dirty bits: holds the [`soft_dirty_test_guard`] and
+/// knows whether this kernel tracks the bits at all.
+#[cfg(test)]
+pub(crate) struct SoftDirtyTest {
+    _guard:    std::sync::MutexGuard<'static, ()>,
+    supported: bool,
+}
+
+
+#[cfg(test)]
+impl SoftDirtyTest {
+    pub(crate) fn lock() -> Self {
+        let guard = soft_dirty_test_guard();
+        SoftDirtyTest {
+            _guard:    guard,
+            supported: soft_dirty_supported(),
+        }
+    }
+
+    /// Whether the soft-dirty checks can run, noting the skip when they
+    /// cannot.
+    pub(crate) fn tracked(&self) -> bool {
+        if !self.supported {
+            eprintln!("skipping, soft-dirty bits are not tracked by this kernel");
+        }
+        self.supported
+    }
+}
+
+
+/// Whether this kernel tracks soft-dirty bits, i.e. a write after
+/// `clear_refs` sets the bit of the written page and only of that page.
+/// Clears the bits of the process, call it with the
+/// [`soft_dirty_test_guard`] held.
+#[cfg(test)]
+fn soft_dirty_supported() -> bool {
+    use crate::mmapfile::MmapFile;
+
+    let mut map = MmapFile::anonymous_private(2 * 4096).unwrap();
+    for index in 0..2 {
+        map.page_mut(index).unwrap().write::<u8>(0, 1);
+    }
+    let vm = ProcessVMA::this_process().unwrap();
+    if vm.clear_refs().is_err() {
+        return false;
+    }
+    map.page_mut(1).unwrap().write::<u8>(0, 2);
+
+    let vm = ProcessVMA::this_process().unwrap();
+    let region = vm.region(map.addr_range().start()).unwrap();
+    let dirty = region
+        .try_iter(Some(PageSize::Normal))
+        .unwrap()
+        .map(|page| page.unwrap().pte.is_soft_dirty())
+        .collect::<Vec<_>>();
+    dirty == vec![false, true]
+}
+
+
 
 macro_rules! warn_once {
         ($name:ident; $($arg:tt)+) => {{
@@ -326,6 +568,14 @@ impl<'a> VMARegion<'a> {
     pub const LEVEL_SIZE: usize = 512;
     pub const PAGESIZE: usize = PageSize::Normal as usize;
 
+    pub const fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub const fn region(&self) -> &'a MappedRegion {
+        self.region
+    }
+
     pub fn try_iter(
         &self,
         page_size_override: Option<PageSize>,
@@ -348,6 +598,61 @@ impl<'a> VMARegion<'a> {
         })
     }
 
+    /// The byte ranges of the backing file covered by the soft-dirty pages of
+    /// this region. `None` unless the region is a shared mapping of a file,
+    /// the writes to a private mapping never reach the file.
+    pub fn dirty_file_ranges(
+        &self,
+        page_size_override: Option<PageSize>,
+    ) -> Result<Option<DirtyFileRanges>, Error> {
+        let region = self.region;
+        if region.kind() != MappingKind::File || !region.perms().contains(Perm::Shared) {
+            return Ok(None);
+        }
+
+        let mut dirty = Vec::new();
+        for page in self.try_iter(page_size_override)? {
+            let page = page?;
+            if page.pte.is_soft_dirty() {
+                dirty.push(page.addr_range);
+            }
+        }
+
+        let end = region.addr_range().end();
+        let ranges = coalesce_ranges(dirty)
+            .into_iter()
+            .map(|range| region.file_offset(range.start()) as u64..region.file_offset(range.end().min(end)) as u64)
+            .collect();
+
+        Ok(Some(DirtyFileRanges {
+            path: PathBuf::from(region.pathname().as_str()),
+            device: *region.device(),
+            inode: region.inode(),
+            ranges,
+        }))
+    }
+
+    /// Read the contents of `ranges` of this region back to back into `buf`
+    /// with `remote`, a reader of the process of this region, see
+    /// [`RemoteMemory::read_ranges`]. Fails with [`Error::RegionNotFound`]
+    /// for a range that is not within the region.
+    pub fn read_pages(
+        &self,
+        remote: &mut RemoteMemory,
+        ranges: &[AddressRange],
+        buf: &mut [u8],
+    ) -> Result<PageRead, Error> {
+        debug_assert_eq!(remote.pid(), self.pid, "reading region of pid {} with a reader of another", self.pid);
+        let addr_range = self.region.addr_range();
+        if let Some(outside) = ranges
+            .iter()
+            .find(|range| range.start() < addr_range.start() || range.end() > addr_range.end())
+        {
+            return Err(Error::RegionNotFound { addr: outside.start() });
+        }
+        remote.read_ranges(ranges, buf)
+    }
+
     fn open_pagemaps(&self) -> Result<BufReader<File>, Error> {
         let path = crate::paths::proc_pid_pagemaps_path(Some(self.pid));
         let offset_bytes = (self.region.addr_range().start() / VMARegion::PAGESIZE) * mem::size_of::<PageTableEntry>();
@@ -457,8 +762,12 @@ impl<'a> Iter<'a> {
             }
         };
 
-        let kpageflags = self.kpageflags_for_pte(&pte)?;
-        let kpagecount = self.kpagecount_for_pte(&pte)?;
+        // the PFN bits of a swap entry are not a PFN
+        let (kpageflags, kpagecount) = if pte.is_swapped() {
+            (None, None)
+        } else {
+            (self.kpageflags_for_pte(&pte)?, self.kpagecount_for_pte(&pte)?)
+        };
         let is_hugepage = kpageflags.as_ref().map(KPageFlags::huge).unwrap_or(false);
 
         let page_size = if let Some(size) = self.page_size_override {
@@ -481,7 +790,7 @@ impl<'a> Iter<'a> {
 
         Ok(Some(PageDescriptor {
             addr_range: AddressRange::new(low, self.current_addr),
-            offset: 0,
+            offset: (self.region.kind() == MappingKind::File).then(|| self.region.file_offset(low)),
             perms: self.region.perms(),
             pathame: self.region.pathname(),
             pte,
@@ -500,3 +809,106 @@ impl<'a> Iterator for Iter<'a> {
     }
 }
 
+
+#[test]
+fn test_swap_entry_decoding() {
+    let swapped = PageTableEntry::new(1 << 62 | 0x1234 << 5 | 3);
+    assert_eq!(swapped.page_frame_number(), None);
+    assert_eq!(swapped.swap_entry(), Some(SwapEntry { swap_type: 3, offset: 0x1234 }));
+
+    let present = PageTableEntry::new(1 << 63 | 0x1234);
+    assert_eq!(present.page_frame_number().map(NonZeroU64::get), Some(0x1234));
+    assert_eq!(present.swap_entry(), None);
+}
+
+
+#[test]
+fn test_matches_without_kpageflags() {
+    use crate::maps::MappedRegion;
+    use std::convert::TryFrom;
+
+    let region = MappedRegion::try_from("7fa28b3c0000-7fa28b3c1000 rw-p 00000000 00:00 0").unwrap();
+    let mut page = PageDescriptor {
+        addr_range: AddressRange::new(0x7fa28b3c0000, 0x7fa28b3c1000),
+        offset:     None,
+        perms:      region.perms(),
+        pathame:    region.pathname(),
+        pte:        PageTableEntry::new(0),
+        kpageflags: None,
+        kpagecount: None,
+    };
+
+    let expr = |value: &str| FlagExpr::from_str(value).unwrap();
+    assert!(page.matches(&expr("")));
+    assert!(page.matches(&expr("!LRU")));
+    assert!(page.matches(&expr("ANON|!THP")));
+    assert!(!page.matches(&expr("ANON")));
+    assert!(!page.matches(&expr("ANON,!LRU")));
+
+    page.kpageflags = Some(KPageFlags::ANON | KPageFlags::LRU);
+    assert!(!page.matches(&expr("!LRU")));
+    assert!(page.matches(&expr("ANON")));
+}
+
+
+#[test]
+fn test_coalesce_ranges() {
+    let ranges = vec![
+        AddressRange::new(0x1000, 0x2000),
+        AddressRange::new(0x2000, 0x3000),
+        AddressRange::new(0x5000, 0x6000),
+        AddressRange::new(0x6000, 0x8000),
+        AddressRange::new(0x9000, 0xa000),
+    ];
+    assert_eq!(coalesce_ranges(ranges), vec![
+        AddressRange::new(0x1000, 0x3000),
+        AddressRange::new(0x5000, 0x8000),
+        AddressRange::new(0x9000, 0xa000),
+    ]);
+
+    // overlapping ranges merge too, a contained range leaves the end alone
+    let ranges = vec![
+        AddressRange::new(0x1000, 0x3000),
+        AddressRange::new(0x1800, 0x2000),
+        AddressRange::new(0x2800, 0x4000),
+        AddressRange::new(0x6000, 0x7000),
+    ];
+    assert_eq!(coalesce_ranges(ranges), vec![
+        AddressRange::new(0x1000, 0x4000),
+        AddressRange::new(0x6000, 0x7000),
+    ]);
+}
+
+
+#[test]
+fn test_dirty_file_ranges_are_file_offsets() {
+    use crate::mmapfile::{
+        temp_file_mapping,
+        MmapFile,
+    };
+
+    let soft_dirty = SoftDirtyTest::lock();
+    let mut map = temp_file_mapping("dirty-ranges", 4 * 4096, 2 * 4096);
+    let start = map.addr_range().start();
+
+    let vm = ProcessVMA::this_process().unwrap();
+    vm.clear_refs().unwrap();
+    map.page_mut(1).unwrap().write::<u8>(0, 1);
+    map.page_mut(2).unwrap().write::<u8>(0, 1);
+
+    let region = vm.region(start).unwrap();
+    let page = region.try_iter(None).unwrap().next().unwrap().unwrap();
+    assert_eq!(page.offset, Some(2 * 4096));
+
+    let dirty = region.dirty_file_ranges(None).unwrap().unwrap();
+    assert_eq!(Some(dirty.path.as_path()), map.path());
+    if soft_dirty.tracked() {
+        assert_eq!(dirty.ranges, vec![3 * 4096..5 * 4096]);
+    }
+
+    // anonymous regions have no file offset
+    let anonymous = MmapFile::anonymous_private(4096).unwrap();
+    let vm = ProcessVMA::this_process().unwrap();
+    let region = vm.region(anonymous.addr_range().start()).unwrap();
+    assert!(region.try_iter(None).unwrap().all(|page| page.unwrap().offset.is_none()));
+}
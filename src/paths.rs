@@ -42,3 +42,43 @@ pub fn proc_kpageflags_path() -> &'static Path {
 pub fn proc_kpagecount_path() -> &'static Path {
     Path::new("/proc/kpagecount")
 }
+
+
+pub fn proc_pid_numa_maps_path(pid: Option<usize>) -> PathBuf {
+    Path::new("/").join("proc").join(pid_to_path(pid)).join("numa_maps")
+}
+
+
+pub fn proc_pid_smaps_path(pid: Option<usize>) -> PathBuf {
+    Path::new("/").join("proc").join(pid_to_path(pid)).join("smaps")
+}
+
+
+pub fn proc_swaps_path() -> &'static Path {
+    Path::new("/proc/swaps")
+}
+
+
+pub fn proc_pid_mem_path(pid: Option<usize>) -> PathBuf {
+    Path::new("/").join("proc").join(pid_to_path(pid)).join("mem")
+}
+
+
+pub fn proc_pid_task_path(pid: Option<usize>) -> PathBuf {
+    Path::new("/").join("proc").join(pid_to_path(pid)).join("task")
+}
+
+
+pub fn proc_pid_cgroup_path(pid: Option<usize>) -> PathBuf {
+    Path::new("/").join("proc").join(pid_to_path(pid)).join("cgroup")
+}
+
+
+pub fn proc_mounts_path() -> &'static Path {
+    Path::new("/proc/mounts")
+}
+
+
+pub fn sys_page_idle_bitmap_path() -> &'static Path {
+    Path::new("/sys/kernel/mm/page_idle/bitmap")
+}
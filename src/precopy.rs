@@ -0,0 +1,286 @@
+//! Iterative pre-copy migration modelled on the real dirty rate of a process.
+//!
+//! Live migration copies the memory of a running process in rounds: first
+//! all of it, then the pages dirtied while the previous round was being
+//! transferred, until the remainder is small enough to copy with the process
+//! stopped. [`run`] performs the `clear_refs` and soft-dirty scan of each
+//! round against the real process and waits for as long as the round would
+//! take to transfer at the configured bandwidth, so that the process dirties
+//! pages at its real rate in between. Nothing is actually transferred.
+//!
+//! The pages dirtied while a round is scanned count towards the next round,
+//! as they would in a real migration: the soft-dirty bits are cleared before
+//! the first scan and right after each later one.
+use std::time::{
+    Duration,
+    Instant,
+};
+
+use crate::{
+    deps::{
+        log::debug,
+        serde,
+    },
+    error::Error,
+    pagemaps::{
+        ProcessVMA,
+        VMARegion,
+    },
+    quiesce::{
+        Quiesce,
+        QuiesceMethod,
+    },
+};
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PrecopyConfig {
+    /// bytes per second
+    pub bandwidth:       u64,
+    /// rounds to run at most, the last one being the stop-and-copy round
+    pub max_rounds:      usize,
+    pub downtime_target: Duration,
+}
+
+
+impl PrecopyConfig {
+    pub fn transfer_time(
+        &self,
+        bytes: u64,
+    ) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / self.bandwidth as f64)
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PrecopyRound {
+    pub round:         usize,
+    /// pages to transfer: every page in round 0, the dirty ones afterwards
+    pub pages:         usize,
+    pub bytes:         u64,
+    pub transfer_time: Duration,
+    /// time spent scanning the page tables
+    pub scan_time:     Duration,
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PrecopyReport {
+    pub config:    PrecopyConfig,
+    /// the rounds copied while the process kept running
+    pub rounds:    Vec<PrecopyRound>,
+    /// the final round, copied with the process stopped
+    pub stop_copy: PrecopyRound,
+    /// the stop-and-copy round met the downtime target
+    pub converged: bool,
+}
+
+
+impl PrecopyReport {
+    /// Bytes transferred while the process kept running.
+    pub fn precopy_bytes(&self) -> u64 {
+        self.rounds.iter().map(|round| round.bytes).sum()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.precopy_bytes() + self.stop_copy.bytes
+    }
+
+    /// The estimated time the process is stopped for.
+    pub fn downtime(&self) -> Duration {
+        self.stop_copy.transfer_time
+    }
+}
+
+
+/// The round by round decisions of a pre-copy migration.
+#[derive(Clone, Debug)]
+pub struct Precopy {
+    config:    PrecopyConfig,
+    rounds:    Vec<PrecopyRound>,
+    stop_copy: Option<PrecopyRound>,
+}
+
+
+impl Precopy {
+    pub fn new(config: PrecopyConfig) -> Self {
+        Self {
+            config,
+            rounds: Vec::new(),
+            stop_copy: None,
+        }
+    }
+
+    /// Record the pages found for the next round. Returns the time to
+    /// transfer them while the process keeps running, or `None` when they
+    /// make the stop-and-copy round: their transfer time meets the downtime
+    /// target or the rounds are used up.
+    pub fn push_round(
+        &mut self,
+        pages: usize,
+        bytes: u64,
+        scan_time: Duration,
+    ) -> Option<Duration> {
+        assert!(self.stop_copy.is_none(), "the migration already stopped");
+
+        let round = PrecopyRound {
+            round: self.rounds.len(),
+            pages,
+            bytes,
+            transfer_time: self.config.transfer_time(bytes),
+            scan_time,
+        };
+        if round.transfer_time <= self.config.downtime_target || round.round + 1 >= self.config.max_rounds {
+            self.stop_copy = Some(round);
+            None
+        } else {
+            self.rounds.push(round);
+            Some(round.transfer_time)
+        }
+    }
+
+    /// The report once [`Precopy::push_round`] returned `None`.
+    pub fn report(&self) -> Option<PrecopyReport> {
+        let stop_copy = self.stop_copy?;
+        Some(PrecopyReport {
+            config: self.config,
+            rounds: self.rounds.clone(),
+            stop_copy,
+            converged: stop_copy.transfer_time <= self.config.downtime_target,
+        })
+    }
+}
+
+
+/// Count the pages of process `pid` with contents, only the soft-dirty ones
+/// when `dirty_only`.
+fn scan(
+    pid: usize,
+    dirty_only: bool,
+) -> Result<usize, Error> {
+    let vm = ProcessVMA::with_pid(pid)?;
+    let mut pages = 0;
+    for (addr, _region) in vm.maps().iter() {
+        let region = vm.region(*addr).ok_or(Error::RegionNotFound { addr: *addr })?;
+        for page in region.try_iter(None)? {
+            let page = page?;
+            let has_contents = page.pte.is_present() || page.pte.is_swapped();
+            if has_contents && (!dirty_only || page.pte.is_soft_dirty()) {
+                pages += page.addr_range.len() / VMARegion::PAGESIZE;
+            }
+        }
+    }
+    Ok(pages)
+}
+
+
+/// Run a pre-copy migration of process `pid` against its real dirty pages.
+///
+/// Each round clears the soft-dirty bits of the process, the rounds reset
+/// any other soft-dirty tracking of it. With `quiesce` the process is
+/// stopped while the soft-dirty bits of a round are scanned and cleared.
+/// Without it a page first written between the scan and the clear is missed
+/// until it is written again.
+pub fn run(
+    pid: usize,
+    config: &PrecopyConfig,
+    quiesce: Option<QuiesceMethod>,
+) -> Result<PrecopyReport, Error> {
+    let mut precopy = Precopy::new(*config);
+    let mut dirty_only = false;
+
+    // round 0 copies every page, the pages written from its scan on are
+    // dirty for round 1
+    ProcessVMA::with_pid(pid)?.clear_refs()?;
+    loop {
+        let start = Instant::now();
+        let pages = if dirty_only {
+            let _quiesce = quiesce.map(|method| Quiesce::new(pid, method)).transpose()?;
+            let pages = scan(pid, true)?;
+            ProcessVMA::with_pid(pid)?.clear_refs()?;
+            pages
+        } else {
+            scan(pid, false)?
+        };
+        let scan_time = start.elapsed();
+
+        let bytes = (pages * VMARegion::PAGESIZE) as u64;
+        match precopy.push_round(pages, bytes, scan_time) {
+            Some(transfer_time) => {
+                debug!("pre-copy round of pid {}: {} bytes in {:?}", pid, bytes, transfer_time);
+                std::thread::sleep(transfer_time);
+            }
+            None => break,
+        }
+        dirty_only = true;
+    }
+    Ok(precopy.report().expect("the last round stopped the migration"))
+}
+
+
+#[test]
+fn test_precopy_stops_at_downtime_target() {
+    const MB: u64 = 1 << 20;
+    let config = PrecopyConfig {
+        bandwidth:       100 * MB,
+        max_rounds:      4,
+        downtime_target: Duration::from_millis(50),
+    };
+
+    let mut precopy = Precopy::new(config);
+    assert_eq!(precopy.push_round(256, 100 * MB, Duration::default()), Some(Duration::from_secs(1)));
+    assert_eq!(precopy.push_round(256, 10 * MB, Duration::default()), Some(Duration::from_millis(100)));
+    assert_eq!(precopy.push_round(256, 4 * MB, Duration::default()), None);
+
+    let report = precopy.report().unwrap();
+    assert!(report.converged);
+    assert_eq!(report.rounds.len(), 2);
+    assert_eq!(report.precopy_bytes(), 110 * MB);
+    assert_eq!(report.total_bytes(), 114 * MB);
+    assert_eq!(report.downtime(), Duration::from_millis(40));
+
+    // a dirty rate above the bandwidth never converges
+    let mut precopy = Precopy::new(config);
+    let rounds = std::iter::repeat(100 * MB)
+        .take_while(|bytes| precopy.push_round(256, *bytes, Duration::default()).is_some())
+        .count();
+    assert_eq!(rounds, 3);
+    let report = precopy.report().unwrap();
+    assert!(!report.converged);
+    assert_eq!(report.downtime(), Duration::from_secs(1));
+}
+
+
+#[test]
+fn test_run_against_idle_child() {
+    use std::process::Command;
+
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let config = PrecopyConfig {
+        bandwidth:       1 << 30,
+        max_rounds:      3,
+        downtime_target: Duration::default(),
+    };
+    assert!(matches!(
+        run(std::process::id() as usize, &config, Some(QuiesceMethod::Signal)),
+        Err(Error::Quiesce { .. })
+    ));
+
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    let pid = child.id() as usize;
+    for quiesce in [None, Some(QuiesceMethod::Signal)].iter() {
+        let report = run(pid, &config, *quiesce).unwrap();
+        assert_eq!(report.stop_copy.round, report.rounds.len());
+        assert!(report.rounds[0].pages > 0);
+        assert_eq!(report.rounds[0].bytes, (report.rounds[0].pages * VMARegion::PAGESIZE) as u64);
+        if soft_dirty.tracked() {
+            // a sleeping process dirties nothing after the first round
+            assert_eq!(report.rounds.len(), 1);
+            assert_eq!(report.stop_copy.pages, 0);
+            assert!(report.converged);
+        }
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
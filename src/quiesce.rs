@@ -0,0 +1,474 @@
+//! Stopping a process for the duration of a scan.
+//!
+//! Scans of the page tables race with the writes of a running target, so a
+//! page may be written after it was found clean. A [`Quiesce`] guard stops
+//! every thread of the target until it is dropped, which also happens when
+//! the scan panics. Three strategies are supported:
+//!
+//! * [`QuiesceMethod::Ptrace`]: `PTRACE_SEIZE` and `PTRACE_INTERRUPT` each
+//!   thread, invisible to the target and its parent, but needs ptrace
+//!   permission and fails if a debugger is attached.
+//! * [`QuiesceMethod::Signal`]: `SIGSTOP` and `SIGCONT`, which the parent of
+//!   the target observes through `waitpid(2)`. A target that was stopped
+//!   before is left stopped by the guard.
+//! * [`QuiesceMethod::Freezer`]: the cgroup v2 `cgroup.freeze` file, which
+//!   freezes every process of the target's cgroup. It is refused when
+//!   beholder is in that cgroup, and a cgroup that was frozen before is left
+//!   frozen.
+//!
+//! If beholder itself is killed the kernel detaches a ptrace stopped target,
+//! which resumes it, but a stopped or frozen target stays stopped.
+use std::{
+    convert::TryFrom,
+    fmt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    str::FromStr,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use crate::{
+    deps::{
+        libc,
+        log::{
+            debug,
+            warn,
+        },
+        nix::{
+            errno::Errno,
+            sys::{
+                ptrace,
+                signal::{
+                    kill,
+                    Signal,
+                },
+                wait::{
+                    waitpid,
+                    WaitPidFlag,
+                },
+            },
+            unistd::Pid,
+        },
+        serde,
+    },
+    error::Error,
+};
+
+
+/// How long to wait for the target to stop.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum QuiesceMethod {
+    Ptrace,
+    Signal,
+    Freezer,
+}
+
+
+impl FromStr for QuiesceMethod {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ptrace" => Ok(QuiesceMethod::Ptrace),
+            "sigstop" | "signal" => Ok(QuiesceMethod::Signal),
+            "freezer" | "cgroup" => Ok(QuiesceMethod::Freezer),
+            _ => {
+                Err(Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<Self>(),
+                    reason:   "expected one of: ptrace, sigstop, freezer".to_string(),
+                })
+            }
+        }
+    }
+}
+
+
+impl fmt::Display for QuiesceMethod {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            QuiesceMethod::Ptrace => "ptrace",
+            QuiesceMethod::Signal => "sigstop",
+            QuiesceMethod::Freezer => "freezer",
+        }
+        .fmt(f)
+    }
+}
+
+
+#[derive(Debug)]
+enum Stopped {
+    /// the seized threads, detached on drop
+    Ptrace(Vec<Pid>),
+    Signal {
+        /// the target was stopped before, it is not continued
+        was_stopped: bool,
+    },
+    Freezer {
+        /// the cgroup.freeze file of the frozen cgroup
+        freeze:     PathBuf,
+        /// the cgroup was frozen before, it is not thawed
+        was_frozen: bool,
+    },
+}
+
+
+/// Keeps process `pid` stopped until dropped.
+///
+/// A ptrace guard must be dropped by the thread that created it, ptrace
+/// requests are only accepted from the tracing thread.
+#[derive(Debug)]
+pub struct Quiesce {
+    pid:     usize,
+    stopped: Stopped,
+}
+
+
+impl Quiesce {
+    pub fn new(
+        pid: usize,
+        method: QuiesceMethod,
+    ) -> Result<Self, Error> {
+        if pid == std::process::id() as usize {
+            return Err(Error::Quiesce {
+                pid,
+                reason: "a process cannot stop itself".to_string(),
+            });
+        }
+        debug!("quiescing pid {} with {}", pid, method);
+
+        // the guard is built first, so whatever was stopped before an error
+        // is resumed by its drop
+        let mut this = Self {
+            pid,
+            stopped: match method {
+                QuiesceMethod::Ptrace => Stopped::Ptrace(Vec::new()),
+                QuiesceMethod::Signal => {
+                    Stopped::Signal {
+                        was_stopped: thread_state(pid, pid) == Some('T'),
+                    }
+                }
+                QuiesceMethod::Freezer => {
+                    let freeze = cgroup_freeze_path(pid)?;
+                    let was_frozen = std::fs::read_to_string(&freeze)?.trim() == "1";
+                    Stopped::Freezer { freeze, was_frozen }
+                }
+            },
+        };
+
+        match &mut this.stopped {
+            Stopped::Ptrace(seized) => seize_threads(pid, seized)?,
+            Stopped::Signal { .. } => {
+                kill(Pid::from_raw(libc::pid_t::try_from(pid)?), Signal::SIGSTOP)?;
+                wait_until(pid, || Ok(threads(pid)?.iter().all(|tid| thread_state(pid, *tid) == Some('T'))))?;
+            }
+            Stopped::Freezer { freeze, .. } => {
+                std::fs::write(&freeze, "1")?;
+                let events = freeze.with_file_name("cgroup.events");
+                wait_until(pid, || {
+                    Ok(std::fs::read_to_string(&events)?.lines().any(|line| line == "frozen 1"))
+                })?;
+            }
+        }
+        Ok(this)
+    }
+
+    pub const fn pid(&self) -> usize {
+        self.pid
+    }
+
+    fn resume(&mut self) -> Result<(), Error> {
+        match &mut self.stopped {
+            Stopped::Ptrace(seized) => {
+                let mut result = Ok(());
+                for tid in seized.drain(..) {
+                    // a thread that exited in the meantime cannot be detached
+                    if let Err(err) = ptrace::detach(tid, None) {
+                        warn!("unable to detach tid {}: {}", tid, err);
+                        result = Err(err.into());
+                    }
+                }
+                result
+            }
+            Stopped::Signal { was_stopped: true } | Stopped::Freezer { was_frozen: true, .. } => Ok(()),
+            Stopped::Signal { was_stopped: false } => {
+                Ok(kill(Pid::from_raw(libc::pid_t::try_from(self.pid)?), Signal::SIGCONT)?)
+            }
+            Stopped::Freezer { freeze, was_frozen: false } => Ok(std::fs::write(freeze, "0")?),
+        }
+    }
+}
+
+
+impl Drop for Quiesce {
+    fn drop(&mut self) {
+        debug!("resuming pid {}", self.pid);
+        self.resume()
+            .unwrap_or_else(|err| warn!("unable to resume pid {}: {}", self.pid, err));
+    }
+}
+
+
+fn threads(pid: usize) -> Result<Vec<usize>, Error> {
+    let mut tids = Vec::new();
+    for entry in std::fs::read_dir(crate::paths::proc_pid_task_path(Some(pid)))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+
+/// The state field of `/proc/pid/task/tid/stat`, e.g. `T` when stopped.
+fn thread_state(
+    pid: usize,
+    tid: usize,
+) -> Option<char> {
+    let path = crate::paths::proc_pid_task_path(Some(pid)).join(tid.to_string()).join("stat");
+    let stat = std::fs::read_to_string(path).ok()?;
+    // the command name in parentheses may contain spaces
+    stat[stat.rfind(')')? + 1..].trim_start().chars().next()
+}
+
+
+/// Seize and interrupt every thread, until no new thread shows up: a thread
+/// can be created by one that was not stopped yet. Threads that exit in the
+/// meantime are skipped.
+fn seize_threads(
+    pid: usize,
+    seized: &mut Vec<Pid>,
+) -> Result<(), Error> {
+    let mut vanished = Vec::new();
+    loop {
+        let pending = threads(pid)?
+            .into_iter()
+            .map(|tid| libc::pid_t::try_from(tid).map(Pid::from_raw))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|tid| !seized.contains(tid) && !vanished.contains(tid))
+            .collect::<Vec<_>>();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        for tid in pending {
+            match ptrace::seize(tid, ptrace::Options::empty()) {
+                Ok(()) => {}
+                Err(err) if err.as_errno() == Some(Errno::ESRCH) => {
+                    debug!("tid {} exited before it was seized", tid);
+                    vanished.push(tid);
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            }
+            seized.push(tid);
+
+            let interrupted = crate::sys::ptrace_interrupt(tid.as_raw() as usize)
+                .and_then(|()| Ok(waitpid(tid, Some(WaitPidFlag::__WALL)).map(drop)?));
+            match interrupted {
+                Ok(()) => {}
+                Err(err) if is_esrch(&err) => {
+                    debug!("tid {} exited before it was interrupted", tid);
+                    seized.retain(|seized| *seized != tid);
+                    vanished.push(tid);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+
+fn is_esrch(err: &Error) -> bool {
+    match err {
+        Error::IO { source, .. } => source.raw_os_error() == Some(libc::ESRCH),
+        Error::Nix { source, .. } => source.as_errno() == Some(Errno::ESRCH),
+        _ => false,
+    }
+}
+
+
+fn wait_until<F>(
+    pid: usize,
+    mut is_stopped: F,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Result<bool, Error>,
+{
+    let start = Instant::now();
+    while !is_stopped()? {
+        if start.elapsed() > STOP_TIMEOUT {
+            return Err(Error::Quiesce {
+                pid,
+                reason: format!("not stopped after {:?}", STOP_TIMEOUT),
+            });
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+
+/// The cgroup v2 group of process `pid`, or of beholder without a pid,
+/// relative to the root of the hierarchy. Empty for the root cgroup.
+fn cgroup_group(pid: Option<usize>) -> Result<String, Error> {
+    // the cgroup v2 entry of /proc/pid/cgroup is "0::/path"
+    let cgroups = std::fs::read_to_string(crate::paths::proc_pid_cgroup_path(pid))?;
+    Ok(cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|group| group.trim_start_matches('/').to_string())
+        .unwrap_or_default())
+}
+
+
+/// The mount point of the cgroup v2 hierarchy.
+fn cgroup_mount(pid: usize) -> Result<PathBuf, Error> {
+    let mounts = std::fs::read_to_string(crate::paths::proc_mounts_path())?;
+    mounts
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[2] == "cgroup2")
+        .map(|fields| PathBuf::from(fields[1]))
+        .ok_or_else(|| {
+            Error::Quiesce {
+                pid,
+                reason: "no cgroup v2 hierarchy is mounted".to_string(),
+            }
+        })
+}
+
+
+/// The `cgroup.freeze` file of the cgroup v2 group of process `pid`.
+fn cgroup_freeze_path(pid: usize) -> Result<PathBuf, Error> {
+    let mount = cgroup_mount(pid)?;
+
+    let group = cgroup_group(Some(pid))?;
+    if group.is_empty() {
+        return Err(Error::Quiesce {
+            pid,
+            reason: "the process is in the root cgroup, which cannot be frozen".to_string(),
+        });
+    }
+
+    // freezing the cgroup beholder runs in would freeze beholder too
+    let own = cgroup_group(None)?;
+    if own == group || own.starts_with(&format!("{}/", group)) {
+        return Err(Error::Quiesce {
+            pid,
+            reason: format!("beholder runs in the cgroup /{} of the process", group),
+        });
+    }
+
+    let freeze = mount.join(&group).join("cgroup.freeze");
+    if !freeze.exists() {
+        return Err(Error::Quiesce {
+            pid,
+            reason: format!("{:?} does not exist, cgroup freezing needs Linux 5.2", freeze),
+        });
+    }
+    Ok(freeze)
+}
+
+
+#[test]
+fn test_quiesced_child_is_resumed() {
+    use std::process::Command;
+
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    let pid = child.id() as usize;
+    let running = |state: Option<char>| state == Some('S') || state == Some('R');
+
+    for method in [QuiesceMethod::Signal, QuiesceMethod::Ptrace].iter() {
+        {
+            let _guard = match Quiesce::new(pid, *method) {
+                Ok(guard) => guard,
+                // ptrace may be denied in restricted containers
+                Err(err) if *method == QuiesceMethod::Ptrace => {
+                    warn!("skipping ptrace: {}", err);
+                    continue;
+                }
+                Err(err) => panic!("{}", err),
+            };
+            assert!(matches!(thread_state(pid, pid), Some('T') | Some('t')), "{:?}", method);
+        }
+        wait_until(pid, || Ok(running(thread_state(pid, pid)))).unwrap();
+    }
+
+    // a target stopped before stays stopped
+    let child_pid = Pid::from_raw(pid as libc::pid_t);
+    kill(child_pid, Signal::SIGSTOP).unwrap();
+    wait_until(pid, || Ok(thread_state(pid, pid) == Some('T'))).unwrap();
+    drop(Quiesce::new(pid, QuiesceMethod::Signal).unwrap());
+    assert_eq!(thread_state(pid, pid), Some('T'));
+    kill(child_pid, Signal::SIGCONT).unwrap();
+    wait_until(pid, || Ok(running(thread_state(pid, pid)))).unwrap();
+
+    assert!(matches!(Quiesce::new(std::process::id() as usize, QuiesceMethod::Signal), Err(Error::Quiesce { .. })));
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+
+#[test]
+fn test_frozen_child_is_thawed() {
+    use std::process::Command;
+
+    let own = cgroup_group(None).unwrap();
+    if own.is_empty() {
+        eprintln!("skipping, beholder runs in the root cgroup");
+        return;
+    }
+
+    let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+    let pid = child.id() as usize;
+    // the child starts in the cgroup of beholder
+    assert!(matches!(Quiesce::new(pid, QuiesceMethod::Freezer), Err(Error::Quiesce { .. })));
+
+    // move the child into a cgroup of its own, which needs write access to
+    // the hierarchy
+    let group = cgroup_mount(pid)
+        .unwrap().join(&own).join(format!("beholder-test-{}", std::process::id()));
+    let moved = std::fs::create_dir(&group)
+        .and_then(|()| std::fs::write(group.join("cgroup.procs"), pid.to_string()));
+    if let Err(err) = moved {
+        eprintln!("skipping, unable to move the child into {:?}: {}", group, err);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let _ = std::fs::remove_dir(&group);
+        return;
+    }
+
+    let events = group.join("cgroup.events");
+    let is_frozen = || std::fs::read_to_string(&events).unwrap().lines().any(|line| line == "frozen 1");
+    {
+        let _guard = Quiesce::new(pid, QuiesceMethod::Freezer).unwrap();
+        assert!(is_frozen());
+    }
+    wait_until(pid, || Ok(!is_frozen())).unwrap();
+
+    // a cgroup frozen before stays frozen
+    std::fs::write(group.join("cgroup.freeze"), "1").unwrap();
+    wait_until(pid, || Ok(is_frozen())).unwrap();
+    drop(Quiesce::new(pid, QuiesceMethod::Freezer).unwrap());
+    assert!(is_frozen());
+    std::fs::write(group.join("cgroup.freeze"), "0").unwrap();
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir(&group).unwrap();
+}
@@ -0,0 +1,334 @@
+//! Proactive reclaim of cold memory of a process.
+//!
+//! [`find_cold`] samples a process with a [`HotnessTracker`] for a window
+//! and returns the ranges whose pages were neither accessed nor written in
+//! any round. Only [`WssMethod::Idle`] sees the accesses through the page
+//! tables, with [`WssMethod::Referenced`] pages the process keeps using may
+//! be found cold. [`reclaim`] passes ranges to `process_madvise(2)` through a
+//! pidfd (since 5.10) with `MADV_PAGEOUT` or `MADV_COLD`, and scans the page
+//! tables of the ranges before and after to show how many pages went to swap
+//! or were dropped. Without swap only clean file pages can be reclaimed.
+use std::{
+    fmt,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    deps::{
+        libc,
+        log::debug,
+        serde,
+    },
+    error::Error,
+    hotness::{
+        HotnessThresholds,
+        HotnessTracker,
+        MAX_ROUNDS,
+    },
+    maps::column::AddressRange,
+    pagemaps::{
+        PageSize,
+        ProcessVMA,
+    },
+    sys::PidFd,
+    wss::WssMethod,
+};
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ReclaimAdvice {
+    /// `MADV_PAGEOUT`, reclaim the pages now
+    PageOut,
+    /// `MADV_COLD`, deactivate the pages so they are reclaimed first
+    Cold,
+}
+
+
+impl ReclaimAdvice {
+    pub const fn as_raw(&self) -> libc::c_int {
+        match self {
+            ReclaimAdvice::PageOut => libc::MADV_PAGEOUT,
+            ReclaimAdvice::Cold => libc::MADV_COLD,
+        }
+    }
+}
+
+
+impl FromStr for ReclaimAdvice {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pageout" => Ok(ReclaimAdvice::PageOut),
+            "cold" => Ok(ReclaimAdvice::Cold),
+            _ => {
+                Err(Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<Self>(),
+                    reason:   "expected one of: pageout, cold".to_string(),
+                })
+            }
+        }
+    }
+}
+
+
+impl fmt::Display for ReclaimAdvice {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            ReclaimAdvice::PageOut => "pageout",
+            ReclaimAdvice::Cold => "cold",
+        }
+        .fmt(f)
+    }
+}
+
+
+/// Where the pages of a range are.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PageResidency {
+    pub present: u64,
+    pub swapped: u64,
+    /// neither present nor swapped, e.g. dropped file pages
+    pub absent:  u64,
+}
+
+
+impl PageResidency {
+    /// Scan the page table entries of `range` of process `vm`.
+    pub fn scan(
+        vm: &ProcessVMA,
+        range: &AddressRange,
+    ) -> Result<Self, Error> {
+        let mut residency = Self::default();
+        for region in vm.regions_overlapping(range) {
+            for page in region.try_iter(Some(PageSize::Normal))? {
+                let page = page?;
+                if !range.contains(page.addr_range.start()) {
+                    continue;
+                }
+                if page.pte.is_present() {
+                    residency.present += 1;
+                } else if page.pte.is_swapped() {
+                    residency.swapped += 1;
+                } else {
+                    residency.absent += 1;
+                }
+            }
+        }
+        Ok(residency)
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &PageResidency,
+    ) {
+        self.present += other.present;
+        self.swapped += other.swapped;
+        self.absent += other.absent;
+    }
+}
+
+
+/// Outcome of [`reclaim`] on one range.
+#[derive(Debug)]
+pub struct RangeReclaim {
+    pub range:   AddressRange,
+    pub before:  PageResidency,
+    /// `None` for a dry run
+    pub after:   Option<PageResidency>,
+    /// the bytes advised, `None` for a dry run
+    pub advised: Option<Result<usize, Error>>,
+}
+
+
+/// The ranges of the regions starting at `regions` of process `pid` whose
+/// pages were present but neither accessed nor written for `cold_for`,
+/// sampled every `interval`. Fails when the page flags or the page frame
+/// numbers are unavailable, rather than finding every page cold.
+pub fn find_cold(
+    pid: usize,
+    regions: &[usize],
+    method: WssMethod,
+    cold_for: Duration,
+    interval: Duration,
+) -> Result<Vec<AddressRange>, Error> {
+    let interval = interval.max(Duration::from_millis(1));
+    let rounds = ((cold_for.as_secs_f64() / interval.as_secs_f64()).ceil() as usize).max(1);
+    if rounds > MAX_ROUNDS {
+        return Err(Error::WorkingSet {
+            pid,
+            reason: format!("{} rounds of {:?} exceed the {} rounds sampled at most", rounds, interval, MAX_ROUNDS),
+        });
+    }
+    debug!("sampling pid {} for {} rounds of {:?}", pid, rounds, interval);
+
+    let mut tracker = HotnessTracker::new(pid, regions, method)?;
+    for _round in 0..rounds {
+        std::thread::sleep(interval);
+        tracker.sample()?;
+    }
+
+    // cold means untouched in every round
+    let thresholds = HotnessThresholds { hot: 1.0, warm: 0.0 };
+    Ok(tracker.classify(&thresholds).cold_ranges.into_iter().flat_map(|(_addr, ranges)| ranges).collect())
+}
+
+
+/// Apply `advice` to `ranges` of process `pid`, or only report where their
+/// pages are when `dry_run`.
+pub fn reclaim(
+    pid: usize,
+    ranges: &[AddressRange],
+    advice: ReclaimAdvice,
+    dry_run: bool,
+) -> Result<Vec<RangeReclaim>, Error> {
+    let vm = ProcessVMA::with_pid(pid)?;
+    let mut outcomes = ranges
+        .iter()
+        .map(|range| {
+            Ok(RangeReclaim {
+                range:   *range,
+                before:  PageResidency::scan(&vm, range)?,
+                after:   None,
+                advised: None,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    if dry_run {
+        return Ok(outcomes);
+    }
+
+    let pidfd = PidFd::open(pid)?;
+    for outcome in outcomes.iter_mut() {
+        outcome.advised = Some(crate::sys::process_madvise(&pidfd, &outcome.range, advice.as_raw()));
+    }
+
+    let vm = ProcessVMA::with_pid(pid)?;
+    for outcome in outcomes.iter_mut() {
+        outcome.after = Some(PageResidency::scan(&vm, &outcome.range)?);
+    }
+    Ok(outcomes)
+}
+
+
+#[test]
+fn test_reclaim_dry_run_and_cold() {
+    use crate::mmapfile::MmapFile;
+
+    const PAGESIZE: usize = PageSize::Normal as usize;
+    assert_eq!("pageout".parse::<ReclaimAdvice>().unwrap(), ReclaimAdvice::PageOut);
+    assert!("free".parse::<ReclaimAdvice>().is_err());
+
+    let mut map = MmapFile::anonymous_private(4 * PAGESIZE).unwrap();
+    for index in 0..2 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let range = map.addr_range();
+    let pid = std::process::id() as usize;
+
+    let outcomes = reclaim(pid, &[range], ReclaimAdvice::Cold, true).unwrap();
+    assert_eq!(outcomes[0].before, PageResidency {
+        present: 2,
+        swapped: 0,
+        absent:  2,
+    });
+    assert!(outcomes[0].after.is_none() && outcomes[0].advised.is_none());
+
+    // MADV_COLD only deactivates, the pages stay present
+    let outcomes = reclaim(pid, &[range], ReclaimAdvice::Cold, false).unwrap();
+    assert_eq!(*outcomes[0].advised.as_ref().unwrap().as_ref().unwrap(), range.len());
+    assert_eq!(outcomes[0].after, Some(outcomes[0].before));
+}
+
+
+#[test]
+fn test_find_cold_and_pageout() {
+    use crate::mmapfile::{
+        temp_file_mapping,
+        MmapFile,
+    };
+    use std::{
+        sync::{
+            atomic::{
+                AtomicBool,
+                Ordering,
+            },
+            Arc,
+        },
+    };
+
+    const PAGESIZE: usize = PageSize::Normal as usize;
+    let pid = std::process::id() as usize;
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+
+    let mut map = MmapFile::anonymous_private(8 * PAGESIZE).unwrap();
+    for index in 0..8 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+    let is_cold = |cold: &[AddressRange], index: usize| cold.iter().any(|range| range.contains(start + index * PAGESIZE));
+
+    // pages left alone come back cold
+    let cold = find_cold(pid, &[start], WssMethod::Referenced, Duration::from_millis(30), Duration::from_millis(10)).unwrap();
+    assert!((0..8).all(|index| is_cold(&cold, index)), "{:?}", cold);
+
+    // pages written all through the window do not
+    let idle_supported = crate::paths::sys_page_idle_bitmap_path().exists();
+    for method in [WssMethod::Referenced, WssMethod::Idle].iter() {
+        match method {
+            WssMethod::Idle if !idle_supported => {
+                eprintln!("skipping idle, /sys/kernel/mm/page_idle/bitmap is missing");
+                continue;
+            }
+            // referenced only sees the writes through the soft-dirty bits
+            WssMethod::Referenced if !soft_dirty.tracked() => {
+                continue;
+            }
+            _ => {}
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    for index in 0..4 {
+                        unsafe { std::ptr::write_volatile((start + index * PAGESIZE) as *mut u64, 1) };
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        let cold = find_cold(pid, &[start], *method, Duration::from_millis(50), Duration::from_millis(10));
+        stop.store(true, Ordering::Relaxed);
+        writer.join().unwrap();
+
+        let cold = cold.unwrap();
+        assert!((0..4).all(|index| !is_cold(&cold, index)), "{:?} {:?}", method, cold);
+        assert!((4..8).all(|index| is_cold(&cold, index)), "{:?} {:?}", method, cold);
+    }
+
+    // MADV_PAGEOUT drops clean file pages even without swap
+    let mut file_map = temp_file_mapping("reclaim", 4 * PAGESIZE, 0);
+    let path = file_map.path().unwrap().to_path_buf();
+    std::fs::write(&path, vec![7u8; 4 * PAGESIZE]).unwrap();
+    std::fs::File::open(&path).unwrap().sync_all().unwrap();
+    for index in 0..4 {
+        assert_eq!(file_map.page_mut(index).unwrap().read::<u8>(0), 7);
+    }
+    let range = file_map.addr_range();
+    let outcomes = reclaim(pid, &[range], ReclaimAdvice::PageOut, false).unwrap();
+    assert_eq!(outcomes[0].before.present, 4);
+    assert_eq!(*outcomes[0].advised.as_ref().unwrap().as_ref().unwrap(), range.len());
+    assert_eq!(outcomes[0].after, Some(PageResidency {
+        present: 0,
+        swapped: 0,
+        absent:  4,
+    }));
+    assert_eq!(file_map.page_mut(3).unwrap().read::<u8>(0), 7);
+}
@@ -0,0 +1,206 @@
+//! Reading the memory of another process.
+//!
+//! Reads use `process_vm_readv(2)`, falling back to `/proc/pid/mem` when the
+//! system call is unavailable or denied (e.g. by a seccomp filter). Both need
+//! ptrace read access to the target. The target keeps running, so a page may
+//! be unmapped between finding it and reading it: such a page is zero filled
+//! and reported as missing rather than failing the whole read.
+use std::{
+    fs::File,
+    os::unix::fs::FileExt,
+};
+
+use crate::{
+    deps::{
+        libc,
+        log::{
+            debug,
+            warn,
+        },
+        serde,
+    },
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        coalesce_ranges,
+        PageSize,
+    },
+};
+
+
+/// The outcome of [`RemoteMemory::read_ranges`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PageRead {
+    pub bytes_read: usize,
+    /// page aligned ranges that could not be read, zero filled in the buffer
+    pub missing:    Vec<AddressRange>,
+}
+
+
+#[derive(Debug)]
+enum Method {
+    VmReadv,
+    Mem(File),
+}
+
+
+/// Reads the memory of process `pid`.
+#[derive(Debug)]
+pub struct RemoteMemory {
+    pid:    usize,
+    method: Method,
+}
+
+
+impl RemoteMemory {
+    const PAGESIZE: usize = PageSize::Normal as usize;
+
+    pub fn new(pid: usize) -> Self {
+        Self {
+            pid,
+            method: Method::VmReadv,
+        }
+    }
+
+    pub const fn pid(&self) -> usize {
+        self.pid
+    }
+
+    /// Read `ranges` back to back into `buf`, which must be at least as long
+    /// as the ranges together.
+    pub fn read_ranges(
+        &mut self,
+        ranges: &[AddressRange],
+        buf: &mut [u8],
+    ) -> Result<PageRead, Error> {
+        let total = ranges.iter().map(AddressRange::len).sum::<usize>();
+        assert!(
+            buf.len() >= total,
+            "a buffer of {} bytes cannot hold {} bytes of ranges",
+            buf.len(),
+            total
+        );
+
+        let mut outcome = PageRead::default();
+        let mut offset = 0;
+        for range in ranges {
+            self.read_range(range, &mut buf[offset..offset + range.len()], &mut outcome)?;
+            offset += range.len();
+        }
+        outcome.missing = coalesce_ranges(std::mem::take(&mut outcome.missing));
+        Ok(outcome)
+    }
+
+    fn read_range(
+        &mut self,
+        range: &AddressRange,
+        buf: &mut [u8],
+        outcome: &mut PageRead,
+    ) -> Result<(), Error> {
+        let mut done = 0;
+        while done < buf.len() {
+            let addr = range.start() + done;
+            match self.read_at(addr, &mut buf[done..])? {
+                Some(n) => {
+                    done += n;
+                    outcome.bytes_read += n;
+                }
+                // the page at addr is gone, skip to the next one
+                None => {
+                    let end = ((addr / Self::PAGESIZE + 1) * Self::PAGESIZE).min(range.end());
+                    debug!("pid {} page {:#x}-{:#x} could not be read", self.pid, addr, end);
+                    buf[done..end - range.start()].iter_mut().for_each(|byte| *byte = 0);
+                    outcome.missing.push(AddressRange::new(addr, end));
+                    done = end - range.start();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read from `addr`, `None` when the page at `addr` cannot be read.
+    fn read_at(
+        &mut self,
+        addr: usize,
+        buf: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        let result = match &self.method {
+            Method::VmReadv => crate::sys::process_vm_readv(self.pid, addr, buf),
+            Method::Mem(file) => file.read_at(buf, addr as u64).map_err(Error::from),
+        };
+
+        match result {
+            Ok(0) => Ok(None),
+            Ok(n) => Ok(Some(n)),
+            // process_vm_readv fails with EFAULT and /proc/pid/mem with EIO
+            Err(err) if matches!(errno(&err), Some(libc::EFAULT) | Some(libc::EIO)) => Ok(None),
+            Err(err)
+                if matches!(self.method, Method::VmReadv)
+                    && matches!(errno(&err), Some(libc::ENOSYS) | Some(libc::EPERM)) =>
+            {
+                warn!(
+                    "process_vm_readv of pid {} failed, falling back to /proc/pid/mem: {}",
+                    self.pid, err
+                );
+                let path = crate::paths::proc_pid_mem_path(Some(self.pid));
+                self.method = Method::Mem(File::open(path)?);
+                self.read_at(addr, buf)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+
+fn errno(err: &Error) -> Option<i32> {
+    match err {
+        Error::IO { source, .. } => source.raw_os_error(),
+        _ => None,
+    }
+}
+
+
+#[test]
+fn test_unreadable_pages_are_missing() {
+    use crate::{
+        deps::nix::sys::mman::{
+            mprotect,
+            ProtFlags,
+        },
+        mmapfile::MmapFile,
+    };
+
+    const PAGESIZE: usize = RemoteMemory::PAGESIZE;
+    let mut map = MmapFile::anonymous_private(4 * PAGESIZE).unwrap();
+    for index in 0..4 {
+        map.page_mut(index).unwrap().write::<u64>(8, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+    unsafe { mprotect((start + PAGESIZE) as *mut _, PAGESIZE, ProtFlags::PROT_NONE).unwrap() };
+
+    let ranges = [
+        AddressRange::new(start, start + 3 * PAGESIZE),
+        AddressRange::new(start + 3 * PAGESIZE, start + 4 * PAGESIZE),
+    ];
+    let mut buf = vec![0xff; 4 * PAGESIZE];
+    let mut remote = RemoteMemory::new(std::process::id() as usize);
+    let outcome = remote.read_ranges(&ranges, &mut buf).unwrap();
+
+    assert_eq!(outcome.bytes_read, 3 * PAGESIZE);
+    assert_eq!(outcome.missing, vec![AddressRange::new(start + PAGESIZE, start + 2 * PAGESIZE)]);
+    let values = buf.chunks(PAGESIZE).map(|page| page[8]).collect::<Vec<_>>();
+    assert_eq!(values, vec![1, 0, 3, 4]);
+
+    unsafe { mprotect((start + PAGESIZE) as *mut _, PAGESIZE, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE).unwrap() };
+
+    // reads through a region are limited to the region
+    let vm = crate::pagemaps::ProcessVMA::this_process().unwrap();
+    let region = vm.region(start).unwrap();
+    let outcome = region.read_pages(&mut remote, &ranges[1..], &mut buf).unwrap();
+    assert_eq!(outcome.bytes_read, PAGESIZE);
+    let beyond = AddressRange::new(start + 3 * PAGESIZE, start + 5 * PAGESIZE);
+    match region.read_pages(&mut remote, &[beyond], &mut vec![0; 2 * PAGESIZE]) {
+        Err(Error::RegionNotFound { addr }) => assert_eq!(addr, beyond.start()),
+        other => panic!("expected a RegionNotFound error, got {:?}", other),
+    }
+}
@@ -0,0 +1,256 @@
+//! Incremental replication of a file written through shared mappings.
+//!
+//! The replica starts as a full copy of the file. Each round then collects
+//! the soft-dirty pages of every shared mapping of the file in the target
+//! process, clears the soft-dirty bits and copies the matching byte ranges
+//! of the file into the replica. A shared mapping writes into the page cache,
+//! so the current contents of a dirty page are read from the file itself.
+//!
+//! Only writes through a mapping are tracked: `write(2)` to the file, or
+//! writes by a process other than the target, are missed until the page is
+//! next dirtied through the mapping. The scan and the clear are not atomic,
+//! a page first written between them loses its bit unseen unless the target
+//! is stopped for the two, see [`Replicator::round`].
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    ops::Range,
+    os::unix::fs::FileExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    deps::{
+        log::{
+            debug,
+            info,
+        },
+        serde,
+    },
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        coalesce_ranges,
+        ProcessVMA,
+    },
+    quiesce::{
+        Quiesce,
+        QuiesceMethod,
+    },
+};
+
+
+/// What one round of [`Replicator::round`] copied.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RoundStats {
+    pub round:        usize,
+    /// shared mappings of the file in the target process
+    pub mappings:     usize,
+    /// coalesced byte ranges copied
+    pub ranges:       usize,
+    pub bytes_copied: u64,
+    /// length of the file when the round ran
+    pub file_len:     u64,
+}
+
+
+/// Replicates the file at `source` into `replica` from the soft-dirty pages
+/// of the mappings of `source` in process `pid`.
+///
+/// Clearing the soft-dirty bits is process wide, any other soft-dirty
+/// tracking of the target process is reset by each round.
+#[derive(Debug)]
+pub struct Replicator {
+    pid:     usize,
+    source:  PathBuf,
+    file:    File,
+    replica: File,
+    round:   usize,
+}
+
+
+impl Replicator {
+    /// Start tracking `source` in process `pid` and copy all of it into
+    /// `replica`, which is created or truncated.
+    pub fn new<P, Q>(
+        pid: usize,
+        source: P,
+        replica: Q,
+    ) -> Result<Self, Error>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        // the pathname column of the maps file holds the canonical path
+        let source = source.as_ref().canonicalize()?;
+        let file = File::open(&source)?;
+        let replica = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(replica.as_ref())?;
+
+        // cleared before the full copy, so writes racing with it are
+        // copied again by the first round
+        ProcessVMA::with_pid(pid)?.clear_refs()?;
+
+        let mut this = Self {
+            pid,
+            source,
+            file,
+            replica,
+            round: 0,
+        };
+        let len = this.file.metadata()?.len();
+        info!("replicating {:?} of pid {}, initial copy of {} bytes", this.source, pid, len);
+        this.copy(&[0..len], len)?;
+        Ok(this)
+    }
+
+    pub const fn pid(&self) -> usize {
+        self.pid
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// The byte ranges of the file written through any shared mapping of
+    /// it since the last round, coalesced across mappings.
+    fn collect_dirty(
+        &self,
+        vm: &ProcessVMA,
+    ) -> Result<(usize, Vec<Range<u64>>), Error> {
+        let addrs = vm
+            .maps()
+            .addrs_for_pathname(self.source.to_string_lossy().as_ref())
+            .unwrap_or(&[]);
+
+        let mut mappings = 0;
+        let mut ranges = Vec::new();
+        for addr_range in addrs {
+            let region = vm.region(addr_range.start()).ok_or(Error::RegionNotFound {
+                addr: addr_range.start(),
+            })?;
+            if let Some(dirty) = region.dirty_file_ranges(None)? {
+                mappings += 1;
+                ranges.extend(
+                    dirty
+                        .ranges
+                        .iter()
+                        .map(|range| AddressRange::new(range.start as usize, range.end as usize)),
+                );
+            }
+        }
+        ranges.sort();
+        let ranges = coalesce_ranges(ranges)
+            .into_iter()
+            .map(|range| range.start() as u64..range.end() as u64)
+            .collect();
+        Ok((mappings, ranges))
+    }
+
+    /// Copy the soft-dirty pages of the file into the replica.
+    ///
+    /// With `quiesce` the target is stopped while the soft-dirty bits are
+    /// scanned and cleared, and runs again during the copy. Without it a page
+    /// first written between the scan and the clear is not copied until it
+    /// is written again.
+    pub fn round(
+        &mut self,
+        quiesce: Option<QuiesceMethod>,
+    ) -> Result<RoundStats, Error> {
+        let vm = ProcessVMA::with_pid(self.pid)?;
+        let (mappings, ranges) = {
+            let _quiesce = quiesce.map(|method| Quiesce::new(self.pid, method)).transpose()?;
+            let dirty = self.collect_dirty(&vm)?;
+            vm.clear_refs()?;
+            dirty
+        };
+
+        // the contents are read after the clear, a page written during the
+        // copy is dirty again and copied next round
+
+        let file_len = self.file.metadata()?.len();
+        let bytes_copied = self.copy(&ranges, file_len)?;
+
+        self.round += 1;
+        let stats = RoundStats {
+            round: self.round,
+            mappings,
+            ranges: ranges.len(),
+            bytes_copied,
+            file_len,
+        };
+        debug!("replication round of {:?}: {:?}", self.source, stats);
+        Ok(stats)
+    }
+
+    /// Copy `ranges` of the file, clipped to `file_len`, at the same offsets
+    /// of the replica and resize the replica to `file_len`.
+    fn copy(
+        &mut self,
+        ranges: &[Range<u64>],
+        file_len: u64,
+    ) -> Result<u64, Error> {
+        const CHUNK_SIZE: u64 = 1 << 20;
+
+        self.replica.set_len(file_len)?;
+
+        let mut buf = Vec::new();
+        let mut copied = 0;
+        for range in ranges {
+            let mut offset = range.start;
+            let end = range.end.min(file_len);
+            while offset < end {
+                let len = (end - offset).min(CHUNK_SIZE) as usize;
+                buf.resize(len, 0);
+                self.file.read_exact_at(&mut buf, offset)?;
+                self.replica.write_all_at(&buf, offset)?;
+                offset += len as u64;
+                copied += len as u64;
+            }
+        }
+
+        self.replica.sync_data()?;
+        Ok(copied)
+    }
+}
+
+
+#[test]
+fn test_replica_follows_mapped_writes() {
+    use crate::mmapfile::temp_file_mapping;
+
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = temp_file_mapping("replicate-source", 4 * 4096, 0);
+    let source = map.path().unwrap().to_path_buf();
+    let replica = std::env::temp_dir().join(format!("beholder-replicate-replica-{}.mmap", std::process::id()));
+    map.page_mut(0).unwrap().write::<u8>(0, b'a');
+
+    let pid = std::process::id() as usize;
+    let mut replicator = Replicator::new(pid, &source, &replica).unwrap();
+    assert_eq!(std::fs::read(&replica).unwrap(), std::fs::read(&source).unwrap());
+
+    map.page_mut(2).unwrap().write::<u8>(1, b'b');
+    // a process cannot stop itself
+    assert!(matches!(replicator.round(Some(QuiesceMethod::Signal)), Err(Error::Quiesce { .. })));
+    let stats = replicator.round(None).unwrap();
+    assert_eq!(stats.round, 1);
+    assert_eq!(stats.mappings, 1);
+    assert_eq!(stats.file_len, 4 * 4096);
+
+    if soft_dirty.tracked() {
+        assert_eq!(stats.bytes_copied, 4096);
+        assert_eq!(std::fs::read(&replica).unwrap(), std::fs::read(&source).unwrap());
+        assert_eq!(replicator.round(None).unwrap().bytes_copied, 0);
+    }
+
+    drop(map);
+    std::fs::remove_file(&replica).unwrap();
+}
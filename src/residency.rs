@@ -0,0 +1,192 @@
+//! Page cache residency of files, from `mincore(2)`.
+//!
+//! `mincore` reports whether each page of a mapping is resident in memory,
+//! regardless of whether the page is mapped by any page table. For a file
+//! mapping this is the page cache state of the file, which makes it possible
+//! to inspect any file by mapping it read-only for the duration of the call,
+//! like `fincore(1)`.
+use std::{
+    fs::File,
+    ops::Range,
+    os::unix::io::AsRawFd,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    deps::{
+        log::debug,
+        nix::sys::mman::{
+            mmap,
+            munmap,
+            MapFlags,
+            ProtFlags,
+        },
+        serde,
+    },
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        coalesce_ranges,
+        PageSize,
+    },
+};
+
+
+/// Residency of consecutive pages, starting at `offset` bytes into a file or
+/// mapping.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Residency {
+    pub offset: u64,
+    pages:      Vec<bool>,
+}
+
+
+impl Residency {
+    pub const PAGESIZE: u64 = PageSize::Normal as u64;
+
+    pub fn new(
+        offset: u64,
+        pages: Vec<bool>,
+    ) -> Self {
+        Self { offset, pages }
+    }
+
+    /// Residency of the pages backing `range` of the calling process.
+    pub fn of_range(range: &AddressRange) -> Result<Self, Error> {
+        Ok(Self::new(0, crate::sys::mincore(range)?))
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn resident(&self) -> usize {
+        self.pages.iter().filter(|resident| **resident).count()
+    }
+
+    /// Whether the page containing byte `offset` (relative to the start of
+    /// the file) is resident, `None` if outside of the scanned pages.
+    pub fn is_resident(
+        &self,
+        offset: u64,
+    ) -> Option<bool> {
+        let index = offset.checked_sub(self.offset)? / Self::PAGESIZE;
+        self.pages.get(index as usize).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        self.pages.iter().copied()
+    }
+
+    /// Coalesced byte ranges of the resident pages.
+    pub fn resident_ranges(&self) -> Vec<Range<u64>> {
+        let pagesize = Self::PAGESIZE as usize;
+        let resident = self.pages.iter().enumerate().filter(|(_index, resident)| **resident).map(|(index, _)| {
+            let start = self.offset as usize + index * pagesize;
+            AddressRange::new(start, start + pagesize)
+        });
+        coalesce_ranges(resident)
+            .into_iter()
+            .map(|range| range.start() as u64..range.end() as u64)
+            .collect()
+    }
+}
+
+
+/// Page cache residency of one file.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct FileResidency {
+    pub path:      PathBuf,
+    pub size:      u64,
+    pub residency: Residency,
+}
+
+
+/// Report which pages of the file at `path` are in the page cache.
+pub fn fincore<P: AsRef<Path>>(path: P) -> Result<FileResidency, Error> {
+    let path = path.as_ref();
+    let size = std::fs::metadata(path)?.len();
+    let residency = fincore_range(path, 0, size)?;
+    Ok(FileResidency {
+        path: path.to_path_buf(),
+        size,
+        residency,
+    })
+}
+
+
+/// Like [`fincore`] for `len` bytes starting at `offset`, clipped to the end
+/// of the file. `offset` is rounded down to a page boundary.
+pub fn fincore_range<P: AsRef<Path>>(
+    path: P,
+    offset: u64,
+    len: u64,
+) -> Result<Residency, Error> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+
+    let offset = offset - offset % Residency::PAGESIZE;
+    let end = offset.saturating_add(len).min(size);
+    if end <= offset {
+        return Ok(Residency::new(offset, Vec::new()));
+    }
+
+    let map_len = (end - offset) as usize;
+    debug!("fincore {:?} offset={} len={}", path, offset, map_len);
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            map_len,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_SHARED,
+            file.as_raw_fd(),
+            offset as i64,
+        )?
+    };
+
+    let pages = crate::sys::mincore(&AddressRange::new(ptr as usize, ptr as usize + map_len));
+    unsafe {
+        munmap(ptr, map_len).unwrap_or_else(|err| debug!("munmap failed: {}", err));
+    }
+
+    Ok(Residency::new(offset, pages?))
+}
+
+
+#[test]
+fn test_resident_ranges_are_coalesced() {
+    let residency = Residency::new(4096, vec![true, true, false, true, false, false]);
+
+    assert_eq!(residency.pages(), 6);
+    assert_eq!(residency.resident(), 3);
+    assert_eq!(residency.resident_ranges(), vec![4096..12288, 16384..20480]);
+    assert_eq!(residency.is_resident(8192), Some(true));
+    assert_eq!(residency.is_resident(12288), Some(false));
+    assert_eq!(residency.is_resident(0), None);
+}
+
+
+#[test]
+fn test_touched_file_page_is_resident() {
+    use crate::mmapfile::temp_file_mapping;
+
+    const PAGESIZE: usize = Residency::PAGESIZE as usize;
+    let mut map = temp_file_mapping("fincore", 4 * PAGESIZE, 0);
+    let path = map.path().unwrap().to_path_buf();
+    map.page_mut(2).unwrap().write::<u8>(0, b'x');
+
+    let file = fincore(&path).unwrap();
+    assert_eq!(file.size, 4 * PAGESIZE as u64);
+    assert_eq!(file.residency.pages(), 4);
+    assert_eq!(file.residency.is_resident(2 * PAGESIZE as u64), Some(true));
+    assert!(file.residency.resident() >= 1);
+
+    let mapped = map.resident_pages().unwrap();
+    assert_eq!(mapped.pages(), 4);
+    assert_eq!(mapped.is_resident(2 * PAGESIZE as u64), Some(true));
+    assert_eq!(mapped.iter().collect::<Vec<_>>(), file.residency.iter().collect::<Vec<_>>());
+}
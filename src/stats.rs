@@ -0,0 +1,316 @@
+//! Page count aggregation over a region, a pathname or any other grouping of
+//! pages produced by a [`crate::pagemaps::Iter`].
+use std::{
+    fmt,
+    iter::FromIterator,
+    ops::AddAssign,
+    str::FromStr,
+};
+
+use crate::{
+    deps::serde,
+    error::Error,
+    kpageflags::KPageFlags,
+    pagemaps::{
+        PageDescriptor,
+        PageSize,
+        VMARegion,
+    },
+};
+
+
+/// Running totals of the pagemap and kpageflags bits for a set of pages.
+///
+/// The `thp`, `huge` and `ksm` counts require `/proc/kpageflags` to be
+/// readable, otherwise they remain zero.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RegionStats {
+    pub pages:       u64,
+    pub bytes:       u64,
+    pub present:     u64,
+    pub not_present: u64,
+    pub swapped:     u64,
+    pub soft_dirty:  u64,
+    pub exclusive:   u64,
+    pub file:        u64,
+    pub thp:         u64,
+    pub huge:        u64,
+    pub ksm:         u64,
+}
+
+
+impl RegionStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scan every page of `region` and total the results.
+    pub fn from_region(
+        region: &VMARegion<'_>,
+        page_size_override: Option<PageSize>,
+    ) -> Result<Self, Error> {
+        let mut stats = Self::new();
+        for page in region.try_iter(page_size_override)? {
+            stats.add_page(&page?);
+        }
+        Ok(stats)
+    }
+
+    pub fn add_page(
+        &mut self,
+        page: &PageDescriptor<'_>,
+    ) {
+        let pte = &page.pte;
+        let flags = page.kpageflags.unwrap_or_else(|| KPageFlags::new(0));
+
+        self.pages += 1;
+        self.bytes += page.addr_range.len() as u64;
+        self.present += pte.is_present() as u64;
+        self.not_present += (!pte.is_present() && !pte.is_swapped()) as u64;
+        self.swapped += pte.is_swapped() as u64;
+        self.soft_dirty += pte.is_soft_dirty() as u64;
+        self.exclusive += pte.is_exclusive() as u64;
+        self.file += pte.is_file_or_shared_anon() as u64;
+        self.thp += flags.thp() as u64;
+        self.huge += flags.huge() as u64;
+        self.ksm += flags.ksm() as u64;
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &RegionStats,
+    ) {
+        self.pages += other.pages;
+        self.bytes += other.bytes;
+        self.present += other.present;
+        self.not_present += other.not_present;
+        self.swapped += other.swapped;
+        self.soft_dirty += other.soft_dirty;
+        self.exclusive += other.exclusive;
+        self.file += other.file;
+        self.thp += other.thp;
+        self.huge += other.huge;
+        self.ksm += other.ksm;
+    }
+
+    pub const fn clean(&self) -> u64 {
+        self.pages - self.soft_dirty
+    }
+
+    pub fn get(
+        &self,
+        field: StatsField,
+    ) -> u64 {
+        use StatsField::*;
+        match field {
+            Pages => self.pages,
+            Bytes => self.bytes,
+            Present => self.present,
+            NotPresent => self.not_present,
+            Swapped => self.swapped,
+            SoftDirty => self.soft_dirty,
+            Clean => self.clean(),
+            Exclusive => self.exclusive,
+            File => self.file,
+            Thp => self.thp,
+            Huge => self.huge,
+            Ksm => self.ksm,
+        }
+    }
+}
+
+
+impl AddAssign<&RegionStats> for RegionStats {
+    fn add_assign(
+        &mut self,
+        other: &RegionStats,
+    ) {
+        self.merge(other)
+    }
+}
+
+
+impl<'a, 'b> Extend<&'b PageDescriptor<'a>> for RegionStats {
+    fn extend<I: IntoIterator<Item = &'b PageDescriptor<'a>>>(
+        &mut self,
+        iter: I,
+    ) {
+        for page in iter {
+            self.add_page(page);
+        }
+    }
+}
+
+
+impl<'a, 'b> FromIterator<&'b PageDescriptor<'a>> for RegionStats {
+    fn from_iter<I: IntoIterator<Item = &'b PageDescriptor<'a>>>(iter: I) -> Self {
+        let mut stats = Self::new();
+        stats.extend(iter);
+        stats
+    }
+}
+
+
+/// Names a single counter of [`RegionStats`], e.g. for sorting a report.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum StatsField {
+    Pages,
+    Bytes,
+    Present,
+    NotPresent,
+    Swapped,
+    SoftDirty,
+    Clean,
+    Exclusive,
+    File,
+    Thp,
+    Huge,
+    Ksm,
+}
+
+
+impl StatsField {
+    pub const ALL: [StatsField; 12] = [
+        StatsField::Pages,
+        StatsField::Bytes,
+        StatsField::Present,
+        StatsField::NotPresent,
+        StatsField::Swapped,
+        StatsField::SoftDirty,
+        StatsField::Clean,
+        StatsField::Exclusive,
+        StatsField::File,
+        StatsField::Thp,
+        StatsField::Huge,
+        StatsField::Ksm,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        use StatsField::*;
+        match self {
+            Pages => "pages",
+            Bytes => "bytes",
+            Present => "present",
+            NotPresent => "not-present",
+            Swapped => "swapped",
+            SoftDirty => "dirty",
+            Clean => "clean",
+            Exclusive => "exclusive",
+            File => "file",
+            Thp => "thp",
+            Huge => "huge",
+            Ksm => "ksm",
+        }
+    }
+}
+
+
+impl FromStr for StatsField {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let trimmed = value.trim();
+        StatsField::ALL
+            .iter()
+            .copied()
+            .find(|field| field.as_str() == trimmed)
+            .ok_or_else(|| {
+                Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<StatsField>(),
+                    reason:   format!(
+                        "value was not one of: {}",
+                        StatsField::ALL
+                            .iter()
+                            .map(StatsField::as_str)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+            })
+    }
+}
+
+
+impl fmt::Display for StatsField {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+
+/// Rank grouped stats for printing: drop the groups without pages, sort the
+/// rest by `field` largest first and then by name, and keep the `top` first.
+pub fn rank_groups<T>(
+    groups: &mut Vec<(String, RegionStats, T)>,
+    field: StatsField,
+    top: Option<usize>,
+) {
+    groups.retain(|(_name, stats, _extra)| stats.pages > 0);
+    groups.sort_by(|(a_name, a, _), (b_name, b, _)| b.get(field).cmp(&a.get(field)).then_with(|| a_name.cmp(b_name)));
+    groups.truncate(top.unwrap_or(usize::max_value()));
+}
+
+
+#[test]
+fn test_stats_merge_and_fields() {
+    let mut first = RegionStats {
+        pages: 4,
+        bytes: 4 * 4096,
+        present: 3,
+        not_present: 1,
+        soft_dirty: 1,
+        ..RegionStats::default()
+    };
+    let second = RegionStats {
+        pages: 2,
+        bytes: 2 * 4096,
+        present: 2,
+        swapped: 1,
+        soft_dirty: 2,
+        ksm: 1,
+        ..RegionStats::default()
+    };
+    first += &second;
+    assert_eq!(first.pages, 6);
+    assert_eq!(first.bytes, 6 * 4096);
+    assert_eq!(first.get(StatsField::Present), 5);
+    assert_eq!(first.get(StatsField::NotPresent), 1);
+    assert_eq!(first.get(StatsField::Swapped), 1);
+    assert_eq!(first.get(StatsField::SoftDirty), 3);
+    assert_eq!(first.get(StatsField::Clean), 3);
+    assert_eq!(first.get(StatsField::Ksm), 1);
+
+    for field in StatsField::ALL.iter() {
+        assert_eq!(field.to_string().parse::<StatsField>().unwrap(), *field);
+    }
+    assert_eq!(" dirty\n".parse::<StatsField>().unwrap(), StatsField::SoftDirty);
+    assert!("soft_dirty".parse::<StatsField>().is_err());
+
+    let tie = RegionStats {
+        pages: 2,
+        soft_dirty: 2,
+        ..RegionStats::default()
+    };
+    let groups = || {
+        vec![
+            ("c".to_string(), tie, ()),
+            ("empty".to_string(), RegionStats::default(), ()),
+            ("a".to_string(), first, ()),
+            ("b".to_string(), second, ()),
+        ]
+    };
+    let ranked = |field: StatsField, top: Option<usize>| {
+        let mut groups = groups();
+        rank_groups(&mut groups, field, top);
+        groups.into_iter().map(|(name, _stats, ())| name).collect::<Vec<_>>()
+    };
+    // empty groups are dropped, ties are ordered by name
+    assert_eq!(ranked(StatsField::SoftDirty, None), vec!["a", "b", "c"]);
+    assert_eq!(ranked(StatsField::Clean, None), vec!["a", "b", "c"]);
+    assert_eq!(ranked(StatsField::Pages, Some(2)), vec!["a", "b"]);
+    assert_eq!(ranked(StatsField::Swapped, Some(0)), Vec::<String>::new());
+}
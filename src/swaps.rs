@@ -0,0 +1,248 @@
+//! Swap areas from `/proc/swaps`.
+//!
+//! ```text
+//! Filename                                Type            Size            Used            Priority
+//! /dev/nvme0n1p3                          partition       16777212        1024            -2
+//! /swapfile                               file            2097148         0               -3
+//! ```
+//!
+//! The kernel lists the areas in swap type order but does not show the type
+//! itself, so the n-th entry is the area of swap type `n` only as long as no
+//! area in between was removed with `swapoff(2)`. Neither the priority nor
+//! anything else in `/proc/swaps` tells whether that happened, so an area
+//! found by its type is a guess and reported as unverified.
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    io::BufRead,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+use crate::{
+    deps::serde,
+    error::Error,
+    pagemaps::{
+        PageDescriptor,
+        SwapEntry,
+        VMARegion,
+    },
+};
+
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SwapDevice {
+    pub filename: PathBuf,
+    /// `partition` or `file`
+    pub kind:     String,
+    pub size_kb:  u64,
+    pub used_kb:  u64,
+    pub priority: i64,
+}
+
+
+impl<'a> TryFrom<&'a str> for SwapDevice {
+    type Error = Error;
+
+    fn try_from(line: &'a str) -> Result<Self, Self::Error> {
+        let parse_error = |reason: &str| {
+            Error::Parse {
+                value:    line.to_string(),
+                typename: std::any::type_name::<SwapDevice>(),
+                reason:   reason.to_string(),
+            }
+        };
+
+        // whitespace in the filename is escaped as octal, e.g. `\040`
+        let columns = line.split_whitespace().collect::<Vec<_>>();
+        let not_numeric = |name: &str| parse_error(&format!("expected a numeric {} column", name));
+
+        match columns.as_slice() {
+            [filename, kind, size, used, priority] => {
+                Ok(SwapDevice {
+                    filename: PathBuf::from(filename.replace("\\040", " ")),
+                    kind:     kind.to_string(),
+                    size_kb:  size.parse::<u64>().map_err(|_| not_numeric("size"))?,
+                    used_kb:  used.parse::<u64>().map_err(|_| not_numeric("used"))?,
+                    priority: priority.parse::<i64>().map_err(|_| not_numeric("priority"))?,
+                })
+            }
+            _ => Err(parse_error("expected 5 columns: filename, type, size, used, priority")),
+        }
+    }
+}
+
+
+/// The parsed `/proc/swaps`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Swaps {
+    devices: Vec<SwapDevice>,
+}
+
+
+impl Swaps {
+    pub fn load() -> Result<Self, Error> {
+        Swaps::try_from(crate::paths::proc_swaps_path())
+    }
+
+    pub fn devices(&self) -> &[SwapDevice] {
+        &self.devices
+    }
+
+    /// The swap area listed at the position of a swap type, see
+    /// [`SwapEntry::swap_type`]. Unverified: after a `swapoff(2)` of a lower
+    /// type this is a different area, see the module documentation.
+    pub fn guess_device(
+        &self,
+        swap_type: u8,
+    ) -> Option<&SwapDevice> {
+        self.devices.get(swap_type as usize)
+    }
+
+    /// Name the area a swapped page was likely written to, unverified like
+    /// [`Swaps::guess_device`].
+    pub fn guess_area(
+        &self,
+        entry: &SwapEntry,
+    ) -> Option<&Path> {
+        self.guess_device(entry.swap_type).map(|device| device.filename.as_path())
+    }
+}
+
+
+impl<'a> TryFrom<&'a mut dyn BufRead> for Swaps {
+    type Error = Error;
+
+    fn try_from(reader: &'a mut dyn BufRead) -> Result<Self, Self::Error> {
+        let mut swaps = Swaps::default();
+        // skip the header line
+        for line in reader.lines().skip(1) {
+            let line = line?;
+            if !line.trim().is_empty() {
+                swaps.devices.push(SwapDevice::try_from(line.as_str())?);
+            }
+        }
+        Ok(swaps)
+    }
+}
+
+
+impl<'a> TryFrom<&'a Path> for Swaps {
+    type Error = Error;
+
+    fn try_from(path: &'a Path) -> Result<Self, Self::Error> {
+        let mut reader = crate::io::new_buffered_file_reader(path, None)?;
+        Swaps::try_from(&mut reader as &mut dyn BufRead)
+    }
+}
+
+
+/// Swapped out pages per swap type.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SwapTotals(BTreeMap<u8, u64>);
+
+
+impl SwapTotals {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scan every page of `region` and count the swapped ones.
+    pub fn from_region(region: &VMARegion<'_>) -> Result<Self, Error> {
+        let mut totals = Self::new();
+        for page in region.try_iter(None)? {
+            totals.add_page(&page?);
+        }
+        Ok(totals)
+    }
+
+    pub fn add_page(
+        &mut self,
+        page: &PageDescriptor<'_>,
+    ) {
+        if let Some(entry) = page.pte.swap_entry() {
+            *self.0.entry(entry.swap_type).or_default() += 1;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u8, u64)> + '_ {
+        self.0.iter().map(|(swap_type, pages)| (*swap_type, *pages))
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &SwapTotals,
+    ) {
+        for (swap_type, pages) in other.iter() {
+            *self.0.entry(swap_type).or_default() += pages;
+        }
+    }
+
+    /// Pair each count with its swap type and the area listed at the
+    /// position of the type, unverified like [`Swaps::guess_device`].
+    pub fn with_guessed_devices<'s>(
+        &'s self,
+        swaps: &'s Swaps,
+    ) -> impl Iterator<Item = (u8, Option<&'s SwapDevice>, u64)> + 's {
+        self.iter()
+            .map(move |(swap_type, pages)| (swap_type, swaps.guess_device(swap_type), pages))
+    }
+}
+
+
+impl fmt::Display for SwapTotals {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "-");
+        }
+        let totals = self
+            .iter()
+            .map(|(swap_type, pages)| format!("type{}={}", swap_type, pages))
+            .collect::<Vec<_>>();
+        write!(f, "{}", totals.join(","))
+    }
+}
+
+
+#[test]
+fn test_parse_proc_swaps() {
+    let text = "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n\
+                /dev/nvme0n1p3                          partition\t16777212\t1024\t-2\n\
+                /var/swap\\040file                       file\t\t2097148\t\t0\t\t-3\n";
+    let swaps = Swaps::try_from(&mut text.as_bytes() as &mut dyn BufRead).unwrap();
+
+    assert_eq!(swaps.devices().len(), 2);
+    assert_eq!(swaps.guess_device(0).unwrap().filename, Path::new("/dev/nvme0n1p3"));
+    assert_eq!(swaps.guess_device(0).unwrap().kind, "partition");
+    assert_eq!(swaps.guess_device(0).unwrap().used_kb, 1024);
+    assert_eq!(swaps.guess_device(0).unwrap().priority, -2);
+    assert_eq!(swaps.guess_device(1).unwrap().filename, Path::new("/var/swap file"));
+    assert_eq!(
+        swaps.guess_area(&SwapEntry {
+            swap_type: 1,
+            offset:    7,
+        }),
+        Some(Path::new("/var/swap file"))
+    );
+    assert!(swaps.guess_device(2).is_none());
+
+    // sizes are unsigned, a negative one is rejected rather than wrapped
+    assert!(matches!(
+        SwapDevice::try_from("/swapfile file -1 0 -3"),
+        Err(Error::Parse { .. })
+    ));
+    assert!(matches!(
+        SwapDevice::try_from("/swapfile file 1024 -4096 -3"),
+        Err(Error::Parse { .. })
+    ));
+}
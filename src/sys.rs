@@ -0,0 +1,235 @@
+//! Thin wrappers over the system calls not covered by `nix`.
+use std::{
+    convert::TryFrom,
+    os::unix::io::{
+        AsRawFd,
+        RawFd,
+    },
+};
+
+use crate::{
+    deps::{
+        libc,
+        log::debug,
+    },
+    error::Error,
+    maps::column::AddressRange,
+};
+
+
+/// A file descriptor referring to a process, see `pidfd_open(2)`.
+#[derive(Debug)]
+pub struct PidFd(RawFd);
+
+
+impl PidFd {
+    pub fn open(pid: usize) -> Result<Self, Error> {
+        let pid = libc::pid_t::try_from(pid)?;
+        debug!("pidfd_open pid={}", pid);
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(PidFd(fd as RawFd))
+    }
+}
+
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+
+/// `madvise(2)` on a range of the calling process.
+pub fn madvise(
+    range: &AddressRange,
+    advice: libc::c_int,
+) -> Result<(), Error> {
+    debug!("madvise {} advice={}", range, advice);
+    let ret = unsafe { libc::madvise(range.start() as *mut libc::c_void, range.len(), advice) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+
+/// `process_madvise(2)` on a range of the process behind `pidfd`, returns
+/// the number of bytes advised.
+pub fn process_madvise(
+    pidfd: &PidFd,
+    range: &AddressRange,
+    advice: libc::c_int,
+) -> Result<usize, Error> {
+    debug!("process_madvise {} advice={}", range, advice);
+    let iov = libc::iovec {
+        iov_base: range.start() as *mut libc::c_void,
+        iov_len:  range.len(),
+    };
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_process_madvise,
+            pidfd.as_raw_fd(),
+            &iov as *const libc::iovec,
+            1usize,
+            advice,
+            0u32,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ret as usize)
+}
+
+
+/// Applies advice to ranges of a process, using `madvise(2)` for the calling
+/// process and `process_madvise(2)` through a pidfd opened once otherwise.
+#[derive(Debug)]
+pub enum ProcessAdvisor {
+    This,
+    Other(PidFd),
+}
+
+
+impl ProcessAdvisor {
+    pub fn new(pid: usize) -> Result<Self, Error> {
+        if pid == std::process::id() as usize {
+            Ok(ProcessAdvisor::This)
+        } else {
+            PidFd::open(pid).map(ProcessAdvisor::Other)
+        }
+    }
+
+    pub fn advise(
+        &self,
+        range: &AddressRange,
+        advice: libc::c_int,
+    ) -> Result<(), Error> {
+        match self {
+            ProcessAdvisor::This => madvise(range, advice),
+            ProcessAdvisor::Other(pidfd) => process_madvise(pidfd, range, advice).map(|_advised| ()),
+        }
+    }
+}
+
+
+/// `move_pages(2)` in query mode (`nodes` is NULL): for every address of
+/// process `pid` the status is the node the page resides on, or a negated
+/// errno such as `-ENOENT` when the page is not present.
+pub fn move_pages_query(
+    pid: usize,
+    addrs: &[usize],
+) -> Result<Vec<libc::c_int>, Error> {
+    let pid = libc::pid_t::try_from(pid)?;
+    let pages = addrs.iter().map(|addr| *addr as *mut libc::c_void).collect::<Vec<_>>();
+    let mut status = vec![0 as libc::c_int; pages.len()];
+
+    debug!("move_pages query pid={} count={}", pid, pages.len());
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_move_pages,
+            pid,
+            pages.len() as libc::c_ulong,
+            pages.as_ptr(),
+            std::ptr::null::<libc::c_int>(),
+            status.as_mut_ptr(),
+            0 as libc::c_int,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(status)
+}
+
+
+/// `mincore(2)` on a page aligned range of the calling process: one entry
+/// per page, `true` when the page is resident in memory.
+pub fn mincore(range: &AddressRange) -> Result<Vec<bool>, Error> {
+    let page_size = crate::pagemaps::PageSize::Normal as usize;
+    let mut vec = vec![0u8; (range.len() + page_size - 1) / page_size];
+
+    debug!("mincore {}", range);
+    let ret = unsafe { libc::mincore(range.start() as *mut libc::c_void, range.len(), vec.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(vec.into_iter().map(|status| status & 1 != 0).collect())
+}
+
+
+/// `mremap(2)` a range of the calling process to `new_len` bytes, returns the
+/// start of the mapping, which only changes when `may_move`.
+pub fn mremap(
+    range: &AddressRange,
+    new_len: usize,
+    may_move: bool,
+) -> Result<usize, Error> {
+    let flags = if may_move { libc::MREMAP_MAYMOVE } else { 0 };
+
+    debug!("mremap {} new_len={} may_move={}", range, new_len, may_move);
+    let ptr = unsafe { libc::mremap(range.start() as *mut libc::c_void, range.len(), new_len, flags) };
+    if ptr == libc::MAP_FAILED {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ptr as usize)
+}
+
+
+/// `process_vm_readv(2)` of `buf.len()` bytes at `addr` of process `pid`,
+/// returns the number of bytes read, which is short when the read crosses
+/// into an unmapped page.
+pub fn process_vm_readv(
+    pid: usize,
+    addr: usize,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let pid = libc::pid_t::try_from(pid)?;
+    let local = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len:  buf.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: addr as *mut libc::c_void,
+        iov_len:  buf.len(),
+    };
+
+    debug!("process_vm_readv pid={} addr={:#x} len={}", pid, addr, buf.len());
+    let ret = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(ret as usize)
+}
+
+
+/// `PTRACE_INTERRUPT` a thread attached with `PTRACE_SEIZE`, which nix does
+/// not wrap.
+pub fn ptrace_interrupt(tid: usize) -> Result<(), Error> {
+    let tid = libc::pid_t::try_from(tid)?;
+    debug!("ptrace interrupt tid={}", tid);
+    let ret = unsafe {
+        libc::ptrace(
+            libc::PTRACE_INTERRUPT,
+            tid,
+            std::ptr::null_mut::<libc::c_void>(),
+            std::ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
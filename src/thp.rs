@@ -0,0 +1,251 @@
+//! Transparent huge page coverage of a mapped region.
+//!
+//! A region is split into 2 MiB aligned ranges, each of which the kernel
+//! could map with a single PMD. For every range the 4K pages are scanned to
+//! see how many are present and how many belong to a THP (or any other large
+//! folio). Ranges that are fully populated but not yet THP-backed are
+//! candidates for `MADV_COLLAPSE` (since 6.1).
+use std::fmt;
+
+use crate::{
+    deps::{
+        libc,
+        log::info,
+        serde,
+    },
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        PageSize,
+        VMARegion,
+    },
+};
+
+
+pub const HUGE_PAGE_SIZE: usize = PageSize::Huge as usize;
+pub const PAGES_PER_HUGE_PAGE: usize = HUGE_PAGE_SIZE / VMARegion::PAGESIZE;
+
+
+/// The pages of one 2 MiB aligned range, clipped to the region.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct HugeRange {
+    pub range:   AddressRange,
+    /// 4K pages of the range that are part of the region
+    pub pages:   usize,
+    pub present: usize,
+    /// present pages belonging to a transparent huge page
+    pub thp:     usize,
+    /// number of THP head pages within the range
+    pub heads:   usize,
+}
+
+
+impl HugeRange {
+    fn new(range: AddressRange) -> Self {
+        Self {
+            range,
+            pages: 0,
+            present: 0,
+            thp: 0,
+            heads: 0,
+        }
+    }
+
+    /// The range covers a whole 2 MiB aligned block of the region.
+    pub fn is_complete(&self) -> bool {
+        self.pages == PAGES_PER_HUGE_PAGE
+    }
+
+    pub fn is_thp_backed(&self) -> bool {
+        self.is_complete() && self.thp == PAGES_PER_HUGE_PAGE
+    }
+
+    /// Every page is present, but not (entirely) backed by a huge page.
+    pub fn is_collapse_candidate(&self) -> bool {
+        self.is_complete() && self.present == PAGES_PER_HUGE_PAGE && !self.is_thp_backed()
+    }
+
+    /// Present pages mapped with regular 4K pages.
+    pub fn small(&self) -> usize {
+        self.present - self.thp
+    }
+}
+
+
+impl fmt::Display for HugeRange {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        let state = if self.is_thp_backed() {
+            "thp"
+        } else if self.is_collapse_candidate() {
+            "candidate"
+        } else if !self.is_complete() {
+            "partial"
+        } else {
+            "sparse"
+        };
+        write!(
+            f,
+            "{} present={}/{} thp={} heads={} {}",
+            self.range, self.present, self.pages, self.thp, self.heads, state
+        )
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct ThpReport {
+    pub region: AddressRange,
+    pub ranges: Vec<HugeRange>,
+}
+
+
+impl ThpReport {
+    /// Scan every 4K page of `region`. Without a readable
+    /// `/proc/kpageflags` no page is reported as THP-backed.
+    pub fn from_region(region: &VMARegion<'_>) -> Result<Self, Error> {
+        let addr_range = *region.region().addr_range();
+        let mut ranges: Vec<HugeRange> = Vec::new();
+
+        for page in region.try_iter(Some(PageSize::Normal))? {
+            let page = page?;
+            let addr = page.addr_range.start();
+            let aligned = addr & !(HUGE_PAGE_SIZE - 1);
+
+            if ranges.last().map(|last| last.range.contains(addr)) != Some(true) {
+                let start = aligned.max(addr_range.start());
+                let end = (aligned + HUGE_PAGE_SIZE).min(addr_range.end());
+                ranges.push(HugeRange::new(AddressRange::new(start, end)));
+            }
+
+            let current = ranges.last_mut().unwrap();
+            current.pages += 1;
+            if page.pte.is_present() {
+                current.present += 1;
+                if let Some(flags) = page.kpageflags {
+                    current.thp += flags.thp() as usize;
+                    current.heads += (flags.thp() && flags.compound_head()) as usize;
+                }
+            }
+        }
+
+        info!("scanned {} huge page ranges of region {}", ranges.len(), addr_range);
+
+        Ok(Self {
+            region: addr_range,
+            ranges,
+        })
+    }
+
+    pub fn thp_bytes(&self) -> usize {
+        self.ranges.iter().map(|range| range.thp).sum::<usize>() * VMARegion::PAGESIZE
+    }
+
+    pub fn small_bytes(&self) -> usize {
+        self.ranges.iter().map(HugeRange::small).sum::<usize>() * VMARegion::PAGESIZE
+    }
+
+    /// THP head pages mapped by the region, one per huge page that starts
+    /// inside it.
+    pub fn heads(&self) -> usize {
+        self.ranges.iter().map(|range| range.heads).sum()
+    }
+
+    pub fn candidates(&self) -> impl Iterator<Item = &HugeRange> {
+        self.ranges.iter().filter(|range| range.is_collapse_candidate())
+    }
+
+    /// Page table bytes freed by collapsing every candidate: each collapse
+    /// replaces a page table of 512 PTEs by a single PMD entry. The pages
+    /// themselves are copied into a huge page, so no data memory is freed.
+    pub fn page_table_savings(&self) -> usize {
+        self.candidates().count() * VMARegion::PAGESIZE
+    }
+}
+
+
+/// Outcome of `MADV_COLLAPSE` on one range.
+#[derive(Debug)]
+pub struct CollapseResult {
+    pub range:  AddressRange,
+    pub result: Result<(), Error>,
+}
+
+
+/// Ask the kernel to synchronously collapse each range of process `pid` into
+/// a transparent huge page. Fails only if the process cannot be opened.
+pub fn collapse<'r, I>(
+    pid: usize,
+    ranges: I,
+) -> Result<Vec<CollapseResult>, Error>
+where
+    I: IntoIterator<Item = &'r AddressRange>,
+{
+    let advisor = crate::sys::ProcessAdvisor::new(pid)?;
+    Ok(ranges
+        .into_iter()
+        .map(|range| {
+            CollapseResult {
+                range:  *range,
+                result: advisor.advise(range, libc::MADV_COLLAPSE),
+            }
+        })
+        .collect())
+}
+
+
+#[test]
+fn test_thp_report_counts_populated_ranges() {
+    use crate::{
+        deps::nix::sys::mman::{
+            mmap,
+            munmap,
+            MapFlags,
+            ProtFlags,
+        },
+        pagemaps::ProcessVMA,
+    };
+
+    // over-allocate so a full 2 MiB aligned range fits inside the mapping
+    let len = 3 * HUGE_PAGE_SIZE;
+    let ptr = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            len,
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+        .unwrap()
+    };
+    let aligned = (ptr as usize + HUGE_PAGE_SIZE - 1) & !(HUGE_PAGE_SIZE - 1);
+    crate::sys::madvise(
+        &AddressRange::new(ptr as usize, ptr as usize + len),
+        libc::MADV_NOHUGEPAGE,
+    )
+    .unwrap();
+    for page in 0..PAGES_PER_HUGE_PAGE {
+        unsafe { *((aligned + page * VMARegion::PAGESIZE) as *mut u8) = 1 };
+    }
+
+    let vm = ProcessVMA::this_process().unwrap();
+    let report = ThpReport::from_region(&vm.region(aligned).unwrap()).unwrap();
+    let populated = report
+        .ranges
+        .iter()
+        .find(|range| range.range.start() == aligned)
+        .unwrap();
+
+    assert!(populated.is_complete());
+    assert_eq!(populated.present, PAGES_PER_HUGE_PAGE);
+    assert!(populated.is_collapse_candidate());
+    assert!(report.candidates().any(|range| range.range.start() == aligned));
+    // MADV_NOHUGEPAGE keeps the range on 4K pages
+    assert_eq!(populated.heads, 0);
+    assert!(report.page_table_savings() >= VMARegion::PAGESIZE);
+
+    unsafe { munmap(ptr, len).unwrap() };
+}
@@ -0,0 +1,457 @@
+//! Working set size estimation.
+//!
+//! Like Brendan Gregg's wss tools, [`estimate`] resets the accessed state of
+//! the pages of a process, waits for an interval and counts the pages that
+//! were accessed in it. The written working set comes from the soft-dirty
+//! bits, which are cleared at the start of the interval as well. Two methods
+//! find the accessed pages:
+//!
+//! * [`WssMethod::Referenced`]: `clear_refs` mode 1 clears the referenced and
+//!   accessed bits. [`estimate`] reads the `Referenced` field of
+//!   `/proc/pid/smaps` back, which counts the accessed bits of the PTEs per
+//!   region. Per page only the `REFERENCED` flag of `/proc/kpageflags` is
+//!   available, which the kernel sets from the accessed bit of a PTE lazily,
+//!   so [`AccessSampler`] under-counts pages only touched through their
+//!   mappings with this method.
+//! * [`WssMethod::Idle`]: the pages are marked idle in
+//!   `/sys/kernel/mm/page_idle/bitmap` (`CONFIG_IDLE_PAGE_TRACKING`), an
+//!   access clears the mark. Only pages on the LRU lists can be marked.
+//!
+//! Idle page tracking and per page sampling need the page frame numbers,
+//! i.e. `CAP_SYS_ADMIN`. Both methods reset the soft-dirty bits of the whole
+//! process.
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    os::unix::fs::FileExt,
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    deps::{
+        log::debug,
+        serde,
+    },
+    error::Error,
+    maps::column::AddressRange,
+    pagemaps::{
+        PageDescriptor,
+        PageSize,
+        ProcessVMA,
+    },
+};
+
+
+pub const PAGESIZE: usize = PageSize::Normal as usize;
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum WssMethod {
+    Referenced,
+    Idle,
+}
+
+
+impl FromStr for WssMethod {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "referenced" | "refs" => Ok(WssMethod::Referenced),
+            "idle" | "page-idle" => Ok(WssMethod::Idle),
+            _ => {
+                Err(Error::Parse {
+                    value:    value.to_string(),
+                    typename: std::any::type_name::<Self>(),
+                    reason:   "expected one of: referenced, idle".to_string(),
+                })
+            }
+        }
+    }
+}
+
+
+impl fmt::Display for WssMethod {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            WssMethod::Referenced => "referenced",
+            WssMethod::Idle => "idle",
+        }
+        .fmt(f)
+    }
+}
+
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WssStats {
+    /// pages present at the end of the interval
+    pub present:  u64,
+    /// present pages read or written in the interval
+    pub accessed: u64,
+    /// pages written in the interval, present or swapped
+    pub written:  u64,
+}
+
+
+impl WssStats {
+    pub const fn accessed_bytes(&self) -> u64 {
+        self.accessed * PAGESIZE as u64
+    }
+
+    pub const fn written_bytes(&self) -> u64 {
+        self.written * PAGESIZE as u64
+    }
+
+    pub fn merge(
+        &mut self,
+        other: &WssStats,
+    ) {
+        self.present += other.present;
+        self.accessed += other.accessed;
+        self.written += other.written;
+    }
+}
+
+
+/// The outcome of [`estimate`], per region by start address.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WssReport {
+    pub method:   WssMethod,
+    pub duration: Duration,
+    pub regions:  BTreeMap<usize, WssStats>,
+    pub total:    WssStats,
+}
+
+
+/// `/sys/kernel/mm/page_idle/bitmap`, one bit per page frame in 64-bit words.
+#[derive(Debug)]
+struct IdleBitmap {
+    file:  File,
+    /// the words read so far by index
+    words: HashMap<u64, u64>,
+}
+
+
+impl IdleBitmap {
+    fn open(pid: usize) -> Result<Self, Error> {
+        let path = crate::paths::sys_page_idle_bitmap_path();
+        let file = std::fs::OpenOptions::new().read(true).write(true).open(path).map_err(|err| {
+            Error::WorkingSet {
+                pid,
+                reason: format!("unable to open {:?}, idle page tracking needs CONFIG_IDLE_PAGE_TRACKING: {}", path, err),
+            }
+        })?;
+        Ok(Self {
+            file,
+            words: HashMap::new(),
+        })
+    }
+
+    /// Mark the page frames `pfns` idle.
+    fn mark(
+        &mut self,
+        pfns: &[u64],
+    ) -> Result<(), Error> {
+        let mut words = BTreeMap::new();
+        for pfn in pfns {
+            *words.entry(pfn / 64).or_insert(0u64) |= 1 << (pfn % 64);
+        }
+        // the kernel ignores the zero bits of a written word
+        for (index, word) in words {
+            self.file.write_all_at(&word.to_ne_bytes(), index * 8)?;
+        }
+        Ok(())
+    }
+
+    fn is_idle(
+        &mut self,
+        pfn: u64,
+    ) -> Result<bool, Error> {
+        let index = pfn / 64;
+        let word = match self.words.get(&index) {
+            Some(word) => *word,
+            None => {
+                let mut buf = [0u8; 8];
+                self.file.read_exact_at(&mut buf, index * 8)?;
+                *self.words.entry(index).or_insert(u64::from_ne_bytes(buf))
+            }
+        };
+        Ok(word & (1 << (pfn % 64)) != 0)
+    }
+}
+
+
+/// Fail unless `/proc/kpageflags` can be read, which sampling the pages of
+/// process `pid` one by one needs. The page frame numbers need the same
+/// capability.
+pub(crate) fn require_page_flags(pid: usize) -> Result<(), Error> {
+    let path = crate::paths::proc_kpageflags_path();
+    File::open(path).map(drop).map_err(|err| {
+        Error::WorkingSet {
+            pid,
+            reason: format!("unable to read {:?}, sampling pages needs CAP_SYS_ADMIN: {}", path, err),
+        }
+    })
+}
+
+
+/// The page frame numbers of the present pages of the regions starting at
+/// `regions`.
+fn present_pfns(
+    vm: &ProcessVMA,
+    regions: &[usize],
+) -> Result<Vec<u64>, Error> {
+    let mut pfns = Vec::new();
+    for addr in regions {
+        let region = vm.region(*addr).ok_or(Error::RegionNotFound { addr: *addr })?;
+        for page in region.try_iter(Some(PageSize::Normal))? {
+            let page = page?;
+            if page.pte.is_present() {
+                let pfn = page.pte.page_frame_number().ok_or_else(|| {
+                    Error::WorkingSet {
+                        pid:    vm.pid(),
+                        reason: "page frame numbers are hidden, CAP_SYS_ADMIN is required".to_string(),
+                    }
+                })?;
+                pfns.push(pfn.get());
+            }
+        }
+    }
+    Ok(pfns)
+}
+
+
+/// Tells which pages were accessed since the accessed state and the
+/// soft-dirty bits of a process were reset.
+#[derive(Debug)]
+pub(crate) struct AccessSampler {
+    pid:    usize,
+    /// the marked pages, `None` for [`WssMethod::Referenced`]
+    bitmap: Option<IdleBitmap>,
+}
+
+
+impl AccessSampler {
+    /// Reset the accessed state of the present pages of the regions starting
+    /// at `regions` and the soft-dirty bits of the process.
+    pub(crate) fn reset(
+        vm: &ProcessVMA,
+        regions: &[usize],
+        method: WssMethod,
+    ) -> Result<Self, Error> {
+        let bitmap = match method {
+            WssMethod::Referenced => {
+                vm.clear_referenced()?;
+                None
+            }
+            WssMethod::Idle => {
+                let mut bitmap = IdleBitmap::open(vm.pid())?;
+                bitmap.mark(&present_pfns(vm, regions)?)?;
+                Some(bitmap)
+            }
+        };
+        vm.clear_refs()?;
+        Ok(Self {
+            pid: vm.pid(),
+            bitmap,
+        })
+    }
+
+    /// Whether the present `page` was read or written since the reset.
+    pub(crate) fn accessed(
+        &mut self,
+        page: &PageDescriptor,
+    ) -> Result<bool, Error> {
+        match self.bitmap.as_mut() {
+            Some(bitmap) => {
+                match page.pte.page_frame_number() {
+                    // a page frame that was not marked was faulted in since
+                    Some(pfn) => Ok(!bitmap.is_idle(pfn.get())?),
+                    None => {
+                        Err(Error::WorkingSet {
+                            pid:    self.pid,
+                            reason: "page frame numbers are hidden, CAP_SYS_ADMIN is required".to_string(),
+                        })
+                    }
+                }
+            }
+            None => {
+                match page.kpageflags {
+                    Some(flags) => Ok(flags.referenced()),
+                    None => {
+                        Err(Error::WorkingSet {
+                            pid:    self.pid,
+                            reason: "the page flags are unavailable, reading /proc/kpageflags needs CAP_SYS_ADMIN"
+                                .to_string(),
+                        })
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+/// The pages referenced since the last `clear_refs` by region start, from
+/// the `Referenced` field of `/proc/pid/smaps`.
+fn smaps_referenced(pid: usize) -> Result<BTreeMap<usize, u64>, Error> {
+    let smaps = std::fs::read_to_string(crate::paths::proc_pid_smaps_path(Some(pid)))?;
+    let mut referenced = BTreeMap::new();
+    let mut start = None;
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("Referenced:"), Some(kb)) => {
+                let kb = kb.parse::<u64>().map_err(|err| {
+                    Error::Parse {
+                        value:    line.to_string(),
+                        typename: std::any::type_name::<u64>(),
+                        reason:   err.to_string(),
+                    }
+                })?;
+                if let Some(start) = start {
+                    referenced.insert(start, kb * 1024 / PAGESIZE as u64);
+                }
+            }
+            // the fields of a region end with its flags, the next line that
+            // is not a field starts a region
+            (Some(first), _) if !first.ends_with(':') => {
+                start = AddressRange::try_from(first).ok().map(|range| range.start());
+            }
+            _ => {}
+        }
+    }
+    Ok(referenced)
+}
+
+
+/// Estimate the working set of the regions of process `pid` starting at
+/// `regions` over `duration`.
+pub fn estimate(
+    pid: usize,
+    regions: &[usize],
+    method: WssMethod,
+    duration: Duration,
+) -> Result<WssReport, Error> {
+    let vm = ProcessVMA::with_pid(pid)?;
+    let mut sampler = AccessSampler::reset(&vm, regions, method)?;
+
+    debug!("measuring the working set of pid {} for {:?}", pid, duration);
+    std::thread::sleep(duration);
+
+    // the regions as they were at the start, unmapped ones are left out
+    let vm = ProcessVMA::with_pid(pid)?;
+    let referenced = match method {
+        WssMethod::Referenced => Some(smaps_referenced(pid)?),
+        WssMethod::Idle => None,
+    };
+    let mut report = WssReport {
+        method,
+        duration,
+        regions: BTreeMap::new(),
+        total: WssStats::default(),
+    };
+    for addr in regions {
+        let region = match vm.region(*addr) {
+            Some(region) => region,
+            None => continue,
+        };
+
+        let mut stats = WssStats::default();
+        for page in region.try_iter(Some(PageSize::Normal))? {
+            let page = page?;
+            if page.pte.is_soft_dirty() && (page.pte.is_present() || page.pte.is_swapped()) {
+                stats.written += 1;
+            }
+            if !page.pte.is_present() {
+                continue;
+            }
+
+            stats.present += 1;
+            if referenced.is_none() {
+                stats.accessed += sampler.accessed(&page)? as u64;
+            }
+        }
+        if let Some(referenced) = referenced.as_ref() {
+            let start = region.region().addr_range().start();
+            stats.accessed = referenced.get(&start).copied().unwrap_or(0).min(stats.present);
+        }
+        report.total.merge(&stats);
+        report.regions.insert(*addr, stats);
+    }
+    Ok(report)
+}
+
+
+#[test]
+fn test_estimate_counts_accessed_and_written_pages() {
+    use crate::mmapfile::MmapFile;
+    use std::sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    };
+
+    assert_eq!("refs".parse::<WssMethod>().unwrap(), WssMethod::Referenced);
+    assert_eq!("idle".parse::<WssMethod>().unwrap(), WssMethod::Idle);
+    assert!("smaps".parse::<WssMethod>().is_err());
+
+    let soft_dirty = crate::pagemaps::SoftDirtyTest::lock();
+    let mut map = MmapFile::anonymous_private(8 * PAGESIZE).unwrap();
+    for index in 0..8 {
+        map.page_mut(index).unwrap().write::<u64>(0, index as u64 + 1);
+    }
+    let start = map.addr_range().start();
+
+    let idle_supported = crate::paths::sys_page_idle_bitmap_path().exists();
+    for method in [WssMethod::Referenced, WssMethod::Idle].iter() {
+        if *method == WssMethod::Idle && !idle_supported {
+            eprintln!("skipping idle, /sys/kernel/mm/page_idle/bitmap is missing");
+            continue;
+        }
+
+        // write pages 0 and 1 and read pages 2 and 3 all through the interval
+        let stop = Arc::new(AtomicBool::new(false));
+        let toucher = {
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    for index in 0..4 {
+                        let addr = (start + index * PAGESIZE) as *mut u64;
+                        unsafe {
+                            if index < 2 {
+                                std::ptr::write_volatile(addr, index as u64 + 1);
+                            } else {
+                                std::ptr::read_volatile(addr);
+                            }
+                        }
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        let report = estimate(std::process::id() as usize, &[start], *method, Duration::from_millis(100));
+        stop.store(true, Ordering::Relaxed);
+        toucher.join().unwrap();
+
+        let report = report.unwrap();
+        let stats = report.regions[&start];
+        assert_eq!(stats.present, 8);
+        assert_eq!(stats.accessed, 4, "{:?}", method);
+        if soft_dirty.tracked() {
+            assert_eq!(stats.written, 2);
+        }
+        assert_eq!(report.total, stats);
+    }
+}